@@ -1,6 +1,8 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 4K
+    /* last sector holds the imu calibration, see sensors/calibration.rs */
+    CALIBRATION : ORIGIN = 0x10000000 + 2048K - 4K, LENGTH = 4K
     /*
      * RAM consists of 4 banks, SRAM0-SRAM3, with a striped mapping.
      * This is usually good for performance, as it distributes load on
@@ -0,0 +1,501 @@
+// TBS Crossfire / ExpressLRS serial protocol, 420000 baud 8N1, full duplex
+// frame is address, length (type + payload + crc), type, payload, crc8 (DVB-S2) over type and payload
+use super::radio::{RadioError, RadioFrame, RadioPacket, Receiver, Telemetry, RADIO_CHANNELS};
+use defmt::Format;
+use fugit::RateExtU32;
+use hal::uart::{DataBits, StopBits, UartConfig};
+use rp2040_hal as hal;
+
+pub const CRSF_MAX_FRAME_LEN: usize = 64;
+const CRSF_ADDRESS_FLIGHT_CONTROLLER: u8 = 0xC8;
+const CRSF_ADDRESS_RADIO: u8 = 0xEA;
+const CRSF_ADDRESS_RECEIVER: u8 = 0xEC;
+const CRSF_SYNC: u8 = 0xC8; // what the receiver actually starts frames with
+const CRSF_TYPE_BATTERY: u8 = 0x08;
+const CRSF_TYPE_LINK_STATISTICS: u8 = 0x14;
+const CRSF_TYPE_RC_CHANNELS: u8 = 0x16;
+const CRSF_TYPE_ATTITUDE: u8 = 0x1E;
+const RC_CHANNELS_LEN: usize = 22; // 16 channels of 11 bits
+const LINK_STATISTICS_LEN: usize = 10;
+const CRSF_CHANNELS: usize = 16;
+
+pub fn crsf_crc8(bytes: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for b in bytes {
+        crc ^= *b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0xD5
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// 172-1811 maps onto 988-2012 us, same scale as SBUS
+#[inline(always)]
+pub fn crsf_to_us(value: u16) -> u16 {
+    value * 5 / 8 + 880
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct LinkStats {
+    pub uplink_rssi: i16, // dBm, from whichever antenna is active
+    pub uplink_lq: u8,    // percent of packets that made it
+    pub uplink_snr: i8,   // dB
+    pub rf_mode: u8,
+    pub tx_power: u8,
+    pub downlink_rssi: i16,
+    pub downlink_lq: u8,
+    pub downlink_snr: i8,
+}
+
+impl LinkStats {
+    // 0 to 1, only uplink matters for flying
+    pub fn health(&self) -> f32 {
+        self.uplink_lq.min(100) as f32 / 100.0
+    }
+}
+
+pub struct CrsfReceiver {
+    buf: [u8; CRSF_MAX_FRAME_LEN],
+    pos: usize,
+    next_telemetry: u8, // battery and attitude take turns
+}
+
+impl CrsfReceiver {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; CRSF_MAX_FRAME_LEN],
+            pos: 0,
+            next_telemetry: 0,
+        }
+    }
+}
+
+impl Default for CrsfReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CrsfReceiver {
+    // header and length state machine, Some(len) once buf holds a whole frame
+    fn collect(&mut self, byte: u8) -> Option<usize> {
+        match self.pos {
+            0 if !is_address(byte) => return None,
+            // type and crc at least, and the whole thing has to fit
+            1 if !(2..=CRSF_MAX_FRAME_LEN as u8 - 2).contains(&byte) => {
+                self.pos = is_address(byte) as usize;
+                if self.pos == 1 {
+                    self.buf[0] = byte;
+                }
+                return None;
+            }
+            _ => (),
+        }
+        self.buf[self.pos] = byte;
+        self.pos += 1;
+        if self.pos < 2 || self.pos < self.buf[1] as usize + 2 {
+            return None;
+        }
+        let len = self.pos;
+        self.reset();
+        Some(len)
+    }
+
+    // after a bad frame, starts again from the next thing that looks like an address.
+    // runs in the interrupt so its a loop over the candidates, not push calling itself.
+    // a good frame found inside the leftovers gets dropped, the next one is only ms away
+    fn resync(&mut self, len: usize) {
+        let frame = self.buf;
+        let mut start = 0;
+        'candidates: loop {
+            start = match (start + 1..len).find(|&i| is_address(frame[i])) {
+                Some(i) => i,
+                None => return,
+            };
+            self.reset();
+            for &b in &frame[start..len] {
+                if let Some(inner) = self.collect(b) {
+                    if let Some(Err(_)) = decode_crsf(&self.buf[..inner]) {
+                        continue 'candidates;
+                    }
+                }
+            }
+            return;
+        }
+    }
+}
+
+fn is_address(byte: u8) -> bool {
+    matches!(
+        byte,
+        CRSF_ADDRESS_FLIGHT_CONTROLLER | CRSF_ADDRESS_RADIO | CRSF_ADDRESS_RECEIVER
+    )
+}
+
+impl Receiver for CrsfReceiver {
+    fn uart_config(&self) -> UartConfig {
+        UartConfig::new(420000.Hz(), DataBits::Eight, None, StopBits::One)
+    }
+
+    fn reset(&mut self) {
+        self.pos = 0;
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Result<RadioPacket, RadioError>> {
+        let len = self.collect(byte)?;
+        let result = decode_crsf(&self.buf[..len]);
+        if let Some(Err(_)) = result {
+            self.resync(len);
+        }
+        result
+    }
+
+    fn encode_telemetry(&mut self, telemetry: &Telemetry, buf: &mut [u8]) -> usize {
+        self.next_telemetry = (self.next_telemetry + 1) % 2;
+        match self.next_telemetry {
+            0 => encode_battery(telemetry.battery_voltage, buf),
+            _ => encode_attitude(telemetry.attitude, buf),
+        }
+    }
+}
+
+// None for frame types nobody here cares about
+pub fn decode_crsf(frame: &[u8]) -> Option<Result<RadioPacket, RadioError>> {
+    let len = frame.len();
+    if len < 4 || frame[1] as usize != len - 2 {
+        return Some(Err(RadioError::FramingError));
+    }
+    if crsf_crc8(&frame[2..len - 1]) != frame[len - 1] {
+        return Some(Err(RadioError::ChecksumError));
+    }
+    let payload = &frame[3..len - 1];
+    match frame[2] {
+        CRSF_TYPE_RC_CHANNELS if payload.len() == RC_CHANNELS_LEN => {
+            Some(Ok(RadioPacket::Channels(decode_channels(payload))))
+        }
+        CRSF_TYPE_LINK_STATISTICS if payload.len() == LINK_STATISTICS_LEN => {
+            Some(Ok(RadioPacket::Link(decode_link_statistics(payload))))
+        }
+        CRSF_TYPE_RC_CHANNELS | CRSF_TYPE_LINK_STATISTICS => Some(Err(RadioError::FramingError)),
+        _ => None,
+    }
+}
+
+fn decode_channels(payload: &[u8]) -> RadioFrame {
+    let mut raw = [0; RADIO_CHANNELS];
+    for (ch, us) in raw.iter_mut().enumerate().take(CRSF_CHANNELS) {
+        let bit = ch * 11;
+        let byte = bit / 8;
+        let mut bits = payload[byte] as u32 | (payload[byte + 1] as u32) << 8;
+        if byte + 2 < payload.len() {
+            bits |= (payload[byte + 2] as u32) << 16;
+        }
+        *us = crsf_to_us(((bits >> (bit % 8)) & 0x07FF) as u16);
+    }
+    RadioFrame::from_raw(raw)
+}
+
+fn decode_link_statistics(payload: &[u8]) -> LinkStats {
+    // rssi is sent as a positive number of -dBm
+    let active_antenna = payload[4];
+    let uplink_rssi = if active_antenna == 0 {
+        payload[0]
+    } else {
+        payload[1]
+    };
+    LinkStats {
+        uplink_rssi: -(uplink_rssi as i16),
+        uplink_lq: payload[2],
+        uplink_snr: payload[3] as i8,
+        rf_mode: payload[5],
+        tx_power: payload[6],
+        downlink_rssi: -(payload[7] as i16),
+        downlink_lq: payload[8],
+        downlink_snr: payload[9] as i8,
+    }
+}
+
+// wraps a payload into a frame for the receiver to pass on, returns how much of buf got used
+pub fn encode_frame(frame_type: u8, payload: &[u8], buf: &mut [u8]) -> usize {
+    let len = payload.len() + 4;
+    if len > buf.len() || len > CRSF_MAX_FRAME_LEN {
+        return 0;
+    }
+    buf[0] = CRSF_SYNC;
+    buf[1] = (payload.len() + 2) as u8;
+    buf[2] = frame_type;
+    buf[3..len - 1].copy_from_slice(payload);
+    buf[len - 1] = crsf_crc8(&buf[2..len - 1]);
+    len
+}
+
+// volts, current and capacity arent measured so they go out as 0
+pub fn encode_battery(voltage: f32, buf: &mut [u8]) -> usize {
+    let decivolts = ((voltage * 10.0) as u16).to_be_bytes();
+    let payload = [decivolts[0], decivolts[1], 0, 0, 0, 0, 0, 0];
+    encode_frame(CRSF_TYPE_BATTERY, &payload, buf)
+}
+
+// attitude is [roll, pitch, yaw] in degrees, goes out as pitch, roll, yaw in 100 urad
+// an i16 of 100 urad only reaches about 187 degrees, so a 0-360 heading gets wrapped to +-180
+pub fn encode_attitude(attitude: [f32; 3], buf: &mut [u8]) -> usize {
+    let to_crsf = |deg: f32| ((deg.to_radians() * 10000.0) as i16).to_be_bytes();
+    let [roll, pitch, yaw] = attitude;
+    let yaw = if yaw > 180.0 { yaw - 360.0 } else { yaw };
+    let [roll, pitch, yaw] = [roll, pitch, yaw].map(to_crsf);
+    let payload = [pitch[0], pitch[1], roll[0], roll[1], yaw[0], yaw[1]];
+    encode_frame(CRSF_TYPE_ATTITUDE, &payload, buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every stick centered, what an ExpressLRS receiver sends with the transmitter idle
+    const CENTERED: [u8; 26] = [
+        0xC8, 0x18, 0x16, 0xE0, 0x03, 0x1F, 0xF8, 0xC0, 0x07, 0x3E, 0xF0, 0x81, 0x0F, 0x7C, 0xE0,
+        0x03, 0x1F, 0xF8, 0xC0, 0x07, 0x3E, 0xF0, 0x81, 0x0F, 0x7C, 0xAD,
+    ];
+
+    fn channels_frame(values: [u16; CRSF_CHANNELS]) -> Vec<u8> {
+        let mut payload = [0; RC_CHANNELS_LEN];
+        for (ch, value) in values.iter().enumerate() {
+            for bit in 0..11 {
+                if value & (1 << bit) != 0 {
+                    let n = ch * 11 + bit;
+                    payload[n / 8] |= 1 << (n % 8);
+                }
+            }
+        }
+        let mut buf = [0; CRSF_MAX_FRAME_LEN];
+        let len = encode_frame(CRSF_TYPE_RC_CHANNELS, &payload, &mut buf);
+        buf[..len].to_vec()
+    }
+
+    // first channel of every channels frame, Err for the bad ones, link stats and the rest skipped
+    fn feed(receiver: &mut CrsfReceiver, bytes: &[u8]) -> Vec<Result<u16, ()>> {
+        bytes
+            .iter()
+            .filter_map(|b| receiver.push(*b))
+            .filter_map(|result| match result {
+                Ok(RadioPacket::Channels(frame)) => Some(Ok(frame.raw[0])),
+                Ok(_) => None,
+                Err(_) => Some(Err(())),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc8_dvb_s2() {
+        assert_eq!(crsf_crc8(b"123456789"), 0xBC);
+        assert_eq!(crsf_crc8(&[]), 0);
+        assert_eq!(crsf_crc8(&CENTERED[2..25]), 0xAD);
+    }
+
+    #[test]
+    fn decodes_centered_sticks() {
+        match decode_crsf(&CENTERED) {
+            Some(Ok(RadioPacket::Channels(frame))) => {
+                assert!(frame.raw[..CRSF_CHANNELS].iter().all(|us| *us == 1500));
+                assert_eq!(&frame.raw[CRSF_CHANNELS..], &[0, 0]);
+            }
+            _ => panic!("didnt decode"),
+        }
+    }
+
+    #[test]
+    fn decodes_every_channel() {
+        let mut values = [0; CRSF_CHANNELS];
+        for (ch, value) in values.iter_mut().enumerate() {
+            *value = 172 + 109 * ch as u16;
+        }
+        match decode_crsf(&channels_frame(values)) {
+            Some(Ok(RadioPacket::Channels(frame))) => {
+                for (ch, value) in values.iter().enumerate() {
+                    assert_eq!(frame.raw[ch], crsf_to_us(*value), "channel {}", ch + 1);
+                }
+            }
+            _ => panic!("didnt decode"),
+        }
+    }
+
+    #[test]
+    fn decodes_link_statistics() {
+        // second antenna active, so its rssi is the one that counts
+        let payload = [70, 55, 87, 251, 1, 4, 2, 60, 100, 9];
+        let mut buf = [0; CRSF_MAX_FRAME_LEN];
+        let len = encode_frame(CRSF_TYPE_LINK_STATISTICS, &payload, &mut buf);
+        match decode_crsf(&buf[..len]) {
+            Some(Ok(RadioPacket::Link(link))) => {
+                assert_eq!(
+                    link,
+                    LinkStats {
+                        uplink_rssi: -55,
+                        uplink_lq: 87,
+                        uplink_snr: -5,
+                        rf_mode: 4,
+                        tx_power: 2,
+                        downlink_rssi: -60,
+                        downlink_lq: 100,
+                        downlink_snr: 9,
+                    }
+                );
+                assert_eq!(link.health(), 0.87);
+            }
+            _ => panic!("didnt decode"),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut crc = CENTERED;
+        crc[10] ^= 0x01;
+        assert!(matches!(
+            decode_crsf(&crc),
+            Some(Err(RadioError::ChecksumError))
+        ));
+        // length byte disagreeing with what arrived
+        assert!(matches!(
+            decode_crsf(&CENTERED[..25]),
+            Some(Err(RadioError::FramingError))
+        ));
+        // right type, wrong size payload
+        let mut buf = [0; CRSF_MAX_FRAME_LEN];
+        let len = encode_frame(CRSF_TYPE_RC_CHANNELS, &[0; 10], &mut buf);
+        assert!(matches!(
+            decode_crsf(&buf[..len]),
+            Some(Err(RadioError::FramingError))
+        ));
+        // somebody elses frame type is not an error, just not ours
+        let len = encode_frame(0x29, &[1, 2, 3], &mut buf);
+        assert!(decode_crsf(&buf[..len]).is_none());
+    }
+
+    #[test]
+    fn stream_with_junk_and_other_frames() {
+        let mut receiver = CrsfReceiver::new();
+        let mut buf = [0; CRSF_MAX_FRAME_LEN];
+        let mut stream = vec![0x00, 0x55, 0xC8, 0xFF, 0x01];
+        stream.extend_from_slice(&CENTERED);
+        let len = encode_frame(0x29, &[1, 2, 3], &mut buf);
+        stream.extend_from_slice(&buf[..len]);
+        let len = encode_frame(
+            CRSF_TYPE_LINK_STATISTICS,
+            &[0; LINK_STATISTICS_LEN],
+            &mut buf,
+        );
+        stream.extend_from_slice(&buf[..len]);
+        stream.extend_from_slice(&channels_frame([1811; CRSF_CHANNELS]));
+        let (head, tail) = stream.split_at(17);
+        let mut frames = feed(&mut receiver, head);
+        frames.extend(feed(&mut receiver, tail));
+        assert_eq!(frames, [Ok(1500), Ok(crsf_to_us(1811))]);
+    }
+
+    #[test]
+    fn resyncs_after_a_bad_frame() {
+        // joined a byte late, so the first address byte is channel data (0xC8 in channel 1)
+        // and the length after it swallows part of the next frame
+        let mut values = [992; CRSF_CHANNELS];
+        values[0] = 0x0C8;
+        values[1] = 0x4;
+        let first = channels_frame(values);
+        assert_eq!(&first[3..5], &[0xC8, 0x20]);
+        let mut stream = first[3..].to_vec();
+        stream.extend_from_slice(&channels_frame([500; CRSF_CHANNELS]));
+        stream.extend_from_slice(&channels_frame([600; CRSF_CHANNELS]));
+        stream.extend_from_slice(&channels_frame([700; CRSF_CHANNELS]));
+        let mut receiver = CrsfReceiver::new();
+        let frames = feed(&mut receiver, &stream);
+        // the start of the next frame was inside the bad one, nothing after it is lost
+        let expected = [
+            Err(()),
+            Ok(crsf_to_us(500)),
+            Ok(crsf_to_us(600)),
+            Ok(crsf_to_us(700)),
+        ];
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn resyncs_past_a_whole_frame_in_the_leftovers() {
+        // a corrupted length swallows a short link statistics frame and the start of the next one
+        let mut buf = [0; CRSF_MAX_FRAME_LEN];
+        let mut stream = vec![0xC8, 0x20, CRSF_TYPE_RC_CHANNELS, 0, 0, 0, 0, 0];
+        let len = encode_frame(
+            CRSF_TYPE_LINK_STATISTICS,
+            &[0; LINK_STATISTICS_LEN],
+            &mut buf,
+        );
+        stream.extend_from_slice(&buf[..len]);
+        stream.extend_from_slice(&channels_frame([500; CRSF_CHANNELS]));
+        stream.extend_from_slice(&channels_frame([600; CRSF_CHANNELS]));
+        let mut receiver = CrsfReceiver::new();
+        let frames = feed(&mut receiver, &stream);
+        assert_eq!(frames, [Err(()), Ok(crsf_to_us(500)), Ok(crsf_to_us(600))]);
+    }
+
+    #[test]
+    fn telemetry_frames() {
+        let mut buf = [0; CRSF_MAX_FRAME_LEN];
+        let len = encode_battery(11.84, &mut buf);
+        assert_eq!(
+            &buf[..len - 1],
+            &[0xC8, 10, CRSF_TYPE_BATTERY, 0, 118, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(buf[len - 1], crsf_crc8(&buf[2..len - 1]));
+        // pitch goes first, 100 urad big endian
+        let len = encode_attitude([-10.0, 20.0, 90.0], &mut buf);
+        assert_eq!(&buf[..3], &[0xC8, 8, CRSF_TYPE_ATTITUDE]);
+        let read = |i: usize| i16::from_be_bytes([buf[i], buf[i + 1]]);
+        assert_eq!([read(3), read(5), read(7)], [3490, -1745, 15707]);
+        assert_eq!(buf[len - 1], crsf_crc8(&buf[2..len - 1]));
+        // doesnt write past a small buffer
+        assert_eq!(encode_battery(12.0, &mut [0; 8]), 0);
+    }
+
+    #[test]
+    fn heading_goes_out_as_plus_minus_180() {
+        let mut buf = [0; CRSF_MAX_FRAME_LEN];
+        let yaw = |buf: &[u8]| i16::from_be_bytes([buf[7], buf[8]]);
+        for (heading, expected) in [
+            (0.0, 0),
+            (90.0, 15707),
+            (180.0, 31415),
+            (270.0, -15707),
+            (359.0, -174),
+        ] {
+            encode_attitude([0.0, 0.0, heading], &mut buf);
+            assert_eq!(yaw(&buf), expected, "heading {}", heading);
+        }
+    }
+
+    #[test]
+    fn telemetry_takes_turns() {
+        let mut receiver = CrsfReceiver::new();
+        let telemetry = Telemetry {
+            battery_voltage: 12.0,
+            attitude: [0.0; 3],
+        };
+        let mut buf = [0; CRSF_MAX_FRAME_LEN];
+        let mut types = Vec::new();
+        for _ in 0..4 {
+            receiver.encode_telemetry(&telemetry, &mut buf);
+            types.push(buf[2]);
+        }
+        assert_eq!(
+            types,
+            [
+                CRSF_TYPE_ATTITUDE,
+                CRSF_TYPE_BATTERY,
+                CRSF_TYPE_ATTITUDE,
+                CRSF_TYPE_BATTERY
+            ]
+        );
+    }
+}
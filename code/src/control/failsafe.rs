@@ -0,0 +1,304 @@
+// what to do when the radio goes quiet
+// stage 1 holds level at the last throttle in case the link comes back,
+// stage 2 ramps the throttle down until it hits the ground, stage 3 disarms
+// everything is timed off the hardware timer so loop rate doesnt matter
+use crate::control::radio::RadioCommand;
+use crate::math::functions::cartesian_to_polar_magnitude;
+use defmt::Format;
+
+#[derive(Clone, Copy)]
+pub struct FailsafeConfig {
+    pub loss_ticks: u64,       // no frames for this long and the link counts as lost
+    pub hold_ticks: u64,       // stage 1
+    pub descent_throttle: f32, // stage 2 starts here (or lower if the pilot already was)
+    pub descent_rate: f32,     // throttle per second taken off during stage 2
+    pub min_descent_throttle: f32,
+    pub landing_impact: f32, // g away from 1 g that counts as touching down
+    pub max_descent_ticks: u64, // gives up and disarms after this
+}
+
+#[derive(Clone, Copy, Format, Debug, PartialEq)]
+pub enum FailsafeStage {
+    Ok,
+    Hold,
+    Descend,
+    Landed, // stays here until the link comes back
+}
+
+pub struct Failsafe {
+    config: FailsafeConfig,
+    stage: FailsafeStage,
+    last_frame: u64,
+    descent_start: u64,
+    last_command: Option<RadioCommand>,
+}
+
+impl Failsafe {
+    pub fn new(config: FailsafeConfig, now: u64) -> Self {
+        Self {
+            config,
+            stage: FailsafeStage::Ok,
+            last_frame: now,
+            descent_start: now,
+            last_command: None,
+        }
+    }
+
+    pub fn stage(&self) -> FailsafeStage {
+        self.stage
+    }
+
+    // command is Some whenever a good frame came in, acc is in g
+    pub fn update(
+        &mut self,
+        now: u64,
+        command: Option<&RadioCommand>,
+        acc: [f32; 3],
+    ) -> FailsafeStage {
+        if let Some(command) = command {
+            self.last_frame = now;
+            self.last_command = Some(*command);
+            self.stage = FailsafeStage::Ok;
+            return self.stage;
+        }
+        let lost_for = now - self.last_frame;
+        self.stage = match self.stage {
+            FailsafeStage::Ok if lost_for > self.config.loss_ticks => FailsafeStage::Hold,
+            FailsafeStage::Hold if lost_for > self.config.loss_ticks + self.config.hold_ticks => {
+                self.descent_start = now;
+                FailsafeStage::Descend
+            }
+            FailsafeStage::Descend => {
+                let impact = (cartesian_to_polar_magnitude(acc) - 1.0).abs();
+                if impact > self.config.landing_impact
+                    || now - self.descent_start > self.config.max_descent_ticks
+                {
+                    FailsafeStage::Landed
+                } else {
+                    FailsafeStage::Descend
+                }
+            }
+            stage => stage,
+        };
+        self.stage
+    }
+
+    // sticks centered, throttle depends on the stage
+    // ticks are microseconds
+    pub fn command(&self, now: u64) -> RadioCommand {
+        let last_throttle = self.last_command.map_or(0.0, |c| c.z_throttle);
+        let z_throttle = match self.stage {
+            FailsafeStage::Ok | FailsafeStage::Hold => last_throttle,
+            FailsafeStage::Descend => {
+                let seconds = (now - self.descent_start) as f32 / 1_000_000.0;
+                let start = self.config.descent_throttle.min(last_throttle);
+                (start - self.config.descent_rate * seconds)
+                    .max(self.config.min_descent_throttle.min(start))
+            }
+            FailsafeStage::Landed => 0.0,
+        };
+        RadioCommand {
+            z_throttle,
+            y_throttle: 0.0,
+            x_throttle: 0.0,
+            twist_throttle: 0.0,
+            mode_select: 1, // anything but off, core1 flies it in angle mode regardless
+            aux: self.last_command.map_or(0.0, |c| c.aux),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: FailsafeConfig = FailsafeConfig {
+        loss_ticks: 100_000,
+        hold_ticks: 1_000_000,
+        descent_throttle: 0.4,
+        descent_rate: 0.1,
+        min_descent_throttle: 0.2,
+        landing_impact: 0.5,
+        max_descent_ticks: 10_000_000,
+    };
+    const STILL: [f32; 3] = [0.0, 0.0, 1.0];
+    const BUMP: [f32; 3] = [0.0, 0.0, 2.0];
+    const STEP: u64 = 1000; // a loop every ms
+
+    fn sticks(throttle: f32) -> RadioCommand {
+        RadioCommand {
+            z_throttle: throttle,
+            y_throttle: 0.3,
+            x_throttle: -0.2,
+            twist_throttle: 0.1,
+            mode_select: 2,
+            aux: 0.7,
+        }
+    }
+
+    // no frames from start to end, the stage at every step
+    fn lose_link(failsafe: &mut Failsafe, start: u64, end: u64) -> FailsafeStage {
+        let mut stage = failsafe.stage();
+        for now in (start..=end).step_by(STEP as usize) {
+            stage = failsafe.update(now, None, STILL);
+        }
+        stage
+    }
+
+    #[test]
+    fn stays_ok_while_frames_come_in() {
+        let mut failsafe = Failsafe::new(CONFIG, 0);
+        for now in (0..10_000_000).step_by(STEP as usize) {
+            assert_eq!(
+                failsafe.update(now, Some(&sticks(0.5)), STILL),
+                FailsafeStage::Ok
+            );
+        }
+        // short dropouts dont count either
+        let last_frame = 10_000_000 - STEP;
+        assert_eq!(
+            lose_link(&mut failsafe, 10_000_000, last_frame + CONFIG.loss_ticks),
+            FailsafeStage::Ok
+        );
+    }
+
+    #[test]
+    fn goes_through_the_stages() {
+        let mut failsafe = Failsafe::new(CONFIG, 0);
+        failsafe.update(0, Some(&sticks(0.5)), STILL);
+        assert_eq!(
+            lose_link(&mut failsafe, STEP, CONFIG.loss_ticks + STEP),
+            FailsafeStage::Hold
+        );
+        let descend_at = CONFIG.loss_ticks + CONFIG.hold_ticks + STEP;
+        assert_eq!(
+            lose_link(
+                &mut failsafe,
+                CONFIG.loss_ticks + 2 * STEP,
+                descend_at - STEP
+            ),
+            FailsafeStage::Hold
+        );
+        assert_eq!(
+            failsafe.update(descend_at, None, STILL),
+            FailsafeStage::Descend
+        );
+        // falling gently is still descending, hitting the ground lands it
+        assert_eq!(
+            lose_link(&mut failsafe, descend_at + STEP, descend_at + 1_000_000),
+            FailsafeStage::Descend
+        );
+        assert_eq!(
+            failsafe.update(descend_at + 1_000_000 + STEP, None, BUMP),
+            FailsafeStage::Landed
+        );
+        // and it stays down even once it settles
+        assert_eq!(
+            lose_link(
+                &mut failsafe,
+                descend_at + 1_002_000,
+                descend_at + 5_000_000
+            ),
+            FailsafeStage::Landed
+        );
+    }
+
+    #[test]
+    fn gives_up_descending_eventually() {
+        let mut failsafe = Failsafe::new(CONFIG, 0);
+        failsafe.update(0, Some(&sticks(0.5)), STILL);
+        let end = CONFIG.loss_ticks + CONFIG.hold_ticks + CONFIG.max_descent_ticks + 10 * STEP;
+        assert_eq!(lose_link(&mut failsafe, STEP, end), FailsafeStage::Landed);
+    }
+
+    #[test]
+    fn link_coming_back_recovers_from_any_stage() {
+        for lost_for in [200_000, 2_000_000, 20_000_000] {
+            let mut failsafe = Failsafe::new(CONFIG, 0);
+            failsafe.update(0, Some(&sticks(0.5)), STILL);
+            assert_ne!(lose_link(&mut failsafe, STEP, lost_for), FailsafeStage::Ok);
+            assert_eq!(
+                failsafe.update(lost_for + STEP, Some(&sticks(0.3)), STILL),
+                FailsafeStage::Ok
+            );
+            assert_eq!(failsafe.command(lost_for + STEP).z_throttle, 0.3);
+        }
+    }
+
+    #[test]
+    fn hold_keeps_the_throttle_and_levels() {
+        let mut failsafe = Failsafe::new(CONFIG, 0);
+        failsafe.update(0, Some(&sticks(0.5)), STILL);
+        lose_link(&mut failsafe, STEP, CONFIG.loss_ticks + STEP);
+        let command = failsafe.command(CONFIG.loss_ticks + STEP);
+        assert_eq!(command.z_throttle, 0.5);
+        assert_eq!(
+            [
+                command.x_throttle,
+                command.y_throttle,
+                command.twist_throttle
+            ],
+            [0.0; 3]
+        );
+        assert_ne!(command.mode_select, 0);
+        assert_eq!(command.aux, 0.7);
+    }
+
+    #[test]
+    fn descent_ramps_down_to_the_floor() {
+        let mut failsafe = Failsafe::new(CONFIG, 0);
+        failsafe.update(0, Some(&sticks(0.5)), STILL);
+        let descend_at = CONFIG.loss_ticks + CONFIG.hold_ticks + STEP;
+        lose_link(&mut failsafe, STEP, descend_at);
+        let throttle = |seconds: f32| {
+            failsafe
+                .command(descend_at + (seconds * 1e6) as u64)
+                .z_throttle
+        };
+        // starts at descent_throttle since the pilot was above it, 0.1 per second off
+        assert!((throttle(0.0) - 0.4).abs() < 1e-6);
+        assert!((throttle(1.0) - 0.3).abs() < 1e-6);
+        assert!((throttle(5.0) - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn descent_never_adds_throttle() {
+        // pilot was already below the descent throttle, and below the floor
+        for last in [0.3, 0.1] {
+            let mut failsafe = Failsafe::new(CONFIG, 0);
+            failsafe.update(0, Some(&sticks(last)), STILL);
+            let descend_at = CONFIG.loss_ticks + CONFIG.hold_ticks + STEP;
+            lose_link(&mut failsafe, STEP, descend_at);
+            for seconds in 0..5 {
+                let command = failsafe.command(descend_at + seconds * 1_000_000);
+                assert!(
+                    command.z_throttle <= last,
+                    "{} {}",
+                    last,
+                    command.z_throttle
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn landed_cuts_the_throttle() {
+        let mut failsafe = Failsafe::new(CONFIG, 0);
+        failsafe.update(0, Some(&sticks(0.5)), STILL);
+        let descend_at = CONFIG.loss_ticks + CONFIG.hold_ticks + STEP;
+        lose_link(&mut failsafe, STEP, descend_at);
+        failsafe.update(descend_at + STEP, None, BUMP);
+        assert_eq!(failsafe.command(descend_at + STEP).z_throttle, 0.0);
+    }
+
+    #[test]
+    fn no_frames_since_boot() {
+        // transmitter never turned on, nothing to hold so it holds nothing
+        let mut failsafe = Failsafe::new(CONFIG, 0);
+        assert_eq!(
+            lose_link(&mut failsafe, 0, CONFIG.loss_ticks + STEP),
+            FailsafeStage::Hold
+        );
+        assert_eq!(failsafe.command(CONFIG.loss_ticks + STEP).z_throttle, 0.0);
+    }
+}
@@ -1,19 +1,29 @@
-use crate::control::radio::{Radio, RadioCommand};
+use crate::control::failsafe::{Failsafe, FailsafeConfig, FailsafeStage};
+use crate::control::mixer::Mixer;
+use crate::control::radio::{Radio, RadioCommand, Telemetry};
+use crate::control::stabilizer::{Stabilizer, IDLE_THROTTLE, MAX_TILT};
+use crate::control::stick_calibration::{CalibrationEvent, StickCalibration};
+use crate::math::attitude::{AttitudeEstimator, TiltEstimator, TiltSource};
 use crate::math::functions::*;
-use crate::sensors::imu::ICM_20948;
-use crate::sensors::imu::{Accelerometer, Gyroscope, Sensor};
-use core::u16;
+use crate::motors::dshot::DShotCommand;
+use crate::motors::pwm::MotorProtocol;
+use crate::motors::MotorOutput;
+use crate::sensors::battery::Battery;
+use crate::sensors::calibration::{
+    self, AccelCalibration, AccelCalibrationEvent, EllipsoidFit, ImuCalibration, StillAverage,
+};
+use crate::sensors::data_ready::ImuInterrupt;
+use crate::sensors::imu::{FlightImu, ImuSample, FIFO_MAX_SAMPLES};
+use crate::sync::clock;
+use crate::sync::seqlock::{SeqLock, SeqLockWriter};
+use core::cell::Cell;
+use critical_section::Mutex;
 use defmt::info;
 use defmt::Format;
-use embedded_hal::PwmPin;
-use hal::pwm::{Channel, FreeRunning, Pwm0, Pwm1, Pwm2, Pwm3, A};
-use hal::sio::Spinlock0 as CoreStateLock;
-use hal::uart::{ReadError, ReadErrorType};
-use libm::{cosf, powf, roundf, acosf};
+use heapless::Vec;
 
 use hal::multicore::{Core, Stack};
 use rp2040_hal as hal;
-use rp2040_hal::rom_data::float_funcs::fsqrt;
 
 use super::radio::RadioError;
 
@@ -24,77 +34,365 @@ pub struct DroneCoreState {
     true_angle: [f32; 2], //best estimate
     desired_angle: [f32; 2],
     angular_velocity: [f32; 3],
+    gyro_bias: [f32; 2], // x y, from the kalman tilt estimator
+    heading: f32,        // degrees from magnetic north, tilt compensated
+    imu_timestamp: u64,  // timer ticks when the newest imu sample was taken
     desired_twist: f32,
     aux: f32,
+    armed: bool,
+    arm_refusals: u8, // counts every refused arm, core0 beeps the motors for each one
+    failsafe: FailsafeStage,
+    battery_voltage: f32,
     current_command: DroneCommand,
     raw_command: RadioCommand, // vestigial
 }
 
+impl DroneCoreState {
+    pub fn angle(&self) -> [f32; 2] {
+        self.true_angle
+    }
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+    pub fn command(&self) -> DroneCommand {
+        self.current_command
+    }
+    pub fn battery_voltage(&self) -> f32 {
+        self.battery_voltage
+    }
+    pub fn heading(&self) -> f32 {
+        self.heading
+    }
+}
+
+// whatever the telemetry tests need to look at, everything else zeroed
+#[cfg(test)]
+impl DroneCoreState {
+    pub(crate) fn for_test(
+        angle: [f32; 2],
+        heading: f32,
+        armed: bool,
+        battery_voltage: f32,
+        current_command: DroneCommand,
+    ) -> Self {
+        Self {
+            true_acceleration: [0.0; 3],
+            desired_acceleration: [0.0; 3],
+            true_angle: angle,
+            desired_angle: [0.0; 2],
+            angular_velocity: [0.0; 3],
+            gyro_bias: [0.0; 2],
+            heading,
+            imu_timestamp: 0,
+            desired_twist: 0.0,
+            aux: 0.0,
+            armed,
+            arm_refusals: 0,
+            failsafe: FailsafeStage::Ok,
+            battery_voltage,
+            current_command,
+            raw_command: RadioCommand {
+                z_throttle: 0.0,
+                y_throttle: 0.0,
+                x_throttle: 0.0,
+                twist_throttle: 0.0,
+                mode_select: 0,
+                aux: 0.0,
+            },
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct DronePeripheralState {} // for when I add more things
 
 #[derive(Clone, Copy, Format, Debug, PartialEq)]
 pub enum DroneCommand {
     Land,
-    Calibrate,
+    Calibrate, // learning stick endpoints, motors stay off
     FullManual,
-    NormalControl,
+    NormalControl,   // rate
+    AngleControl,    // self level
     FallOutOfTheSky, // probably better than going up
 }
 
-static mut CORESTATE: Option<DroneCoreState> = None;
+// what main.rs gets to pick without touching this file
+#[derive(Clone, Copy)]
+pub struct FlightConfig {
+    // what each mode switch position flies, indexed by RadioCommand::mode_select
+    // position 0 is always off, the arming checks treat it as the kill switch
+    pub modes: &'static [DroneCommand],
+    pub tilt_source: TiltSource,
+}
+
+impl FlightConfig {
+    // off, rate, self level. rate stays where it always was, manual goes back in by swapping a
+    // position for DroneCommand::FullManual or adding one with a bigger mode_switch in RadioConfig
+    pub const fn standard() -> Self {
+        Self {
+            modes: &[
+                DroneCommand::FallOutOfTheSky,
+                DroneCommand::NormalControl,
+                DroneCommand::AngleControl,
+            ],
+            tilt_source: TiltSource::Mahony,
+        }
+    }
+
+    pub fn mode(&self, mode_select: u8) -> DroneCommand {
+        match self.modes.get(mode_select as usize) {
+            Some(mode) if mode_select != 0 => *mode,
+            _ => DroneCommand::FallOutOfTheSky,
+        }
+    }
+}
+
+// why an arm request got turned down
+#[derive(Clone, Copy, Format, Debug, PartialEq)]
+pub enum ArmRefusal {
+    KillSwitch, // mode switch is in the off position
+    ThrottleNotLow,
+    NotCalibrated,
+    ImuUnhealthy,
+    RadioStale,
+    NotLevel,
+}
+
+#[derive(Clone, Copy, Format, Debug, PartialEq)]
+pub enum ArmEvent {
+    Armed,
+    Disarmed,
+    Refused(ArmRefusal),
+}
+
+#[derive(Clone, Copy, Format, Debug, PartialEq)]
+pub enum ArmGesture {
+    None,
+    Arm,    // throttle down, twist all the way right
+    Disarm, // throttle down, twist all the way left
+}
+
+impl ArmGesture {
+    pub fn from_command(command: &RadioCommand) -> Self {
+        if command.z_throttle > IDLE_THROTTLE {
+            Self::None
+        } else if command.twist_throttle > ARM_STICK_THRESHOLD {
+            Self::Arm
+        } else if command.twist_throttle < -ARM_STICK_THRESHOLD {
+            Self::Disarm
+        } else {
+            Self::None
+        }
+    }
+}
+
+// everything the pre-arm checks need that isnt on the sticks
+#[derive(Clone, Copy)]
+pub struct ArmChecks {
+    pub imu_healthy: bool,
+    pub radio_fresh: bool,
+    pub calibrated: bool,
+    pub angle: [f32; 2], // degrees
+}
+
+impl ArmChecks {
+    pub fn check(&self, command: &RadioCommand) -> Result<(), ArmRefusal> {
+        if command.mode_select == 0 {
+            Err(ArmRefusal::KillSwitch)
+        } else if command.z_throttle > IDLE_THROTTLE {
+            Err(ArmRefusal::ThrottleNotLow)
+        } else if !self.calibrated {
+            Err(ArmRefusal::NotCalibrated)
+        } else if !self.imu_healthy {
+            Err(ArmRefusal::ImuUnhealthy)
+        } else if !self.radio_fresh {
+            Err(ArmRefusal::RadioStale)
+        } else if self.angle[0].abs() > ARM_MAX_TILT || self.angle[1].abs() > ARM_MAX_TILT {
+            Err(ArmRefusal::NotLevel)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// gestures have to be held for ARM_HOLD_TICKS and released before they do anything again
+// the kill switch disarms straight away
+pub struct Arming {
+    armed: bool,
+    gesture: ArmGesture,
+    gesture_start: u64,
+    gesture_used: bool,
+}
+
+impl Arming {
+    pub fn new() -> Self {
+        Self {
+            armed: false,
+            gesture: ArmGesture::None,
+            gesture_start: 0,
+            gesture_used: false,
+        }
+    }
+
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    pub fn disarm(&mut self) -> Option<ArmEvent> {
+        if self.armed {
+            self.armed = false;
+            Some(ArmEvent::Disarmed)
+        } else {
+            None
+        }
+    }
+
+    // now is in timer ticks
+    pub fn update(
+        &mut self,
+        now: u64,
+        command: &RadioCommand,
+        checks: ArmChecks,
+    ) -> Option<ArmEvent> {
+        if command.mode_select == 0 && self.armed {
+            return self.disarm();
+        }
+        let gesture = ArmGesture::from_command(command);
+        if gesture != self.gesture {
+            self.gesture = gesture;
+            self.gesture_start = now;
+            self.gesture_used = false;
+        }
+        if gesture == ArmGesture::None
+            || self.gesture_used
+            || now - self.gesture_start < ARM_HOLD_TICKS
+        {
+            return None;
+        }
+        self.gesture_used = true;
+        match (gesture, self.armed) {
+            (ArmGesture::Arm, false) => match checks.check(command) {
+                Ok(()) => {
+                    self.armed = true;
+                    Some(ArmEvent::Armed)
+                }
+                Err(reason) => Some(ArmEvent::Refused(reason)),
+            },
+            (ArmGesture::Disarm, true) => self.disarm(),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Arming {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// written by core1 only, read by core0
+static CORESTATE: SeqLock<Option<DroneCoreState>> = SeqLock::new(None);
+
+// for anything on core0 that wants to look, never call from core1
+pub fn latest_state() -> Option<DroneCoreState> {
+    CORESTATE.read()
+}
+
+// anything on either core can ask for a different pwm protocol, core0 switches once disarmed
+static PROTOCOL_REQUEST: Mutex<Cell<Option<MotorProtocol>>> = Mutex::new(Cell::new(None));
+
+pub fn request_motor_protocol(protocol: MotorProtocol) {
+    critical_section::with(|cs| PROTOCOL_REQUEST.borrow(cs).set(Some(protocol)));
+}
 
 static mut CORE1_STACK: Stack<8192> = Stack::new();
 
 // arbitrary max values
-const MAX_TWIST: f32 = 100.0; //dps
-const MAX_TILT: f32 = 20.0; //degrees
 const CAL_LENGTH: u32 = 1000; //number of cycles
+const GYRO_CALIBRATION_TICKS: u64 = 5_000_000; // give up on finding a still moment after this
+const ACCEL_CALIBRATION_TICKS: u64 = 120_000_000;
+const UPSIDE_DOWN: f32 = 0.8; // g on z at boot that starts the accelerometer calibration
+const MAG_CALIBRATION_TICKS: u64 = 30_000_000;
+const MAG_TICKS: u64 = 10_000; // the magnetometer only makes 100 Hz
 const IMU_FAILURE_THRESHOLD: u8 = 100;
-const RADIO_TEMPORARY_FAILURE_THRESHOLD: u16 = 100;
-const RADIO_FULL_FAILURE_THRESHOLD: u16 = 2000;
-// 1-2ms PWM
-const MAX_THROTTLE: u16 = 0xCCCC;
-const MIN_THROTTLE: u16 = 0x6666;
-const TICKS2SEC: f32 = 1.0f32 / 1000000.0f32;
+const IMU_STALE_TICKS: u64 = 10_000; // 10 ms without a data ready
 
-const MAX_THRUST: f32 = 20.0; // m/s^2
+// mahony gains, k_i mostly eats gyro bias
+const ATTITUDE_KP: f32 = 1.0;
+const ATTITUDE_KI: f32 = 0.05;
+// kalman tilt, degrees and dps
+const KALMAN_Q_ANGLE: f32 = 0.001;
+const KALMAN_Q_BIAS: f32 = 0.003;
+const KALMAN_R_MEASURE: f32 = 0.03;
+// radio failsafe, ticks are microseconds
+const FAILSAFE: FailsafeConfig = FailsafeConfig {
+    loss_ticks: 100_000,
+    hold_ticks: 1_000_000,
+    descent_throttle: 0.4,
+    descent_rate: 0.05,
+    min_descent_throttle: 0.2,
+    landing_impact: 0.8,
+    max_descent_ticks: 10_000_000,
+};
+const TICKS2SEC: f32 = 1.0f32 / 1000000.0f32;
 
 //percent difference for manual control
 const MAX_THROTTLE_DIFFERENCE: f32 = 0.1;
 
-pub struct FlightSystem {
-    fl: Channel<Pwm0, FreeRunning, A>,
-    bl: Channel<Pwm1, FreeRunning, A>,
-    br: Channel<Pwm2, FreeRunning, A>,
-    fr: Channel<Pwm3, FreeRunning, A>,
+// arming
+const ARM_STICK_THRESHOLD: f32 = 0.9;
+const ARM_HOLD_TICKS: u64 = 1_000_000; // 1 s
+const ARM_MAX_TILT: f32 = 25.0; // degrees
+const ARM_RADIO_STALE_TICKS: u64 = 100_000; // 100 ms since the last frame
+const MIN_LINK_HEALTH: f32 = 0.1; // below this the link counts as gone even if frames still come in
+const TELEMETRY_TICKS: u64 = 100_000; // 10 Hz, receivers only have so much downlink
+const ESTIMATOR_SETTLE_TICKS: u64 = 2_000_000; // 2 s of imu samples before the attitude is trusted
+
+// bidirectional dshot, a motor told to spin that reports less than this is stalled or desynced
+const STALL_SPEED: f32 = 0.2;
+const STALL_RPM: u32 = 1000;
+const STALL_LOOPS: u16 = 50;
+
+pub struct FlightSystem<M: MotorOutput> {
+    motors: M,
+    mixer: Mixer<4>,
+    config: FlightConfig,
     last_time: u64,
     timer: hal::Timer,
+    stabilizer: Stabilizer,
+    last_speeds: [f32; 4],
+    motor_rpm: [u32; 4], // clockwise starting at Front Left, 0 if unknown
+    stalled_loops: [u16; 4],
+    motor_fault: bool, // stays set until disarmed
+    rpm_filter: RpmFilter<4>,
+    arm_refusals: u8, // the last DroneCoreState::arm_refusals beeped for
 }
 
-impl FlightSystem {
-    pub fn new(
-        fl: Channel<Pwm0, FreeRunning, A>,
-        bl: Channel<Pwm1, FreeRunning, A>,
-        br: Channel<Pwm2, FreeRunning, A>,
-        fr: Channel<Pwm3, FreeRunning, A>,
-        timer: hal::timer::Timer,
-    ) -> Self {
+impl<M: MotorOutput + 'static> FlightSystem<M> {
+    pub fn new(motors: M, mixer: Mixer<4>, config: FlightConfig, timer: hal::timer::Timer) -> Self {
         Self {
-            fl,
-            bl,
-            br,
-            fr,
+            motors,
+            mixer,
+            config,
             last_time: timer.get_counter().ticks(),
             timer,
+            stabilizer: Stabilizer::new(),
+            last_speeds: [0.0; 4],
+            motor_rpm: [0; 4],
+            stalled_loops: [0; 4],
+            motor_fault: false,
+            rpm_filter: RpmFilter::new(),
+            arm_refusals: 0,
         }
     }
 
-    pub fn start(
+    pub fn start<I: FlightImu + Send + 'static>(
         &mut self,
         mut delay: cortex_m::delay::Delay,
-        mut imu: ICM_20948,
+        mut imu: I,
+        imu_interrupt: ImuInterrupt,
         radio: Radio,
+        battery: Battery,
         core1: &mut Core,
     ) -> ! {
         if !cfg!(debug_assertions) {
@@ -102,71 +400,283 @@ impl FlightSystem {
         } // delay before calibration if im not debuggin g
 
         imu.init(&mut delay).unwrap();
+        match calibration::load() {
+            Some(stored) => {
+                info!("imu calibration from flash: {}", stored);
+                imu.set_calibration(stored);
+            }
+            None => info!("no imu calibration in flash, accelerometer is uncorrected"),
+        }
         imu.update_all(&mut delay).unwrap();
-        let initial_command = radio.get_command();
-        unsafe {
-            let _clock = CoreStateLock::claim();
-            CORESTATE = Some(
-                DroneCoreState {
-                    true_acceleration: imu.get_acc(),
-                    desired_acceleration: [0.0,0.0,0.0],
-                    true_angle: cartesian_to_polar_theta(imu.get_acc(), true),
-                    desired_angle: [0.0,0.0],
-                    angular_velocity: [0.0,0.0,0.0],
-                    desired_twist: 0.0,
-                    aux: initial_command.aux,
-                    current_command: DroneCommand::FallOutOfTheSky,
-                    raw_command: initial_command,
-                }
-            )
+        if imu.get_acc()[2] < -UPSIDE_DOWN {
+            // booted upside down, that means the accelerometer and compass want calibrating
+            let before = imu.calibration();
+            self.calibrate_accelerometer(&mut imu, &mut delay);
+            self.calibrate_magnetometer(&mut imu, &mut delay);
+            if imu.calibration() != before {
+                calibration::save(&imu.calibration());
+            }
         }
+        let gyro_calibrated = self.calibrate_gyro(&mut imu, &mut delay);
+        // without a compass it just points north
+        let heading = match imu.read_mag(&mut delay) {
+            Some(Ok(mag)) => tilt_compensated_heading(mag, accelerometer_tilt(imu.get_acc(), true)),
+            Some(Err(e)) => {
+                e.info();
+                0.0
+            }
+            None => 0.0,
+        };
+        let initial_command = radio.get_command();
+        let mut state_writer = CORESTATE.writer().unwrap();
+        state_writer.write(Some(DroneCoreState {
+            true_acceleration: imu.get_acc(),
+            desired_acceleration: [0.0, 0.0, 0.0],
+            true_angle: accelerometer_tilt(imu.get_acc(), true),
+            desired_angle: [0.0, 0.0],
+            angular_velocity: [0.0, 0.0, 0.0],
+            gyro_bias: [0.0, 0.0],
+            heading,
+            imu_timestamp: self.timer.get_counter().ticks(),
+            desired_twist: 0.0,
+            aux: initial_command.aux,
+            armed: false,
+            arm_refusals: 0,
+            failsafe: FailsafeStage::Ok,
+            battery_voltage: battery.voltage(),
+            current_command: DroneCommand::FallOutOfTheSky,
+            raw_command: initial_command,
+        }));
         let mut g = 0.0;
         for _ in 0..CAL_LENGTH {
             imu.update_raw_acc().unwrap();
             g += cartesian_to_polar_magnitude(imu.get_acc());
         }
         g /= CAL_LENGTH as f32;
-        let _core1task = core1.spawn(unsafe { &mut CORE1_STACK.mem }, move || {
-            Self::core1_task(g, delay, imu, radio)
-        });
+        imu.enable_fifo(false).unwrap();
+        let config = self.config;
+        // core1 reads the counter straight off the peripheral, the timer stays with core0
+        let _core1task = core1.spawn(
+            unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK.mem) },
+            move || {
+                Self::core1_task(
+                    g,
+                    config,
+                    gyro_calibrated,
+                    delay,
+                    imu,
+                    imu_interrupt,
+                    radio,
+                    battery,
+                    state_writer,
+                )
+            },
+        );
         self.core0_task();
     }
 
-    fn core1_task(
+    // runs before core1 starts
+    fn calibrate_accelerometer<I: FlightImu>(
+        &self,
+        imu: &mut I,
+        delay: &mut cortex_m::delay::Delay,
+    ) {
+        info!(
+            "accelerometer calibration: set the drone on each of its six sides and hold it still"
+        );
+        let previous = imu.calibration();
+        imu.set_calibration(ImuCalibration::identity());
+        let start = self.timer.get_counter().ticks();
+        let mut accel_calibration = AccelCalibration::new();
+        while self.timer.get_counter().ticks() - start < ACCEL_CALIBRATION_TICKS {
+            if let Err(e) = imu.update_all(delay) {
+                e.info();
+                continue;
+            }
+            match accel_calibration.push(imu.get_gyr(), imu.get_acc()) {
+                Some(AccelCalibrationEvent::Face(face, done)) => {
+                    info!("accelerometer calibration: side {} done, {}/6", face, done)
+                }
+                Some(AccelCalibrationEvent::Finished(result)) => {
+                    info!("accelerometer calibration done: {}", result);
+                    imu.set_calibration(ImuCalibration {
+                        mag_offset: previous.mag_offset,
+                        mag_soft_iron: previous.mag_soft_iron,
+                        ..result
+                    });
+                    return;
+                }
+                Some(AccelCalibrationEvent::Failed(e)) => {
+                    info!(
+                        "accelerometer calibration failed, keeping the old one: {}",
+                        e
+                    );
+                    break;
+                }
+                None => (),
+            }
+        }
+        if accel_calibration.done() < 6 {
+            info!("accelerometer calibration timed out");
+        }
+        imu.set_calibration(previous);
+    }
+
+    // the fit needs to see the field from every side, so it has to be turned all the way around
+    fn calibrate_magnetometer<I: FlightImu>(
+        &self,
+        imu: &mut I,
+        delay: &mut cortex_m::delay::Delay,
+    ) {
+        if imu.read_mag(delay).is_none() {
+            return; // no compass to calibrate
+        }
+        info!("compass calibration: slowly turn the drone through every orientation");
+        let previous = imu.calibration();
+        let identity = ImuCalibration::identity();
+        imu.set_calibration(ImuCalibration {
+            mag_offset: identity.mag_offset,
+            mag_soft_iron: identity.mag_soft_iron,
+            ..previous
+        });
+        let start = self.timer.get_counter().ticks();
+        let mut last_sample = start;
+        let mut fit = EllipsoidFit::new();
+        loop {
+            let now = self.timer.get_counter().ticks();
+            if now - start > MAG_CALIBRATION_TICKS {
+                break;
+            }
+            if now - last_sample < MAG_TICKS {
+                continue;
+            }
+            last_sample = now;
+            match imu.read_mag(delay) {
+                Some(Ok(mag)) => fit.push(mag),
+                Some(Err(e)) => e.info(),
+                None => (),
+            }
+        }
+        match fit.result() {
+            Ok((mag_offset, mag_soft_iron)) => {
+                info!("compass calibration done: offset {} uT", mag_offset);
+                imu.set_calibration(ImuCalibration {
+                    mag_offset,
+                    mag_soft_iron,
+                    ..previous
+                });
+            }
+            Err(e) => {
+                info!("compass calibration failed, keeping the old one: {}", e);
+                imu.set_calibration(previous);
+            }
+        }
+    }
+
+    // gyro bias drifts with temperature so it gets redone every boot, as long as nobody bumps it
+    // false if it never sat still, arming stays refused until a reboot gets a good one
+    fn calibrate_gyro<I: FlightImu>(
+        &self,
+        imu: &mut I,
+        delay: &mut cortex_m::delay::Delay,
+    ) -> bool {
+        let stored = imu.calibration();
+        imu.set_calibration(ImuCalibration {
+            gyro_bias: [0.0; 3],
+            ..stored
+        });
+        let start = self.timer.get_counter().ticks();
+        let mut still = StillAverage::new();
+        while self.timer.get_counter().ticks() - start < GYRO_CALIBRATION_TICKS {
+            if let Err(e) = imu.update_all(delay) {
+                e.info();
+                continue;
+            }
+            if let Some((gyro_bias, _)) = still.push(imu.get_gyr(), imu.get_acc()) {
+                info!("gyro bias {} dps", gyro_bias);
+                imu.set_calibration(ImuCalibration {
+                    gyro_bias,
+                    ..stored
+                });
+                return true;
+            }
+        }
+        info!("drone kept moving, using the gyro bias from flash. reboot it sitting still to arm");
+        imu.set_calibration(stored);
+        false
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn core1_task<I: FlightImu + Send + 'static>(
         //read from stuff and update states
         g: f32,
+        config: FlightConfig,
+        gyro_calibrated: bool,
         mut delay: cortex_m::delay::Delay,
-        mut imu: ICM_20948,
+        mut imu: I,
+        mut imu_interrupt: ImuInterrupt,
         mut radio: Radio,
+        mut battery: Battery,
+        mut state_writer: SeqLockWriter<'static, Option<DroneCoreState>>,
     ) -> ! {
+        let mut estimator = AttitudeEstimator::from_acc(ATTITUDE_KP, ATTITUDE_KI, imu.get_acc());
+        let mut tilt_estimator = TiltEstimator::from_acc(
+            KALMAN_Q_ANGLE,
+            KALMAN_Q_BIAS,
+            KALMAN_R_MEASURE,
+            imu.get_acc(),
+        );
+        radio.listen();
+        imu_interrupt.listen();
+        let mut last_imu_time: u64 = clock::now();
         let mut failed_imu: u8 = 0;
-        let mut failed_radio: u16 = 0;
-        let mut newimu: bool = false;
-        let mut newradio: bool = false;
+        let mut first_sample: Option<u64> = None; // when the estimators started getting data
+        let mut arming = Arming::new();
+        let mut stick_calibration = StickCalibration::new();
+        let mut failsafe = Failsafe::new(FAILSAFE, clock::now());
+        let mut last_telemetry: u64 = 0;
+        let mut last_mag: u64 = 0;
+        let mut samples: Vec<ImuSample, FIFO_MAX_SAMPLES> = Vec::new();
+        let sample_ticks = (imu.sample_period_us() as u64).max(1);
         loop {
-            // theres a race condition somewhere here i think
-            newimu = match imu.update_all(&mut delay) {
-                Ok(()) => {
-                    failed_imu = 0;
-                    true
-                }
-                Err(e) => {
-                    failed_imu += 1;
-                    e.info();
+            // only touch the bus once the imu says theres something to read
+            let newimu = match imu_interrupt.take() {
+                Some(ready) => match imu.read_fifo(ready.timestamp, &mut samples) {
+                    Ok(()) => {
+                        failed_imu = 0;
+                        if !samples.is_empty() {
+                            first_sample.get_or_insert(clock::now());
+                        }
+                        !samples.is_empty()
+                    }
+                    Err(e) => {
+                        failed_imu = failed_imu.saturating_add(1);
+                        e.info();
+                        false
+                    }
+                },
+                None => {
+                    let silent = clock::now() - last_imu_time;
+                    if silent > IMU_STALE_TICKS {
+                        // stopped interrupting, as good as dead. counts the samples it missed,
+                        // not trips round this loop, those only take microseconds
+                        let missed = (silent / sample_ticks).min(u8::MAX as u64) as u8;
+                        failed_imu = failed_imu.max(missed);
+                    }
                     false
                 }
             };
-            newradio = match radio.read() {
+            let newradio = match radio.read() {
                 Ok(()) => {
-                    failed_radio = 0;
                     // info!("works {}", num_overrun);
                     true
                 }
                 Err(e) => {
-                    failed_radio += 1;
                     match e {
                         RadioError::NoNewData => (),
                         RadioError::ChecksumError => info!("radio no work :("),
+                        RadioError::FramingError => info!("radio lost sync"),
+                        RadioError::ReceiverFailsafe => info!("receiver in failsafe"),
                         RadioError::ReadError(r) => match r {
                             Some(t) => match t {
                                 rp2040_hal::uart::ReadErrorType::Overrun => {
@@ -180,147 +690,518 @@ impl FlightSystem {
                     false
                 }
             };
-            unsafe {
-                let mut newstate: DroneCoreState = CORESTATE.clone().unwrap();
-                if newimu {
-                    let measured_acceleration: [f32; 3] = imu.get_acc();
-                    let measured_angular_velocity: [f32; 3] = imu.get_gyr();
-                    if measured_acceleration[0] == 0.0 && measured_acceleration[1] == 0.0 {
-                        newstate.true_angle = [0.0,0.0];
-                    } else {
-                        let projection_constant: f32 = 1.0f32 / fsqrt(measured_acceleration[0] * measured_acceleration[0] + measured_acceleration[1] * measured_acceleration[1]);
-                        newstate.true_angle = [acosf(measured_acceleration[0] * projection_constant), acosf(measured_acceleration[1] * projection_constant)];
-                    }
-                    // TODO
-                    // newstate.true_acceleration = [ , , -g];
-
-                } else if failed_imu > IMU_FAILURE_THRESHOLD {
-                    newstate.current_command = DroneCommand::FallOutOfTheSky;
+            let mut newstate: DroneCoreState = state_writer.read().unwrap();
+            battery.update();
+            newstate.battery_voltage = battery.voltage();
+            if newimu {
+                // every sample the fifo caught, each with its own dt
+                for sample in samples.iter() {
+                    let dt = sample.timestamp.saturating_sub(last_imu_time) as f32 * TICKS2SEC;
+                    last_imu_time = sample.timestamp;
+                    estimator.update(sample.gyr, sample.acc, dt);
+                    tilt_estimator.push(sample.gyr, sample.acc, dt);
                 }
-                if newradio {
-                    let radio_command: RadioCommand = radio.get_command();
-                    // TODO
-                    // figure out how to denormalize (?)
-                    newstate.desired_acceleration = [radio_command.x_throttle, radio_command.y_throttle, radio_command.z_throttle + g];
-                    if newstate.desired_acceleration[0] == 0.0 && newstate.desired_acceleration[1] == 0.0 {
-                        newstate.desired_angle = [0.0,0.0];
-                    } else {
-                        // TODO
-                        // let projection_constant: f32 = 1.0f32 / fsqrt(newstate.desired_acceleration[0] * newstate.desired_acceleration[0] + newstate.desired_acceleration[1] * newstate.desired_acceleration[1]);
-                        // newstate.desired_angle = [acosf(newstate.desired_acceleration[0] * projection_constant), acosf(newstate.desired_acceleration[1] * projection_constant)];
-
+                newstate.angular_velocity = imu.get_gyr();
+                newstate.imu_timestamp = last_imu_time;
+                newstate.gyro_bias = tilt_estimator.bias();
+                newstate.true_angle = config.tilt_source.angle(&estimator, &tilt_estimator);
+                // TODO
+                // newstate.true_acceleration = [ , , -g];
+            }
+            let now = clock::now();
+            if now - last_mag > MAG_TICKS {
+                last_mag = now;
+                match imu.read_mag(&mut delay) {
+                    Some(Ok(mag)) => {
+                        newstate.heading = tilt_compensated_heading(mag, newstate.true_angle)
                     }
-                    newstate.current_command = match radio_command.mode_select {
-                        1 => DroneCommand::NormalControl,
-                        2 => DroneCommand::FullManual,
-                        _ => DroneCommand::FallOutOfTheSky,
-                    };
-                    newstate.raw_command = radio_command;
-                } else if failed_radio > RADIO_TEMPORARY_FAILURE_THRESHOLD {
+                    Some(Err(e)) => e.info(),
+                    None => (),
+                }
+            }
+            // receivers that report link quality can call it before the frames stop
+            let link_ok = radio
+                .link()
+                .is_none_or(|link| link.health() >= MIN_LINK_HEALTH);
+            let radio_command = if newradio && link_ok {
+                Some(radio.get_command())
+            } else {
+                None
+            };
+            if let Some(radio_command) = radio_command {
+                // TODO
+                // figure out how to denormalize (?)
+                newstate.desired_acceleration = [
+                    radio_command.x_throttle,
+                    radio_command.y_throttle,
+                    radio_command.z_throttle + g,
+                ];
+                newstate.desired_angle = [
+                    radio_command.x_throttle * MAX_TILT,
+                    radio_command.y_throttle * MAX_TILT,
+                ];
+                newstate.current_command = config.mode(radio_command.mode_select);
+                newstate.raw_command = radio_command;
+            }
+            let stage = failsafe.update(now, radio_command.as_ref(), imu.get_acc());
+            if stage != newstate.failsafe {
+                info!("failsafe {}", stage);
+            }
+            newstate.failsafe = stage;
+            match stage {
+                FailsafeStage::Ok => (),
+                FailsafeStage::Hold | FailsafeStage::Descend => {
+                    newstate.raw_command = failsafe.command(now);
+                    newstate.desired_angle = [0.0, 0.0];
                     newstate.current_command = DroneCommand::Land;
-                } else if failed_radio > RADIO_FULL_FAILURE_THRESHOLD {
-                    newstate.current_command = DroneCommand::FallOutOfTheSky;
                 }
-                let _clock = CoreStateLock::claim();
-                CORESTATE = Some(newstate);
+                FailsafeStage::Landed => {
+                    if let Some(event) = arming.disarm() {
+                        info!("failsafe landed, {}", event);
+                    }
+                }
+            }
+            let checks = ArmChecks {
+                imu_healthy: failed_imu == 0,
+                radio_fresh: link_ok && now - radio.timestamp() < ARM_RADIO_STALE_TICKS,
+                calibrated: gyro_calibrated
+                    && first_sample
+                        .is_some_and(|t| now.saturating_sub(t) >= ESTIMATOR_SETTLE_TICKS),
+                angle: newstate.true_angle,
+            };
+            if !stick_calibration.active() {
+                match arming.update(now, &newstate.raw_command, checks) {
+                    Some(ArmEvent::Refused(reason)) => {
+                        info!("arming refused: {}", reason);
+                        newstate.arm_refusals = newstate.arm_refusals.wrapping_add(1);
+                    }
+                    Some(event) => info!("{}", event),
+                    None => (),
+                }
+            }
+            newstate.armed = arming.armed();
+            if newstate.armed || !checks.radio_fresh {
+                stick_calibration.abort();
+            } else {
+                let config = radio.config();
+                match stick_calibration.update(now, &radio.frame(), &config) {
+                    Some(CalibrationEvent::Started) => info!(
+                        "stick calibration: move every stick to its ends, then center them with throttle down"
+                    ),
+                    Some(CalibrationEvent::Progress(spans)) => {
+                        info!("stick calibration: x y throttle twist spans {} us", spans)
+                    }
+                    Some(CalibrationEvent::Finished(new_config)) => {
+                        let map = new_config.map;
+                        for ch in [map.x, map.y, map.z, map.twist, map.mode, map.aux] {
+                            info!("channel {}: {}", ch + 1, new_config.calibration[ch]);
+                        }
+                        info!("stick calibration done");
+                        radio.set_config(new_config);
+                    }
+                    Some(CalibrationEvent::Failed(e)) => {
+                        info!("stick calibration failed, keeping the old endpoints: {}", e)
+                    }
+                    None => (),
+                }
             }
+            if failed_imu > IMU_FAILURE_THRESHOLD {
+                // no attitude means nothing to level with
+                newstate.current_command = DroneCommand::FallOutOfTheSky;
+            }
+            if !newstate.armed {
+                // nothing spins while disarmed
+                newstate.current_command = if stick_calibration.active() {
+                    DroneCommand::Calibrate
+                } else {
+                    DroneCommand::FallOutOfTheSky
+                };
+            }
+            if now - last_telemetry > TELEMETRY_TICKS {
+                last_telemetry = now;
+                radio.send_telemetry(&Telemetry {
+                    battery_voltage: newstate.battery_voltage,
+                    attitude: [
+                        newstate.true_angle[0],
+                        newstate.true_angle[1],
+                        newstate.heading,
+                    ],
+                });
+            }
+            state_writer.write(Some(newstate));
         }
     }
 
-    fn core0_task(&mut self) -> ! { // must do dt here
+    fn core0_task(&mut self) -> ! {
+        // must do dt here
         // let mut maxovern: MaxOverN<500> = MaxOverN::new();
         loop {
-            let mut current_state = None;
-            unsafe {
-                let _clock = CoreStateLock::claim();
-                current_state = CORESTATE.clone();
-            }
-            let current_state = current_state.unwrap();
+            let mut current_state = CORESTATE.read().unwrap();
 
-            let dticks: u64 = self.timer.get_counter().ticks() - self.last_time;
+            // the loops close once per imu sample, dt is between samples not between passes here
+            let dticks: u64 = current_state.imu_timestamp.saturating_sub(self.last_time);
+            let new_sample = dticks > 0;
             self.last_time += dticks;
             let dt: f32 = dticks as f32 * TICKS2SEC;
 
-            info!("{} Hz", 1.0 / dt);
+            if new_sample {
+                info!("{} Hz", 1.0 / dt);
+                // motor noise out before the rate loops see it, no-op without rpm telemetry
+                current_state.angular_velocity =
+                    self.rpm_filter
+                        .push(current_state.angular_velocity, self.motor_rpm, 1.0 / dt);
+            }
+
+            if !current_state.armed {
+                // disarming is how the pilot acknowledges a motor fault
+                self.motor_fault = false;
+                self.stalled_loops = [0; 4];
+                self.switch_protocol();
+                if current_state.arm_refusals != self.arm_refusals {
+                    // the pilot cant see the log, so a refused arm beeps instead
+                    self.arm_refusals = current_state.arm_refusals;
+                    self.special_command(DShotCommand::Beep2);
+                }
+            }
+            self.check_motors();
+            if self.motor_fault {
+                self.fall_out_of_the_sky();
+                continue;
+            }
 
             match current_state.current_command {
                 DroneCommand::FullManual => self.full_manual(current_state.raw_command),
-                DroneCommand::NormalControl => {
-                    self.normal_control(current_state, dt)
-                }
-                DroneCommand::Calibrate => self.set_speeds([current_state.raw_command.z_throttle; 4]),
+                DroneCommand::NormalControl if new_sample => self.normal_control(current_state, dt),
+                DroneCommand::AngleControl if new_sample => self.angle_control(current_state, dt),
+                DroneCommand::Land if new_sample => self.angle_control(current_state, dt), // failsafe sets the sticks
+                // same gyro reading as last time, the motors keep what they had
+                DroneCommand::NormalControl | DroneCommand::AngleControl | DroneCommand::Land => (),
                 _ => self.fall_out_of_the_sky(),
             }
         }
     }
 
-    fn normal_control(
-        &mut self,
-        current_state: DroneCoreState,
-        dt: f32,
-    ) {
-        // black magic
-    }
-
-    fn unreliable_speeds(&mut self, new_speeds: &mut [f32; 4]) {
-        let mut max = new_speeds[0];
-        for i in 1..4 {
-            if new_speeds[i] > max {
-                max = new_speeds[i];
+    // only called while disarmed, a request made while flying waits for the landing
+    fn switch_protocol(&mut self) {
+        if let Some(protocol) = critical_section::with(|cs| PROTOCOL_REQUEST.borrow(cs).take()) {
+            if self.motors.set_protocol(protocol) {
+                info!("motor protocol now {}", protocol);
+            } else {
+                info!("these motors dont switch protocol");
             }
         }
-        if max < 0.05 {
-            self.set_speeds([0.0; 4]);
-        } else {
-            if max > 1.0 {
-                for i in 0..4 {
-                    new_speeds[i] /= max;
+    }
+
+    fn check_motors(&mut self) {
+        let rpm = match self.motors.read_rpm() {
+            Some(rpm) => rpm,
+            None => return,
+        };
+        for (i, reply) in rpm.iter().enumerate() {
+            // missed replies dont count either way
+            if let Ok(r) = *reply {
+                self.motor_rpm[i] = r;
+                if self.last_speeds[i] > STALL_SPEED && r < STALL_RPM {
+                    self.stalled_loops[i] = self.stalled_loops[i].saturating_add(1);
+                } else {
+                    self.stalled_loops[i] = 0;
                 }
             }
-            self.set_speeds(*new_speeds);
+            if self.stalled_loops[i] > STALL_LOOPS && !self.motor_fault {
+                info!("motor {} stalled or desynced, cutting motors", i);
+                self.motor_fault = true;
+            }
+        }
+    }
+
+    pub fn motor_rpm(&self) -> [u32; 4] {
+        self.motor_rpm
+    }
+
+    fn normal_control(&mut self, current_state: DroneCoreState, dt: f32) {
+        let command = current_state.raw_command;
+        match self
+            .stabilizer
+            .rate(&command, current_state.angular_velocity, dt)
+        {
+            Some(correction) => self.mix(command.z_throttle, correction),
+            None => self.set_speeds([0.0; 4]),
+        }
+    }
+
+    fn angle_control(&mut self, current_state: DroneCoreState, dt: f32) {
+        let command = current_state.raw_command;
+        match self.stabilizer.angle(
+            &command,
+            current_state.desired_angle,
+            current_state.true_angle,
+            current_state.angular_velocity,
+            dt,
+        ) {
+            Some(correction) => self.mix(command.z_throttle, correction),
+            None => self.set_speeds([0.0; 4]),
         }
     }
+
     fn set_speeds(&mut self, speeds: [f32; 4]) {
         // clockwise starting at Front Left
-        let mut speedsu16: [u16; 4] = [0; 4]; //inefficient!!
-        for i in 0..4 {
-            speedsu16[i] = (speeds[i] * (MAX_THROTTLE - MIN_THROTTLE) as f32) as u16 + MIN_THROTTLE;
+        self.last_speeds = speeds;
+        self.motors.set_speeds(speeds);
+    }
+
+    // before start or while disarmed, false if the motors are spinning
+    pub fn special_command(&mut self, command: DShotCommand) -> bool {
+        if latest_state().is_some_and(|state| state.armed) {
+            return false;
         }
-        self.fl.set_duty(speedsu16[0]);
-        self.fr.set_duty(speedsu16[1]);
-        self.br.set_duty(speedsu16[2]);
-        self.bl.set_duty(speedsu16[3]);
+        self.motors.special_command(command, &mut clock::ClockDelay);
+        true
     }
 
     fn full_manual(&mut self, command: RadioCommand) {
-        let mut speeds: [f32; 4] = [command.z_throttle; 4];
-        speeds[0] += MAX_THROTTLE_DIFFERENCE
-            * (command.x_throttle - command.y_throttle + command.twist_throttle);
-        speeds[1] += MAX_THROTTLE_DIFFERENCE
-            * (-command.x_throttle - command.y_throttle - command.twist_throttle);
-        speeds[2] += MAX_THROTTLE_DIFFERENCE
-            * (-command.x_throttle + command.y_throttle + command.twist_throttle);
-        speeds[3] += MAX_THROTTLE_DIFFERENCE
-            * (command.x_throttle + command.y_throttle - command.twist_throttle);
-        let mut max = speeds[0];
-        for i in 1..4 {
-            if speeds[i] > max {
-                max = speeds[i];
-            }
-        }
-        if max == 0.0 {
+        if command.z_throttle < IDLE_THROTTLE {
             self.set_speeds([0.0; 4]);
-        } else if max > 1.0 {
-            for i in 0..4 {
-                speeds[i] /= max;
-            }
-            self.set_speeds(speeds);
-        } else {
-            self.set_speeds(speeds);
+            return;
         }
+        self.mix(
+            command.z_throttle,
+            [
+                MAX_THROTTLE_DIFFERENCE * command.x_throttle,
+                MAX_THROTTLE_DIFFERENCE * command.y_throttle,
+                MAX_THROTTLE_DIFFERENCE * command.twist_throttle,
+            ],
+        );
+    }
+
+    // correction is [x, y, twist] in throttle units
+    fn mix(&mut self, throttle: f32, correction: [f32; 3]) {
+        let speeds = self.mixer.mix(throttle, correction);
+        self.set_speeds(speeds);
     }
 
     fn fall_out_of_the_sky(&mut self) {
-        self.set_speeds([0.0; 4]);
+        self.last_speeds = [0.0; 4];
+        self.motors.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::Vec;
+
+    #[test]
+    fn standard_modes_include_self_level() {
+        let config = FlightConfig::standard();
+        assert_eq!(config.mode(0), DroneCommand::FallOutOfTheSky);
+        assert_eq!(config.mode(1), DroneCommand::NormalControl);
+        assert_eq!(config.mode(2), DroneCommand::AngleControl);
+        assert_eq!(config.mode(3), DroneCommand::FallOutOfTheSky);
+    }
+
+    #[test]
+    fn modes_are_configurable_but_zero_stays_off() {
+        let config = FlightConfig {
+            modes: &[
+                DroneCommand::FullManual,
+                DroneCommand::AngleControl,
+                DroneCommand::NormalControl,
+                DroneCommand::FullManual,
+            ],
+            ..FlightConfig::standard()
+        };
+        assert_eq!(config.mode(0), DroneCommand::FallOutOfTheSky);
+        assert_eq!(config.mode(1), DroneCommand::AngleControl);
+        assert_eq!(config.mode(2), DroneCommand::NormalControl);
+        assert_eq!(config.mode(3), DroneCommand::FullManual);
+    }
+    const GOOD: ArmChecks = ArmChecks {
+        imu_healthy: true,
+        radio_fresh: true,
+        calibrated: true,
+        angle: [0.0, 0.0],
+    };
+
+    fn sticks(throttle: f32, twist: f32, mode_select: u8) -> RadioCommand {
+        RadioCommand {
+            z_throttle: throttle,
+            y_throttle: 0.0,
+            x_throttle: 0.0,
+            twist_throttle: twist,
+            mode_select,
+            aux: 0.0,
+        }
+    }
+
+    // holds the sticks from start until just past ARM_HOLD_TICKS, everything that happened on the way
+    fn hold(
+        arming: &mut Arming,
+        start: u64,
+        command: RadioCommand,
+        checks: ArmChecks,
+    ) -> Vec<ArmEvent, 8> {
+        let mut events = Vec::new();
+        for now in (start..=start + ARM_HOLD_TICKS + 1000).step_by(1000) {
+            if let Some(event) = arming.update(now, &command, checks) {
+                events.push(event).unwrap();
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn gestures() {
+        assert_eq!(
+            ArmGesture::from_command(&sticks(0.0, 1.0, 1)),
+            ArmGesture::Arm
+        );
+        assert_eq!(
+            ArmGesture::from_command(&sticks(0.0, -1.0, 1)),
+            ArmGesture::Disarm
+        );
+        assert_eq!(
+            ArmGesture::from_command(&sticks(0.0, 0.5, 1)),
+            ArmGesture::None
+        );
+        // twist with throttle up is flying, not a gesture
+        assert_eq!(
+            ArmGesture::from_command(&sticks(0.5, 1.0, 1)),
+            ArmGesture::None
+        );
+    }
+
+    #[test]
+    fn arms_after_a_held_gesture_and_disarms_the_same_way() {
+        let mut arming = Arming::new();
+        let events = hold(&mut arming, 0, sticks(0.0, 1.0, 1), GOOD);
+        assert_eq!(events.as_slice(), &[ArmEvent::Armed]);
+        assert!(arming.armed());
+        let events = hold(&mut arming, 2 * ARM_HOLD_TICKS, sticks(0.0, -1.0, 1), GOOD);
+        assert_eq!(events.as_slice(), &[ArmEvent::Disarmed]);
+        assert!(!arming.armed());
+    }
+
+    #[test]
+    fn short_gestures_do_nothing() {
+        let mut arming = Arming::new();
+        for now in (0..ARM_HOLD_TICKS).step_by(1000) {
+            assert_eq!(arming.update(now, &sticks(0.0, 1.0, 1), GOOD), None);
+        }
+        // let go just before the second was up, starting again starts the clock again
+        assert_eq!(
+            arming.update(ARM_HOLD_TICKS, &sticks(0.0, 0.0, 1), GOOD),
+            None
+        );
+        assert_eq!(
+            arming.update(ARM_HOLD_TICKS + 1, &sticks(0.0, 1.0, 1), GOOD),
+            None
+        );
+        assert!(!arming.armed());
+    }
+
+    #[test]
+    fn gesture_has_to_be_released_before_it_counts_again() {
+        let mut arming = Arming::new();
+        hold(&mut arming, 0, sticks(0.0, 1.0, 1), GOOD);
+        // still holding the arm gesture, disarming from somewhere else doesnt immediately rearm
+        arming.disarm();
+        let events = hold(
+            &mut arming,
+            ARM_HOLD_TICKS + 2000,
+            sticks(0.0, 1.0, 1),
+            GOOD,
+        );
+        assert!(events.is_empty());
+        assert!(!arming.armed());
+        // a fresh one does
+        arming.update(10 * ARM_HOLD_TICKS, &sticks(0.0, 0.0, 1), GOOD);
+        let events = hold(&mut arming, 11 * ARM_HOLD_TICKS, sticks(0.0, 1.0, 1), GOOD);
+        assert_eq!(events.as_slice(), &[ArmEvent::Armed]);
+    }
+
+    #[test]
+    fn refusals_say_why() {
+        let cases = [
+            (
+                ArmChecks {
+                    calibrated: false,
+                    ..GOOD
+                },
+                ArmRefusal::NotCalibrated,
+            ),
+            (
+                ArmChecks {
+                    imu_healthy: false,
+                    ..GOOD
+                },
+                ArmRefusal::ImuUnhealthy,
+            ),
+            (
+                ArmChecks {
+                    radio_fresh: false,
+                    ..GOOD
+                },
+                ArmRefusal::RadioStale,
+            ),
+            (
+                ArmChecks {
+                    angle: [ARM_MAX_TILT + 1.0, 0.0],
+                    ..GOOD
+                },
+                ArmRefusal::NotLevel,
+            ),
+            (
+                ArmChecks {
+                    angle: [0.0, -ARM_MAX_TILT - 1.0],
+                    ..GOOD
+                },
+                ArmRefusal::NotLevel,
+            ),
+        ];
+        for (checks, reason) in cases {
+            let mut arming = Arming::new();
+            let events = hold(&mut arming, 0, sticks(0.0, 1.0, 1), checks);
+            assert_eq!(events.as_slice(), &[ArmEvent::Refused(reason)]);
+            assert!(!arming.armed());
+        }
+        assert_eq!(
+            GOOD.check(&sticks(0.0, 1.0, 0)),
+            Err(ArmRefusal::KillSwitch)
+        );
+        assert_eq!(
+            GOOD.check(&sticks(0.5, 1.0, 1)),
+            Err(ArmRefusal::ThrottleNotLow)
+        );
+        assert_eq!(GOOD.check(&sticks(0.0, 1.0, 1)), Ok(()));
+    }
+
+    #[test]
+    fn kill_switch_disarms_straight_away() {
+        let mut arming = Arming::new();
+        hold(&mut arming, 0, sticks(0.0, 1.0, 1), GOOD);
+        assert!(arming.armed());
+        // mid flight, throttle up, no hold needed
+        let now = 5 * ARM_HOLD_TICKS;
+        assert_eq!(
+            arming.update(now, &sticks(0.6, 0.0, 0), GOOD),
+            Some(ArmEvent::Disarmed)
+        );
+        assert!(!arming.armed());
+        assert_eq!(arming.update(now + 1, &sticks(0.6, 0.0, 0), GOOD), None);
+        // and arming with it off gets refused
+        let events = hold(&mut arming, 10 * ARM_HOLD_TICKS, sticks(0.0, 1.0, 0), GOOD);
+        assert_eq!(
+            events.as_slice(),
+            &[ArmEvent::Refused(ArmRefusal::KillSwitch)]
+        );
+    }
+
+    #[test]
+    fn disarm_only_reports_once() {
+        let mut arming = Arming::new();
+        assert_eq!(arming.disarm(), None);
+        hold(&mut arming, 0, sticks(0.0, 1.0, 1), GOOD);
+        assert_eq!(arming.disarm(), Some(ArmEvent::Disarmed));
+        assert_eq!(arming.disarm(), None);
     }
 }
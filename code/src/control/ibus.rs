@@ -0,0 +1,351 @@
+// FlySky iBUS servo frames (FS-iA6B on the servo/ibus pin), 115200 8N1
+// frame is 0x20 0x40, 14 little endian channels, then a little endian checksum
+use super::radio::{RadioError, RadioFrame, RadioPacket, Receiver, RADIO_CHANNELS};
+use fugit::RateExtU32;
+use hal::uart::{DataBits, StopBits, UartConfig};
+use rp2040_hal as hal;
+
+const IBUS_LENGTH: u8 = 0x20; // first byte of every frame
+const IBUS_COMMAND: u8 = 0x40; // servo data, the only thing the receiver sends on this pin
+pub const IBUS_FRAME_LEN: usize = 32;
+const IBUS_SLOTS: usize = 14; // 15-18 are hidden in the top nibbles of 1-12
+
+// byte at a time ibus state machine, doesnt touch hardware
+pub struct IbusReceiver {
+    buf: [u8; IBUS_FRAME_LEN],
+    pos: usize,
+}
+
+impl IbusReceiver {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; IBUS_FRAME_LEN],
+            pos: 0,
+        }
+    }
+}
+
+impl Default for IbusReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Receiver for IbusReceiver {
+    fn uart_config(&self) -> UartConfig {
+        UartConfig::new(115200.Hz(), DataBits::Eight, None, StopBits::One)
+    }
+
+    fn reset(&mut self) {
+        self.pos = 0;
+    }
+
+    // Some once a whole frame has gone past, good or bad
+    fn push(&mut self, byte: u8) -> Option<Result<RadioPacket, RadioError>> {
+        match self.pos {
+            0 if byte != IBUS_LENGTH => return None,
+            1 if byte != IBUS_COMMAND => {
+                // a 0x20 could still be the start of the real frame
+                self.pos = (byte == IBUS_LENGTH) as usize;
+                return None;
+            }
+            _ => (),
+        }
+        self.buf[self.pos] = byte;
+        self.pos += 1;
+        if self.pos < IBUS_FRAME_LEN {
+            return None;
+        }
+        self.reset();
+        let result = decode_ibus(&self.buf);
+        if result.is_err() {
+            // probably synced on a 0x20 0x40 in the middle of a frame, carry on from the next
+            // place a header could start. whatever is left is shorter than a frame so it can
+            // just be moved down, runs in the interrupt so no replaying it through push
+            let next = (1..IBUS_FRAME_LEN).find(|&i| {
+                self.buf[i] == IBUS_LENGTH && self.buf.get(i + 1).is_none_or(|b| *b == IBUS_COMMAND)
+            });
+            if let Some(start) = next {
+                self.buf.copy_within(start.., 0);
+                self.pos = IBUS_FRAME_LEN - start;
+            }
+        }
+        Some(result.map(RadioPacket::Channels))
+    }
+}
+
+// same on the servo and sensor pins: 0xFFFF minus the sum of every byte before it
+pub fn ibus_checksum(bytes: &[u8]) -> u16 {
+    let mut checksum: u16 = 0xffff;
+    for b in bytes {
+        checksum = checksum.wrapping_sub(*b as u16);
+    }
+    checksum
+}
+
+pub fn ibus_checksum_ok(frame: &[u8; IBUS_FRAME_LEN]) -> bool {
+    ibus_checksum(&frame[..IBUS_FRAME_LEN - 2])
+        == u16::from_le_bytes([frame[IBUS_FRAME_LEN - 2], frame[IBUS_FRAME_LEN - 1]])
+}
+
+// raw values come out in microseconds, 0 for channels the receiver doesnt have
+pub fn decode_ibus(frame: &[u8; IBUS_FRAME_LEN]) -> Result<RadioFrame, RadioError> {
+    if frame[0] != IBUS_LENGTH || frame[1] != IBUS_COMMAND || !ibus_checksum_ok(frame) {
+        return Err(RadioError::ChecksumError);
+    }
+    let mut raw = [0; RADIO_CHANNELS];
+    for i in 0..IBUS_SLOTS {
+        let lo = frame[2 + i * 2] as u16;
+        let hi = frame[3 + i * 2] as u16;
+        raw[i] = lo | ((hi & 0x0F) << 8);
+    }
+    // newer FS-iA6B/iA10B firmware: each extra channel is the top nibbles of three high bytes
+    for i in 0..RADIO_CHANNELS - IBUS_SLOTS {
+        let hi = |slot: usize| frame[3 + slot * 2] as u16 & 0xF0;
+        raw[IBUS_SLOTS + i] = (hi(i * 3) >> 4) | hi(i * 3 + 1) | (hi(i * 3 + 2) << 4);
+    }
+    Ok(RadioFrame::from_raw(raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the FS-iA6B frame that gets passed around in ibus write-ups, 14 channel firmware so
+    // 15-18 read 0. sticks a little off center, throttle down, switches at both ends
+    const IA6B_CAPTURE: [u8; IBUS_FRAME_LEN] = [
+        0x20, 0x40, 0xDB, 0x05, 0xDC, 0x05, 0x54, 0x05, 0xDC, 0x05, 0xE8, 0x03, 0xD0, 0x07, 0xD2,
+        0x05, 0xE8, 0x03, 0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05, 0xDC, 0x05,
+        0xDA, 0xF3,
+    ];
+    const IA6B_CHANNELS: [u16; RADIO_CHANNELS] = [
+        1499, 1500, 1364, 1500, 1000, 2000, 1490, 1000, 1500, 1500, 1500, 1500, 1500, 1500, 0, 0,
+        0, 0,
+    ];
+
+    // 18 channel firmware with 15-18 at 1000, 2000, 1500 and 1234. not a capture, written out
+    // nibble by nibble from the layout betaflight's ibus.c reads until there is one off a real
+    // receiver: 15 rides on the high bytes of 1-3 lowest nibble first, 16 on 4-6 and so on
+    const EXTENDED_FRAME: [u8; IBUS_FRAME_LEN] = [
+        0x20, 0x40, // header
+        0xDC, 0x85, 0xDC, 0xE5, 0xE8, 0x33, // 1500 1500 1000, 15 = 0x3E8
+        0xDC, 0x05, 0xD0, 0xD7, 0xE8, 0x73, // 1500 2000 1000, 16 = 0x7D0
+        0xDC, 0xC5, 0xDC, 0xD5, 0xDC, 0x55, // 1500 1500 1500, 17 = 0x5DC
+        0xDC, 0x25, 0xDC, 0xD5, 0xDC, 0x45, // 1500 1500 1500, 18 = 0x4D2
+        0xDC, 0x05, 0xDC, 0x05, // 13 and 14 carry nothing extra
+        0x67, 0xED, // checksum
+    ];
+    const EXTENDED_CHANNELS: [u16; RADIO_CHANNELS] = [
+        1500, 1500, 1000, 1500, 2000, 1000, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1500, 1000,
+        2000, 1500, 1234,
+    ];
+
+    const FIXTURES: [([u8; IBUS_FRAME_LEN], [u16; RADIO_CHANNELS]); 2] = [
+        (IA6B_CAPTURE, IA6B_CHANNELS),
+        (EXTENDED_FRAME, EXTENDED_CHANNELS),
+    ];
+
+    // what a receiver with the extended encoding sends for these channels
+    fn frame(raw: [u16; RADIO_CHANNELS]) -> [u8; IBUS_FRAME_LEN] {
+        let mut frame = [0; IBUS_FRAME_LEN];
+        frame[0] = IBUS_LENGTH;
+        frame[1] = IBUS_COMMAND;
+        for i in 0..IBUS_SLOTS {
+            frame[2 + i * 2] = raw[i] as u8;
+            frame[3 + i * 2] = (raw[i] >> 8) as u8 & 0x0F;
+        }
+        for i in 0..RADIO_CHANNELS - IBUS_SLOTS {
+            let extra = raw[IBUS_SLOTS + i];
+            for n in 0..3 {
+                frame[3 + (i * 3 + n) * 2] |= ((extra >> (4 * n)) as u8 & 0x0F) << 4;
+            }
+        }
+        let checksum = (0xFFFF - frame[..30].iter().map(|b| *b as u16).sum::<u16>()).to_le_bytes();
+        frame[30] = checksum[0];
+        frame[31] = checksum[1];
+        frame
+    }
+
+    #[test]
+    fn decodes_the_fixtures() {
+        for (bytes, channels) in FIXTURES {
+            let decoded = decode_ibus(&bytes).ok().unwrap();
+            assert_eq!(decoded.raw, channels);
+            assert!(!decoded.frame_lost && !decoded.failsafe);
+            // the helper the other tests build frames with has to agree with them
+            assert_eq!(frame(channels), bytes);
+        }
+        let decoded = decode_ibus(&IA6B_CAPTURE).ok().unwrap();
+        assert_eq!(decoded.normalized[4], 0.0);
+        assert_eq!(decoded.normalized[5], 1.0);
+        assert_eq!(decoded.centered(1), 0.0);
+    }
+
+    #[test]
+    fn fixtures_in_a_stream() {
+        // half a frame from before the receiver was listening, then the fixtures back to back
+        let mut stream = IA6B_CAPTURE[17..].to_vec();
+        for (bytes, _) in FIXTURES {
+            stream.extend_from_slice(&bytes);
+        }
+        stream.extend_from_slice(&[0x00, 0x20]);
+        stream.extend_from_slice(&EXTENDED_FRAME);
+        let mut receiver = IbusReceiver::new();
+        assert_eq!(
+            feed(&mut receiver, &stream),
+            [
+                Ok(IA6B_CHANNELS),
+                Ok(EXTENDED_CHANNELS),
+                Ok(EXTENDED_CHANNELS)
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_every_channel() {
+        let mut ramp = [0; RADIO_CHANNELS];
+        for (i, ch) in ramp.iter_mut().enumerate() {
+            *ch = 1000 + 55 * i as u16;
+        }
+        let mut alternating = [1000; RADIO_CHANNELS];
+        for ch in alternating.iter_mut().step_by(2) {
+            *ch = 2000;
+        }
+        let cases = [
+            [1500; RADIO_CHANNELS],
+            [1000; RADIO_CHANNELS],
+            [2000; RADIO_CHANNELS],
+            ramp,
+            alternating,
+            // the extended channels use all 12 bits, the slots they ride on are untouched
+            {
+                let mut raw = [1500; RADIO_CHANNELS];
+                raw[14..].copy_from_slice(&[0xFFF, 0x000, 0xABC, 0x123]);
+                raw
+            },
+        ];
+        for raw in cases {
+            assert_eq!(decode_ibus(&frame(raw)).ok().unwrap().raw, raw);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let good = frame([1500; RADIO_CHANNELS]);
+        let mut cases = [good; 5];
+        cases[0][0] = 0x21; // wrong length
+        cases[1][1] = 0x41; // not servo data
+        cases[2][10] ^= 0x01; // a flipped bit
+        cases[3][30] ^= 0x80; // checksum itself damaged
+        cases[4][31] ^= 0x01;
+        for frame in cases {
+            assert!(matches!(
+                decode_ibus(&frame),
+                Err(RadioError::ChecksumError)
+            ));
+        }
+        assert!(ibus_checksum_ok(&good));
+    }
+
+    // every frame that came out of a byte stream, Err for the ones that failed
+    fn feed(receiver: &mut IbusReceiver, bytes: &[u8]) -> Vec<Result<[u16; RADIO_CHANNELS], ()>> {
+        bytes
+            .iter()
+            .filter_map(|b| receiver.push(*b))
+            .map(|result| match result {
+                Ok(RadioPacket::Channels(frame)) => Ok(frame.raw),
+                _ => Err(()),
+            })
+            .collect()
+    }
+
+    fn channels(value: u16) -> [u16; RADIO_CHANNELS] {
+        let mut raw = [value; RADIO_CHANNELS];
+        raw[14..].copy_from_slice(&[0; 4]);
+        raw
+    }
+
+    #[test]
+    fn frames_back_to_back() {
+        let mut receiver = IbusReceiver::new();
+        let mut stream = Vec::new();
+        for value in [1100, 1200, 1300] {
+            stream.extend_from_slice(&frame(channels(value)));
+        }
+        let frames = feed(&mut receiver, &stream);
+        assert_eq!(
+            frames,
+            [Ok(channels(1100)), Ok(channels(1200)), Ok(channels(1300))]
+        );
+    }
+
+    #[test]
+    fn partial_frames_wait() {
+        let mut receiver = IbusReceiver::new();
+        let whole = frame(channels(1500));
+        assert!(feed(&mut receiver, &whole[..31]).is_empty());
+        assert_eq!(feed(&mut receiver, &whole[31..]), [Ok(channels(1500))]);
+        // reset throws away half a frame, like after a uart error
+        feed(&mut receiver, &whole[..20]);
+        receiver.reset();
+        assert_eq!(feed(&mut receiver, &whole), [Ok(channels(1500))]);
+    }
+
+    #[test]
+    fn skips_junk_between_frames() {
+        let mut receiver = IbusReceiver::new();
+        let mut stream = vec![0x00, 0xFF, 0x40, 0x20, 0x41, 0x13];
+        stream.extend_from_slice(&frame(channels(1500)));
+        stream.extend_from_slice(&[0x20, 0x20]);
+        stream.extend_from_slice(&frame(channels(1600)));
+        assert_eq!(
+            feed(&mut receiver, &stream),
+            [Ok(channels(1500)), Ok(channels(1600))]
+        );
+    }
+
+    #[test]
+    fn checksum_failures_are_reported_and_the_next_frame_still_decodes() {
+        let mut receiver = IbusReceiver::new();
+        let mut bad = frame(channels(1500));
+        bad[12] ^= 0x01;
+        let mut stream = bad.to_vec();
+        stream.extend_from_slice(&frame(channels(1700)));
+        assert_eq!(feed(&mut receiver, &stream), [Err(()), Ok(channels(1700))]);
+    }
+
+    #[test]
+    fn resyncs_off_a_header_inside_a_frame() {
+        // channel 4 reads 0x20 0x40 on the wire, so joining the stream at the wrong moment
+        // locks onto it. the failed frame has the real next header in it
+        let mut inside = channels(1500);
+        inside[3] = 0x020;
+        inside[15] = 0x004;
+        let first = frame(inside);
+        assert_eq!(&first[8..10], &[IBUS_LENGTH, IBUS_COMMAND]);
+        let mut stream = first[8..].to_vec();
+        stream.extend_from_slice(&frame(channels(1200)));
+        stream.extend_from_slice(&frame(channels(1300)));
+        let mut receiver = IbusReceiver::new();
+        let frames = feed(&mut receiver, &stream);
+        assert_eq!(frames, [Err(()), Ok(channels(1200)), Ok(channels(1300))]);
+    }
+
+    #[test]
+    fn resyncs_off_a_header_split_over_the_end() {
+        // the failed frame ends in 0x20, the 0x40 is the first byte of the next push
+        let mut receiver = IbusReceiver::new();
+        let mut bad = frame(channels(1500));
+        bad[0] = IBUS_LENGTH;
+        bad[31] = IBUS_LENGTH;
+        bad[30] ^= 0xFF;
+        let good = frame(channels(1200));
+        let mut stream = bad.to_vec();
+        stream.extend_from_slice(&good[1..]);
+        stream.extend_from_slice(&frame(channels(1300)));
+        assert_eq!(
+            feed(&mut receiver, &stream),
+            [Err(()), Ok(channels(1200)), Ok(channels(1300))]
+        );
+    }
+}
@@ -0,0 +1,437 @@
+// FlySky iBUS sensor port, on UART0 (tx gpio16, rx gpio17 tied together through a 1k resistor)
+// the receiver polls every sensor address with 4 byte packets: length, command | address, checksum
+// 0x8x is discovery (answered with the same 4 bytes), 0x9x asks for the sensor type, 0xAx for the value
+// checksum is 0xFFFF minus the sum of every byte before it, little endian
+// the line is half duplex so everything we send comes straight back and has to be ignored
+use core::cell::RefCell;
+use cortex_m::prelude::_embedded_hal_serial_Read;
+use critical_section::Mutex;
+use defmt::Format;
+use fugit::RateExtU32;
+use hal::gpio::bank0::{Gpio16, Gpio17};
+use hal::gpio::{Function, Pin};
+use hal::pac::{self, interrupt};
+use hal::uart::{DataBits, StopBits, UartConfig, UartPeripheral};
+use rp2040_hal as hal;
+
+use crate::control::flight_system::{latest_state, DroneCommand, DroneCoreState};
+use crate::control::ibus::ibus_checksum;
+
+const IBUS_CMD_DISCOVER: u8 = 0x80;
+const IBUS_CMD_TYPE: u8 = 0x90;
+const IBUS_CMD_MEASURE: u8 = 0xA0;
+const IBUS_POLL_LEN: usize = 4;
+const IBUS_VALUE_LEN: u8 = 2; // every sensor here is 16 bit
+const MAX_RESPONSE_LEN: usize = 6;
+const MAX_ADDRESS: u8 = 15;
+
+// what the transmitter knows how to display, values are the iBUS sensor type ids
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum IbusSensor {
+    BatteryVoltage = 0x03, // 0.01 V
+    Heading = 0x08,        // degrees
+    Roll = 0x0f,           // 0.01 degree
+    Pitch = 0x10,          // 0.01 degree
+    Armed = 0x15,          // 0 or 1
+    FlightMode = 0x16,     // 0 stab, 1 acro, 9 land
+}
+
+impl IbusSensor {
+    pub fn type_id(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn value(&self, state: &DroneCoreState) -> u16 {
+        let centi = |x: f32| (x * 100.0) as i16 as u16;
+        match self {
+            Self::BatteryVoltage => (state.battery_voltage() * 100.0) as u16,
+            Self::Heading => state.heading() as u16,
+            Self::Roll => centi(state.angle()[0]),
+            Self::Pitch => centi(state.angle()[1]),
+            Self::Armed => state.armed() as u16,
+            Self::FlightMode => match state.command() {
+                DroneCommand::NormalControl | DroneCommand::FullManual => 1,
+                DroneCommand::Land => 9,
+                _ => 0,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum IbusPoll {
+    Discover(u8),
+    Type(u8),
+    Measure(u8),
+}
+
+// byte at a time, throws away anything that isnt a valid poll
+pub struct PollParser {
+    buf: [u8; IBUS_POLL_LEN],
+    pos: usize,
+}
+
+impl PollParser {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; IBUS_POLL_LEN],
+            pos: 0,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<IbusPoll> {
+        if self.pos == 0 && byte != IBUS_POLL_LEN as u8 {
+            return None;
+        }
+        self.buf[self.pos] = byte;
+        self.pos += 1;
+        if self.pos < IBUS_POLL_LEN {
+            return None;
+        }
+        self.pos = 0;
+        let checksum = u16::from_le_bytes([self.buf[2], self.buf[3]]);
+        if checksum != ibus_checksum(&self.buf[..2]) {
+            return None;
+        }
+        let address = self.buf[1] & 0x0F;
+        match self.buf[1] & 0xF0 {
+            IBUS_CMD_DISCOVER => Some(IbusPoll::Discover(address)),
+            IBUS_CMD_TYPE => Some(IbusPoll::Type(address)),
+            IBUS_CMD_MEASURE => Some(IbusPoll::Measure(address)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for PollParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IbusResponse {
+    buf: [u8; MAX_RESPONSE_LEN],
+    len: usize,
+}
+
+impl IbusResponse {
+    fn new(payload: &[u8]) -> Self {
+        let mut buf = [0; MAX_RESPONSE_LEN];
+        let len = payload.len() + 3;
+        buf[0] = len as u8;
+        buf[1..len - 2].copy_from_slice(payload);
+        let checksum = ibus_checksum(&buf[..len - 2]).to_le_bytes();
+        buf[len - 2] = checksum[0];
+        buf[len - 1] = checksum[1];
+        Self { buf, len }
+    }
+
+    pub fn discover(address: u8) -> Self {
+        Self::new(&[IBUS_CMD_DISCOVER | address])
+    }
+
+    pub fn sensor_type(address: u8, sensor: IbusSensor) -> Self {
+        Self::new(&[IBUS_CMD_TYPE | address, sensor.type_id(), IBUS_VALUE_LEN])
+    }
+
+    pub fn measurement(address: u8, value: u16) -> Self {
+        let [lo, hi] = value.to_le_bytes();
+        Self::new(&[IBUS_CMD_MEASURE | address, lo, hi])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+// works out which polls are for us and what to say back, no hardware in here
+pub struct IbusResponder {
+    sensors: &'static [IbusSensor],
+    base_address: Option<u8>, // first address the receiver asks about, the ones before belong to it
+    parser: PollParser,
+}
+
+impl IbusResponder {
+    pub const fn new(sensors: &'static [IbusSensor]) -> Self {
+        Self {
+            sensors,
+            base_address: None,
+            parser: PollParser::new(),
+        }
+    }
+
+    fn sensor(&self, address: u8) -> Option<IbusSensor> {
+        let base = self.base_address?;
+        if address < base {
+            return None;
+        }
+        self.sensors.get((address - base) as usize).copied()
+    }
+
+    pub fn push(&mut self, byte: u8, state: Option<&DroneCoreState>) -> Option<IbusResponse> {
+        match self.parser.push(byte)? {
+            IbusPoll::Discover(address) => {
+                if self.base_address.is_none() && address != 0 && address <= MAX_ADDRESS {
+                    self.base_address = Some(address);
+                }
+                self.sensor(address)?;
+                Some(IbusResponse::discover(address))
+            }
+            IbusPoll::Type(address) => {
+                Some(IbusResponse::sensor_type(address, self.sensor(address)?))
+            }
+            IbusPoll::Measure(address) => {
+                let value = self.sensor(address)?.value(state?);
+                Some(IbusResponse::measurement(address, value))
+            }
+        }
+    }
+}
+
+type TelemetryUart = UartPeripheral<
+    hal::uart::Enabled,
+    pac::UART0,
+    (
+        Pin<Gpio16, Function<hal::gpio::Uart>>,
+        Pin<Gpio17, Function<hal::gpio::Uart>>,
+    ),
+>;
+
+struct IbusTelemetry {
+    uart: TelemetryUart,
+    responder: IbusResponder,
+    echo: usize, // bytes of our own reply still to come back
+}
+
+static IBUS_TELEMETRY: Mutex<RefCell<Option<IbusTelemetry>>> = Mutex::new(RefCell::new(None));
+
+impl IbusTelemetry {
+    fn service(&mut self) {
+        while let Ok(byte) = self.uart.read() {
+            if self.echo > 0 {
+                self.echo -= 1;
+                continue;
+            }
+            let state = latest_state();
+            if let Some(response) = self.responder.push(byte, state.as_ref()) {
+                // fifo is empty, whole reply fits
+                let _ = self.uart.write_raw(response.as_bytes());
+                self.echo = response.as_bytes().len();
+            }
+        }
+    }
+}
+
+#[interrupt]
+fn UART0_IRQ() {
+    critical_section::with(|cs| {
+        if let Some(telemetry) = IBUS_TELEMETRY.borrow_ref_mut(cs).as_mut() {
+            telemetry.service();
+        }
+    });
+}
+
+// has to be called from core0, core1 writes the state and would deadlock reading it from an interrupt
+pub fn start_ibus_telemetry(
+    uart: pac::UART0,
+    tx: Pin<Gpio16, Function<hal::gpio::Uart>>,
+    rx: Pin<Gpio17, Function<hal::gpio::Uart>>,
+    resets: &mut pac::RESETS,
+    peripheral_clock_freq: fugit::HertzU32,
+    sensors: &'static [IbusSensor],
+) {
+    let mut uart = UartPeripheral::new(uart, (tx, rx), resets)
+        .enable(
+            UartConfig::new(115200.Hz(), DataBits::Eight, None, StopBits::One),
+            peripheral_clock_freq,
+        )
+        .unwrap();
+    uart.set_fifos(true);
+    uart.enable_rx_interrupt();
+    critical_section::with(|cs| {
+        IBUS_TELEMETRY.borrow(cs).replace(Some(IbusTelemetry {
+            uart,
+            responder: IbusResponder::new(sensors),
+            echo: 0,
+        }));
+    });
+    unsafe {
+        pac::NVIC::unmask(pac::Interrupt::UART0_IRQ);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENSORS: &[IbusSensor] = &[
+        IbusSensor::BatteryVoltage,
+        IbusSensor::Armed,
+        IbusSensor::FlightMode,
+        IbusSensor::Roll,
+    ];
+
+    fn poll(command: u8, address: u8) -> [u8; IBUS_POLL_LEN] {
+        let mut bytes = [IBUS_POLL_LEN as u8, command | address, 0, 0];
+        let checksum = ibus_checksum(&bytes[..2]).to_le_bytes();
+        bytes[2..].copy_from_slice(&checksum);
+        bytes
+    }
+
+    fn state() -> DroneCoreState {
+        DroneCoreState::for_test(
+            [-12.5, 3.0],
+            271.9,
+            true,
+            11.84,
+            DroneCommand::NormalControl,
+        )
+    }
+
+    // feeds a whole packet, the answer to its last byte
+    fn send(
+        responder: &mut IbusResponder,
+        bytes: &[u8],
+        state: Option<&DroneCoreState>,
+    ) -> Option<IbusResponse> {
+        let mut response = None;
+        for b in bytes {
+            response = responder.push(*b, state);
+        }
+        response
+    }
+
+    #[test]
+    fn checksum() {
+        // discovery of sensor 1, straight off the wire
+        assert_eq!(poll(IBUS_CMD_DISCOVER, 1), [0x04, 0x81, 0x7A, 0xFF]);
+        assert_eq!(ibus_checksum(&[]), 0xFFFF);
+    }
+
+    #[test]
+    fn parses_polls() {
+        let mut parser = PollParser::new();
+        let cases = [
+            (poll(IBUS_CMD_DISCOVER, 1), IbusPoll::Discover(1)),
+            (poll(IBUS_CMD_TYPE, 2), IbusPoll::Type(2)),
+            (poll(IBUS_CMD_MEASURE, 15), IbusPoll::Measure(15)),
+        ];
+        for (bytes, expected) in cases {
+            assert_eq!(parser.push(bytes[0]), None);
+            assert_eq!(parser.push(bytes[1]), None);
+            assert_eq!(parser.push(bytes[2]), None);
+            assert_eq!(parser.push(bytes[3]), Some(expected));
+        }
+    }
+
+    #[test]
+    fn ignores_junk() {
+        let mut parser = PollParser::new();
+        // bad checksum, unknown command, then garbage that isnt a length byte
+        let mut bad = poll(IBUS_CMD_MEASURE, 1);
+        bad[2] ^= 1;
+        let unknown = poll(0xC0, 1);
+        for b in bad
+            .iter()
+            .chain(unknown.iter())
+            .chain([0x00, 0xFF, 0x81].iter())
+        {
+            assert_eq!(parser.push(*b), None);
+        }
+        // and still picks up the next good one
+        let good = poll(IBUS_CMD_TYPE, 3);
+        let results: Vec<_> = good.iter().map(|b| parser.push(*b)).collect();
+        assert_eq!(results.last().unwrap(), &Some(IbusPoll::Type(3)));
+    }
+
+    #[test]
+    fn responses_are_framed() {
+        assert_eq!(
+            IbusResponse::discover(1).as_bytes(),
+            &[0x04, 0x81, 0x7A, 0xFF]
+        );
+        let sensor_type = IbusResponse::sensor_type(2, IbusSensor::BatteryVoltage);
+        let bytes = sensor_type.as_bytes();
+        assert_eq!(&bytes[..4], &[0x06, 0x92, 0x03, 0x02]);
+        assert_eq!(
+            u16::from_le_bytes([bytes[4], bytes[5]]),
+            ibus_checksum(&bytes[..4])
+        );
+        let measurement = IbusResponse::measurement(3, 0x1234);
+        let bytes = measurement.as_bytes();
+        assert_eq!(&bytes[..4], &[0x06, 0xA3, 0x34, 0x12]);
+        assert_eq!(
+            u16::from_le_bytes([bytes[4], bytes[5]]),
+            ibus_checksum(&bytes[..4])
+        );
+    }
+
+    #[test]
+    fn sensor_values() {
+        let state = state();
+        assert_eq!(IbusSensor::BatteryVoltage.value(&state), 1184);
+        assert_eq!(IbusSensor::Heading.value(&state), 271);
+        assert_eq!(IbusSensor::Roll.value(&state), -1250i16 as u16);
+        assert_eq!(IbusSensor::Pitch.value(&state), 300);
+        assert_eq!(IbusSensor::Armed.value(&state), 1);
+        assert_eq!(IbusSensor::FlightMode.value(&state), 1);
+        let landing = DroneCoreState::for_test([0.0; 2], 0.0, false, 0.0, DroneCommand::Land);
+        assert_eq!(IbusSensor::FlightMode.value(&landing), 9);
+        assert_eq!(IbusSensor::Armed.value(&landing), 0);
+        let level = DroneCoreState::for_test([0.0; 2], 0.0, true, 0.0, DroneCommand::AngleControl);
+        assert_eq!(IbusSensor::FlightMode.value(&level), 0);
+    }
+
+    #[test]
+    fn answers_after_the_receivers_own_sensors() {
+        // receiver keeps address 1 for its own voltage, so discovery starts at 2
+        let mut responder = IbusResponder::new(SENSORS);
+        let state = state();
+        let discovered: Vec<_> = (2..=15)
+            .map(|address| {
+                send(
+                    &mut responder,
+                    &poll(IBUS_CMD_DISCOVER, address),
+                    Some(&state),
+                )
+            })
+            .collect();
+        for (i, response) in discovered.iter().enumerate() {
+            let address = i as u8 + 2;
+            if i < SENSORS.len() {
+                assert_eq!(
+                    response.unwrap().as_bytes(),
+                    IbusResponse::discover(address).as_bytes()
+                );
+            } else {
+                assert_eq!(*response, None, "address {}", address);
+            }
+        }
+        // addresses line up with the sensor list from there
+        let response = send(&mut responder, &poll(IBUS_CMD_TYPE, 3), Some(&state)).unwrap();
+        assert_eq!(response, IbusResponse::sensor_type(3, IbusSensor::Armed));
+        let response = send(&mut responder, &poll(IBUS_CMD_MEASURE, 2), Some(&state)).unwrap();
+        assert_eq!(response, IbusResponse::measurement(2, 1184));
+        assert_eq!(
+            send(&mut responder, &poll(IBUS_CMD_MEASURE, 1), Some(&state)),
+            None
+        );
+    }
+
+    #[test]
+    fn stays_quiet_before_discovery_and_without_state() {
+        let mut responder = IbusResponder::new(SENSORS);
+        assert_eq!(
+            send(&mut responder, &poll(IBUS_CMD_MEASURE, 2), Some(&state())),
+            None
+        );
+        assert_eq!(
+            send(&mut responder, &poll(IBUS_CMD_TYPE, 2), Some(&state())),
+            None
+        );
+        assert!(send(&mut responder, &poll(IBUS_CMD_DISCOVER, 2), None).is_some());
+        // flight loop hasnt written anything yet, nothing to measure
+        assert_eq!(send(&mut responder, &poll(IBUS_CMD_MEASURE, 2), None), None);
+    }
+}
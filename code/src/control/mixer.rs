@@ -0,0 +1,226 @@
+// turns throttle plus [x, y, twist] corrections into per motor speeds
+// one row per motor, motors go clockwise starting at Front Left (Front for quad plus)
+// x positive speeds up the left side, y positive speeds up the back, twist alternates with prop direction
+
+const SIN_30: f32 = 0.5;
+const COS_30: f32 = 0.866_025_4;
+
+#[derive(Clone, Copy)]
+pub struct MixerRow {
+    pub x: f32,
+    pub y: f32,
+    pub twist: f32,
+    pub throttle: f32,
+}
+
+const fn row(x: f32, y: f32, twist: f32) -> MixerRow {
+    MixerRow {
+        x,
+        y,
+        twist,
+        throttle: 1.0,
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Mixer<const N: usize> {
+    rows: [MixerRow; N],
+}
+
+impl Mixer<4> {
+    pub fn quad_x() -> Self {
+        Self::custom([
+            row(1.0, -1.0, 1.0),   // front left
+            row(-1.0, -1.0, -1.0), // front right
+            row(-1.0, 1.0, 1.0),   // back right
+            row(1.0, 1.0, -1.0),   // back left
+        ])
+    }
+
+    pub fn quad_plus() -> Self {
+        Self::custom([
+            row(0.0, -1.0, 1.0),  // front
+            row(-1.0, 0.0, -1.0), // right
+            row(0.0, 1.0, 1.0),   // back
+            row(1.0, 0.0, -1.0),  // left
+        ])
+    }
+}
+
+// the mixer doesnt care how many rows there are, only the motor outputs stop at four
+impl Mixer<6> {
+    pub fn hex() -> Self {
+        Self::custom([
+            row(SIN_30, -COS_30, 1.0),   // front left
+            row(-SIN_30, -COS_30, -1.0), // front right
+            row(-1.0, 0.0, 1.0),         // right
+            row(-SIN_30, COS_30, -1.0),  // back right
+            row(SIN_30, COS_30, 1.0),    // back left
+            row(1.0, 0.0, -1.0),         // left
+        ])
+    }
+}
+
+impl<const N: usize> Mixer<N> {
+    pub fn custom(rows: [MixerRow; N]) -> Self {
+        Self { rows }
+    }
+
+    // correction is in throttle units, output is 0 to 1 per motor
+    // when the corrections dont fit they get scaled down together and throttle moves to make room,
+    // so the drone keeps turning the way it was asked instead of losing whichever axis saturated
+    pub fn mix(&self, throttle: f32, correction: [f32; 3]) -> [f32; N] {
+        let [x, y, twist] = correction;
+        let mut attitude = [0.0; N];
+        let mut min = f32::MAX;
+        let mut max = f32::MIN;
+        for (a, r) in attitude.iter_mut().zip(self.rows.iter()) {
+            *a = r.x * x + r.y * y + r.twist * twist;
+            min = min.min(*a);
+            max = max.max(*a);
+        }
+        let range = max - min;
+        if range > 1.0 {
+            for a in attitude.iter_mut() {
+                *a /= range;
+            }
+            min /= range;
+            max /= range;
+        }
+        let throttle = throttle.max(-min).min(1.0 - max);
+        core::array::from_fn(|i| (self.rows[i].throttle * throttle + attitude[i]).clamp(0.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    // which motors are faster than the others for a small correction on one axis
+    fn faster(mixer: &Mixer<4>, correction: [f32; 3]) -> [bool; 4] {
+        mixer.mix(0.5, correction).map(|speed| speed > 0.5)
+    }
+
+    #[test]
+    fn quad_x_directions() {
+        let mixer = Mixer::quad_x();
+        // fl, fr, br, bl
+        assert_eq!(faster(&mixer, [0.1, 0.0, 0.0]), [true, false, false, true]); // left side
+        assert_eq!(faster(&mixer, [0.0, 0.1, 0.0]), [false, false, true, true]); // back
+        assert_eq!(faster(&mixer, [0.0, 0.0, 0.1]), [true, false, true, false]);
+        // diagonals, the two axes cancel on the motors between them
+        assert_eq!(faster(&mixer, [0.1, 0.1, 0.0]), [false, false, false, true]);
+        assert_eq!(
+            faster(&mixer, [0.1, -0.1, 0.0]),
+            [true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn quad_plus_directions() {
+        let mixer = Mixer::quad_plus();
+        // front, right, back, left, motors on an axis dont move for the other one
+        assert_eq!(mixer.mix(0.5, [0.1, 0.0, 0.0]), [0.5, 0.4, 0.5, 0.6]);
+        assert_eq!(mixer.mix(0.5, [0.0, 0.1, 0.0]), [0.4, 0.5, 0.6, 0.5]);
+        assert_eq!(faster(&mixer, [0.0, 0.0, 0.1]), [true, false, true, false]);
+    }
+
+    #[test]
+    fn presets_only_move_what_was_asked() {
+        // every axis adds as much as it takes away, so throttle and the other axes dont shift
+        for mixer in [Mixer::quad_x(), Mixer::quad_plus()] {
+            for axis in 0..3 {
+                let mut correction = [0.0; 3];
+                correction[axis] = 0.1;
+                let speeds = mixer.mix(0.5, correction);
+                assert!(close(speeds.iter().sum::<f32>(), 2.0), "{:?}", speeds);
+            }
+        }
+    }
+
+    #[test]
+    fn no_correction_is_just_throttle() {
+        let mixer = Mixer::quad_x();
+        for throttle in [0.0, 0.3, 1.0] {
+            assert_eq!(mixer.mix(throttle, [0.0; 3]), [throttle; 4]);
+        }
+        assert_eq!(mixer.mix(1.5, [0.0; 3]), [1.0; 4]);
+        assert_eq!(mixer.mix(-0.5, [0.0; 3]), [0.0; 4]);
+    }
+
+    #[test]
+    fn throttle_makes_room_at_the_ends() {
+        let mixer = Mixer::quad_x();
+        // full throttle still gets the whole roll difference, by slowing the other side
+        let speeds = mixer.mix(1.0, [0.2, 0.0, 0.0]);
+        assert!(
+            close(speeds[0], 1.0) && close(speeds[1], 0.6),
+            "{:?}",
+            speeds
+        );
+        // and at zero throttle by speeding it up
+        let speeds = mixer.mix(0.0, [0.2, 0.0, 0.0]);
+        assert!(
+            close(speeds[0], 0.4) && close(speeds[1], 0.0),
+            "{:?}",
+            speeds
+        );
+    }
+
+    #[test]
+    fn oversized_corrections_keep_their_ratio() {
+        // roll twice as hard as yaw and far too much of both, the ratio between them survives
+        let mixer = Mixer::quad_x();
+        let speeds = mixer.mix(0.5, [0.8, 0.0, 0.4]);
+        assert!(speeds.iter().all(|s| (0.0..=1.0).contains(s)));
+        let [fl, fr, br, bl] = speeds;
+        let roll = (fl + bl) - (fr + br);
+        let twist = (fl + br) - (fr + bl);
+        assert!(close(roll, 2.0 * twist), "{:?}", speeds);
+        // and uses the whole range doing it
+        let max = speeds.iter().cloned().fold(f32::MIN, f32::max);
+        let min = speeds.iter().cloned().fold(f32::MAX, f32::min);
+        assert!(close(max - min, 1.0));
+    }
+
+    #[test]
+    fn hex_directions() {
+        let mixer = Mixer::hex();
+        assert_eq!(
+            mixer.mix(0.5, [0.0, 0.0, 0.1]),
+            [0.6, 0.4, 0.6, 0.4, 0.6, 0.4]
+        );
+        // the side motors take all of a roll and none of a pitch
+        let speeds = mixer.mix(0.5, [0.1, 0.0, 0.0]);
+        assert!(
+            close(speeds[5], 0.6) && close(speeds[2], 0.4),
+            "{:?}",
+            speeds
+        );
+        let speeds = mixer.mix(0.5, [0.0, 0.1, 0.0]);
+        assert!(
+            close(speeds[2], 0.5) && close(speeds[5], 0.5),
+            "{:?}",
+            speeds
+        );
+        assert!(speeds[3] > 0.5 && speeds[4] > 0.5 && speeds[0] < 0.5 && speeds[1] < 0.5);
+        for axis in 0..3 {
+            let mut correction = [0.0; 3];
+            correction[axis] = 0.1;
+            let speeds = mixer.mix(0.5, correction);
+            assert!(close(speeds.iter().sum::<f32>(), 3.0), "{:?}", speeds);
+        }
+    }
+
+    #[test]
+    fn custom_layouts() {
+        // a motor that only helps with throttle
+        let mut rows = [row(0.0, 0.0, 0.0); 2];
+        rows[1].throttle = 0.5;
+        assert_eq!(Mixer::custom(rows).mix(0.8, [0.0; 3]), [0.8, 0.4]);
+    }
+}
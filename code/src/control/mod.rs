@@ -1,4 +1,14 @@
+pub mod crsf;
+pub mod failsafe;
 pub mod flight_system;
+pub mod ibus;
+pub mod ibus_telemetry;
 pub use flight_system::FlightSystem;
+pub mod mixer;
+pub use mixer::Mixer;
 pub mod radio;
 pub use radio::Radio;
+pub mod radio_config;
+pub mod sbus;
+pub mod stabilizer;
+pub mod stick_calibration;
@@ -1,113 +1,378 @@
-// FlySky iA6B ibus interface
-// only rx for now
-// TODO: program will freeze if radio is disconnected; should crash (very bad)
+// radio receivers on UART1 (tx gpio4, rx gpio5)
+// bytes come in on the UART1 interrupt, get decoded there by whichever Receiver is plugged in,
+// and the finished frames get published through a seqlock, so nothing here ever waits on the radio
+use core::cell::RefCell;
 use cortex_m::prelude::_embedded_hal_serial_Read;
-use defmt::info;
+use critical_section::Mutex;
 use defmt::Format;
-use fugit::RateExtU32;
 use hal::gpio::bank0::{Gpio4, Gpio5};
-use hal::gpio::Function;
-use hal::gpio::Pin;
+use hal::gpio::{Function, InputOverride, Pin};
+use hal::pac::interrupt;
+use hal::uart::ReadErrorType;
 use hal::{
     pac,
-    uart::{DataBits, Reader, StopBits, UartConfig, UartPeripheral},
+    uart::{UartConfig, UartPeripheral},
 };
 use rp2040_hal as hal;
 
+use super::crsf::{CrsfReceiver, LinkStats, CRSF_MAX_FRAME_LEN};
+use super::ibus::IbusReceiver;
+use super::radio_config::RadioConfig;
+use super::sbus::SbusReceiver;
+use crate::sync::clock;
+use crate::sync::seqlock::{SeqLock, SeqLockWriter};
+
+pub const RADIO_CHANNELS: usize = 18;
+
 pub enum RadioError {
     ChecksumError,
+    FramingError, // no checksum to go on, header or footer was wrong
     ReadError(Option<hal::uart::ReadErrorType>),
     NoNewData,
+    ReceiverFailsafe, // receiver lost the transmitter and is making up channels
+}
+
+// anything a receiver can hand over
+#[derive(Clone, Copy)]
+pub enum RadioPacket {
+    Channels(RadioFrame),
+    Link(LinkStats),
+}
+
+// what gets sent back to the transmitter on receivers that can
+#[derive(Clone, Copy)]
+pub struct Telemetry {
+    pub battery_voltage: f32,
+    pub attitude: [f32; 3], // roll pitch yaw, degrees
+}
+
+// one protocol's byte level decoder, no hardware in here
+pub trait Receiver {
+    fn uart_config(&self) -> UartConfig;
+    fn inverted(&self) -> bool {
+        false
+    }
+    fn reset(&mut self);
+    // Some once a whole frame has gone past, good or bad
+    fn push(&mut self, byte: u8) -> Option<Result<RadioPacket, RadioError>>;
+    // fills buf with the next frame to send back, 0 if theres nothing to say
+    fn encode_telemetry(&mut self, _telemetry: &Telemetry, _buf: &mut [u8]) -> usize {
+        0
+    }
+}
+
+// what actually sits in the interrupt, a static cant be generic
+pub enum Protocol {
+    Ibus(IbusReceiver),
+    Sbus(SbusReceiver),
+    Crsf(CrsfReceiver),
+}
+
+impl Receiver for Protocol {
+    fn uart_config(&self) -> UartConfig {
+        match self {
+            Self::Ibus(r) => r.uart_config(),
+            Self::Sbus(r) => r.uart_config(),
+            Self::Crsf(r) => r.uart_config(),
+        }
+    }
+    fn inverted(&self) -> bool {
+        match self {
+            Self::Ibus(r) => r.inverted(),
+            Self::Sbus(r) => r.inverted(),
+            Self::Crsf(r) => r.inverted(),
+        }
+    }
+    fn reset(&mut self) {
+        match self {
+            Self::Ibus(r) => r.reset(),
+            Self::Sbus(r) => r.reset(),
+            Self::Crsf(r) => r.reset(),
+        }
+    }
+    fn push(&mut self, byte: u8) -> Option<Result<RadioPacket, RadioError>> {
+        match self {
+            Self::Ibus(r) => r.push(byte),
+            Self::Sbus(r) => r.push(byte),
+            Self::Crsf(r) => r.push(byte),
+        }
+    }
+    fn encode_telemetry(&mut self, telemetry: &Telemetry, buf: &mut [u8]) -> usize {
+        match self {
+            Self::Ibus(r) => r.encode_telemetry(telemetry, buf),
+            Self::Sbus(r) => r.encode_telemetry(telemetry, buf),
+            Self::Crsf(r) => r.encode_telemetry(telemetry, buf),
+        }
+    }
+}
+
+// one decoded frame, raw values are in microseconds (1000-2000, 0 for channels the receiver doesnt have)
+#[derive(Clone, Copy, Debug, Format)]
+pub struct RadioFrame {
+    pub raw: [u16; RADIO_CHANNELS],
+    pub normalized: [f32; RADIO_CHANNELS], // 0 to 1
+    pub frame_lost: bool,                  // receiver missed one, channels are the last good ones
+    pub failsafe: bool,                    // receiver gave up on the transmitter
+    pub timestamp: u64,                    // timer ticks
+}
+
+impl RadioFrame {
+    pub const fn empty() -> Self {
+        Self {
+            raw: [0; RADIO_CHANNELS],
+            normalized: [0.0; RADIO_CHANNELS],
+            frame_lost: false,
+            failsafe: false,
+            timestamp: 0,
+        }
+    }
+
+    pub fn from_raw(raw: [u16; RADIO_CHANNELS]) -> Self {
+        Self {
+            raw,
+            normalized: raw.map(|us| (us.clamp(1000, 2000) - 1000) as f32 / 1000.0),
+            ..Self::empty()
+        }
+    }
+
+    // -1 to 1
+    pub fn centered(&self, channel: usize) -> f32 {
+        self.normalized[channel] * 2.0 - 1.0
+    }
+}
+
+type RadioPins = (
+    Pin<Gpio4, Function<hal::gpio::Uart>>,
+    Pin<Gpio5, Function<hal::gpio::Uart>>,
+);
+type RadioUart = UartPeripheral<hal::uart::Enabled, pac::UART1, RadioPins>;
+
+// what the interrupt hands over, counters only ever go up
+#[derive(Clone, Copy)]
+struct RadioShared {
+    frame: RadioFrame,
+    link: Option<LinkStats>, // only from receivers that report it
+    frames: u32,
+    checksum_errors: u32,
+    framing_errors: u32,
+    read_errors: u32,
+    last_read_error: u8, // ReadErrorType isnt Copy
+}
+
+static RADIO_SHARED: SeqLock<RadioShared> = SeqLock::new(RadioShared {
+    frame: RadioFrame::empty(),
+    link: None,
+    frames: 0,
+    checksum_errors: 0,
+    framing_errors: 0,
+    read_errors: 0,
+    last_read_error: 0,
+});
+
+// everything the interrupt needs, lives here once Radio::new hands it over
+struct RadioRx {
+    uart: RadioUart,
+    receiver: Protocol,
+    writer: SeqLockWriter<'static, RadioShared>,
+}
+
+static RADIO_RX: Mutex<RefCell<Option<RadioRx>>> = Mutex::new(RefCell::new(None));
+
+fn error_code(e: &ReadErrorType) -> u8 {
+    match e {
+        ReadErrorType::Overrun => 0,
+        ReadErrorType::Break => 1,
+        ReadErrorType::Parity => 2,
+        ReadErrorType::Framing => 3,
+    }
+}
+
+fn error_from_code(code: u8) -> ReadErrorType {
+    match code {
+        0 => ReadErrorType::Overrun,
+        1 => ReadErrorType::Break,
+        2 => ReadErrorType::Parity,
+        _ => ReadErrorType::Framing,
+    }
+}
+
+impl RadioRx {
+    // drains the fifo, runs on every rx or rx timeout interrupt
+    fn service(&mut self) {
+        let mut shared = self.writer.read();
+        let mut changed = false;
+        loop {
+            match self.uart.read() {
+                Ok(byte) => match self.receiver.push(byte) {
+                    Some(Ok(RadioPacket::Channels(mut frame))) => {
+                        frame.timestamp = clock::now();
+                        shared.frame = frame;
+                        shared.frames = shared.frames.wrapping_add(1);
+                        changed = true;
+                    }
+                    Some(Ok(RadioPacket::Link(link))) => {
+                        shared.link = Some(link);
+                        changed = true;
+                    }
+                    Some(Err(RadioError::FramingError)) => {
+                        shared.framing_errors = shared.framing_errors.wrapping_add(1);
+                        changed = true;
+                    }
+                    Some(Err(_)) => {
+                        shared.checksum_errors = shared.checksum_errors.wrapping_add(1);
+                        changed = true;
+                    }
+                    None => (),
+                },
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(e)) => {
+                    // whatever frame this was in is garbage now
+                    self.receiver.reset();
+                    shared.read_errors = shared.read_errors.wrapping_add(1);
+                    shared.last_read_error = error_code(&e);
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.writer.write(shared);
+        }
+    }
+}
+
+#[interrupt]
+fn UART1_IRQ() {
+    critical_section::with(|cs| {
+        if let Some(rx) = RADIO_RX.borrow_ref_mut(cs).as_mut() {
+            rx.service();
+        }
+    });
 }
 
 pub struct Radio {
-    uart: UartPeripheral<
-        hal::uart::Enabled,
-        pac::UART1,
-        (
-            Pin<Gpio4, Function<hal::gpio::Uart>>,
-            Pin<Gpio5, Function<hal::gpio::Uart>>,
-        ),
-    >,
-    pub buf: [u8; 31],
+    frame: RadioFrame,
+    config: RadioConfig,
+    link: Option<LinkStats>,
+    frames: u32,
+    checksum_errors: u32,
+    framing_errors: u32,
+    read_errors: u32,
 }
 
 impl Radio {
     pub fn new(
         uart: pac::UART1,
         mosi: Pin<Gpio4, Function<hal::gpio::Uart>>,
-        miso: Pin<Gpio5, Function<hal::gpio::Uart>>,
+        mut miso: Pin<Gpio5, Function<hal::gpio::Uart>>,
         resets: &mut pac::RESETS,
         peripheral_clock_freq: fugit::HertzU32,
+        receiver: Protocol,
+        config: RadioConfig,
     ) -> Self {
+        if receiver.inverted() {
+            miso.set_input_override(InputOverride::Invert);
+        }
         // language server = dumb because this works
         let mut uart = UartPeripheral::new(uart, (mosi, miso), resets)
-            .enable(
-                UartConfig::new(115200.Hz(), DataBits::Eight, None, StopBits::One),
-                peripheral_clock_freq,
-            )
+            .enable(receiver.uart_config(), peripheral_clock_freq)
             .unwrap();
         uart.set_fifos(true);
-        Self { uart, buf: [0; 31] }
+        uart.enable_rx_interrupt();
+        let writer = RADIO_SHARED.writer().unwrap();
+        critical_section::with(|cs| {
+            RADIO_RX.borrow(cs).replace(Some(RadioRx {
+                uart,
+                receiver,
+                writer,
+            }));
+        });
+        Self {
+            frame: RadioFrame {
+                timestamp: clock::now(),
+                ..RadioFrame::empty()
+            },
+            config,
+            link: None,
+            frames: 0,
+            checksum_errors: 0,
+            framing_errors: 0,
+            read_errors: 0,
+        }
+    }
+
+    // the interrupt goes to whichever core unmasks it, so call this from the core that reads
+    pub fn listen(&mut self) {
+        unsafe {
+            pac::NVIC::unmask(pac::Interrupt::UART1_IRQ);
+        }
     }
+
+    // picks up whatever the interrupt has finished since last time
+    // a failsafe frame doesnt count as hearing from the transmitter
     pub fn read(&mut self) -> Result<(), RadioError> {
-        let mut newdata: [u8; 31] = [0; 31];
-        // if self.uart.uart_is_readable() {
-        let mut first = match nb::block!(self.uart.read()) {
-            Ok(n) => n,
-            Err(e) => {
-                return Err(RadioError::ReadError(Some(e)));
+        let shared = RADIO_SHARED.read();
+        self.link = shared.link;
+        if shared.frames != self.frames {
+            self.frames = shared.frames;
+            if shared.frame.failsafe {
+                return Err(RadioError::ReceiverFailsafe);
             }
-        };
-        while first != 0x20 {
-            first = match nb::block!(self.uart.read()) {
-                Ok(n) => n,
-                Err(e) => {
-                    return Err(RadioError::ReadError(Some(e)));
+            self.frame = shared.frame;
+            Ok(())
+        } else if shared.read_errors != self.read_errors {
+            self.read_errors = shared.read_errors;
+            Err(RadioError::ReadError(Some(error_from_code(
+                shared.last_read_error,
+            ))))
+        } else if shared.checksum_errors != self.checksum_errors {
+            self.checksum_errors = shared.checksum_errors;
+            Err(RadioError::ChecksumError)
+        } else if shared.framing_errors != self.framing_errors {
+            self.framing_errors = shared.framing_errors;
+            Err(RadioError::FramingError)
+        } else {
+            Err(RadioError::NoNewData)
+        }
+    }
+
+    // timer ticks of the last good frame
+    pub fn timestamp(&self) -> u64 {
+        self.frame.timestamp
+    }
+
+    pub fn frame(&self) -> RadioFrame {
+        self.frame
+    }
+
+    // latest link statistics, None if the receiver doesnt send them
+    pub fn link(&self) -> Option<LinkStats> {
+        self.link
+    }
+
+    // goes out the tx pin, dropped if the fifo doesnt have room
+    pub fn send_telemetry(&mut self, telemetry: &Telemetry) {
+        critical_section::with(|cs| {
+            if let Some(rx) = RADIO_RX.borrow_ref_mut(cs).as_mut() {
+                let mut buf = [0; CRSF_MAX_FRAME_LEN];
+                let len = rx.receiver.encode_telemetry(telemetry, &mut buf);
+                if len > 0 {
+                    let _ = rx.uart.write_raw(&buf[..len]);
                 }
             }
-        }
-        match self.uart.read_full_blocking(&mut newdata) {
-            Ok(()) => (),
-            Err(e) => return Err(RadioError::ReadError(Some(e))),
-        };
-        let mut checksum: u16 = 0xffdf;
-        for i in &newdata[0..29] {
-            checksum -= *i as u16;
-        }
-        if checksum != u16::from_be_bytes([newdata[30], newdata[29]]) {
-            return Err(RadioError::ChecksumError);
-        }
-        self.buf = newdata;
-        Ok(())
-        // } else {
-        //     Err(RadioError::NoNewData)
-        // }
+        });
+    }
+
+    pub fn config(&self) -> RadioConfig {
+        self.config
+    }
+
+    pub fn set_config(&mut self, config: RadioConfig) {
+        self.config = config;
     }
+
     #[inline(always)]
     pub fn get_command(&self) -> RadioCommand {
-        let mut channels: [f32; 6] = [0.0; 6];
-        for i in 1..7 {
-            let cval = u16::from_be_bytes([self.buf[i * 2], self.buf[i * 2 - 1]]);
-            channels[i - 1] = if cval < 1000 {
-                0.0
-            } else if cval > 2000 {
-                1.0
-            } else {
-                (cval - 1000) as f32 / 1000.0
-            };
-        }
-        if channels[2] > 0.95 {
-            channels[2] = 1.0;
-        }
-        RadioCommand {
-            z_throttle: channels[2],
-            y_throttle: (channels[1] * 2.0 - 1.0),
-            x_throttle: (channels[0] * 2.0 - 1.0),
-            twist_throttle: (channels[3] * 2.0 - 1.0),
-            mode_select: (channels[4] * 2.0) as u8,
-            aux: channels[5],
-        }
+        self.config.command(&self.frame)
     }
 }
 
@@ -0,0 +1,375 @@
+// turns a RadioFrame into a RadioCommand: which channel is which, transmitter endpoints, stick curves
+use super::radio::{RadioCommand, RadioFrame, RADIO_CHANNELS};
+use defmt::Format;
+
+// per channel endpoints in microseconds, straight off the transmitter
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct ChannelCalibration {
+    pub min: u16,
+    pub center: u16,
+    pub max: u16,
+    pub reversed: bool,
+}
+
+impl ChannelCalibration {
+    pub const fn new(min: u16, center: u16, max: u16) -> Self {
+        Self {
+            min,
+            center,
+            max,
+            reversed: false,
+        }
+    }
+
+    pub const fn standard() -> Self {
+        Self::new(1000, 1500, 2000)
+    }
+
+    pub const fn reversed(self) -> Self {
+        Self {
+            reversed: true,
+            ..self
+        }
+    }
+
+    // -1 to 1, center is 0 even if it isnt halfway between the ends
+    pub fn centered(&self, us: u16) -> f32 {
+        let us = us.clamp(self.min, self.max) as f32;
+        let center = self.center as f32;
+        let x = if us >= center {
+            (us - center) / (self.max as f32 - center).max(1.0)
+        } else {
+            (us - center) / (center - self.min as f32).max(1.0)
+        };
+        if self.reversed {
+            -x
+        } else {
+            x
+        }
+    }
+
+    // 0 to 1 from min to max, for throttle and switches
+    pub fn unipolar(&self, us: u16) -> f32 {
+        let us = us.clamp(self.min, self.max);
+        let x = (us - self.min) as f32 / (self.max - self.min).max(1) as f32;
+        if self.reversed {
+            1.0 - x
+        } else {
+            x
+        }
+    }
+}
+
+// stick shaping, input and output are -1 to 1 and full stick stays full stick
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct Curve {
+    pub deadband: f32,   // fraction of stick travel around center that reads 0
+    pub expo: f32,       // 0 is linear, 1 is all cubic, softens the middle
+    pub super_rate: f32, // 0 to 0.99, steepens the ends
+}
+
+impl Curve {
+    pub const fn linear() -> Self {
+        Self {
+            deadband: 0.0,
+            expo: 0.0,
+            super_rate: 0.0,
+        }
+    }
+
+    pub fn apply(&self, x: f32) -> f32 {
+        let x = x.clamp(-1.0, 1.0);
+        let magnitude = x.abs();
+        if magnitude <= self.deadband {
+            return 0.0;
+        }
+        let x = x.signum() * (magnitude - self.deadband) / (1.0 - self.deadband);
+        let expo = self.expo.clamp(0.0, 1.0);
+        let x = x * (1.0 - expo) + x * x * x * expo;
+        let super_rate = self.super_rate.clamp(0.0, 0.99);
+        x * (1.0 - super_rate) / (1.0 - x.abs() * super_rate)
+    }
+}
+
+// n position switch off one channel, evenly spread over its travel
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct SwitchDecoder {
+    pub positions: u8,
+}
+
+impl SwitchDecoder {
+    pub fn position(&self, x: f32) -> u8 {
+        let top = self.positions.max(1) - 1;
+        ((x.clamp(0.0, 1.0) * top as f32 + 0.5) as u8).min(top)
+    }
+}
+
+// zero based channel numbers
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct ChannelMap {
+    pub x: usize,
+    pub y: usize,
+    pub z: usize,
+    pub twist: usize,
+    pub mode: usize,
+    pub aux: usize,
+}
+
+impl ChannelMap {
+    // aileron elevator throttle rudder
+    pub const fn aetr() -> Self {
+        Self {
+            x: 0,
+            y: 1,
+            z: 2,
+            twist: 3,
+            mode: 4,
+            aux: 5,
+        }
+    }
+
+    // what frsky and elrs radios usually default to
+    pub const fn taer() -> Self {
+        Self {
+            x: 1,
+            y: 2,
+            z: 0,
+            twist: 3,
+            mode: 4,
+            aux: 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct RadioConfig {
+    pub map: ChannelMap,
+    pub calibration: [ChannelCalibration; RADIO_CHANNELS],
+    pub curves: [Curve; 3], // x y twist
+    pub mode_switch: SwitchDecoder,
+}
+
+impl RadioConfig {
+    pub const fn standard() -> Self {
+        Self {
+            map: ChannelMap::aetr(),
+            calibration: [ChannelCalibration::standard(); RADIO_CHANNELS],
+            curves: [Curve::linear(); 3],
+            mode_switch: SwitchDecoder { positions: 3 },
+        }
+    }
+
+    pub fn command(&self, frame: &RadioFrame) -> RadioCommand {
+        let centered = |ch: usize| self.calibration[ch].centered(frame.raw[ch]);
+        let unipolar = |ch: usize| self.calibration[ch].unipolar(frame.raw[ch]);
+        RadioCommand {
+            z_throttle: unipolar(self.map.z),
+            y_throttle: self.curves[1].apply(centered(self.map.y)),
+            x_throttle: self.curves[0].apply(centered(self.map.x)),
+            twist_throttle: self.curves[2].apply(centered(self.map.twist)),
+            mode_select: self.mode_switch.position(unipolar(self.map.mode)),
+            aux: unipolar(self.map.aux),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    const CURVES: [Curve; 5] = [
+        Curve::linear(),
+        Curve {
+            deadband: 0.05,
+            expo: 0.0,
+            super_rate: 0.0,
+        },
+        Curve {
+            deadband: 0.0,
+            expo: 0.5,
+            super_rate: 0.0,
+        },
+        Curve {
+            deadband: 0.0,
+            expo: 0.0,
+            super_rate: 0.7,
+        },
+        Curve {
+            deadband: 0.02,
+            expo: 0.3,
+            super_rate: 0.6,
+        },
+    ];
+
+    #[test]
+    fn curves_keep_full_stick_and_symmetry() {
+        for curve in CURVES {
+            assert!(close(curve.apply(1.0), 1.0), "{:?}", curve);
+            assert!(close(curve.apply(-1.0), -1.0), "{:?}", curve);
+            assert_eq!(curve.apply(0.0), 0.0);
+            // past the ends is the ends
+            assert!(close(curve.apply(1.5), 1.0));
+            let mut last = -1.0;
+            for n in -100..=100 {
+                let x = n as f32 / 100.0;
+                let y = curve.apply(x);
+                assert!(close(y, -curve.apply(-x)), "{:?} at {}", curve, x);
+                assert!(y >= last - 1e-6, "{:?} goes backwards at {}", curve, x);
+                last = y;
+            }
+        }
+    }
+
+    #[test]
+    fn deadband() {
+        let curve = CURVES[1];
+        assert_eq!(curve.apply(0.05), 0.0);
+        assert_eq!(curve.apply(-0.04), 0.0);
+        // and no step at its edge, the rest of the travel is stretched over 0 to 1
+        assert!(curve.apply(0.051) < 0.002);
+        assert!(close(curve.apply(0.525), 0.5));
+    }
+
+    #[test]
+    fn expo_softens_the_middle() {
+        let curve = CURVES[2];
+        assert!(close(curve.apply(0.5), 0.5 * 0.5 + 0.125 * 0.5));
+        for n in 1..100 {
+            let x = n as f32 / 100.0;
+            assert!(curve.apply(x) < x);
+        }
+        // all cubic
+        let cubic = Curve {
+            expo: 1.0,
+            ..Curve::linear()
+        };
+        assert!(close(cubic.apply(0.5), 0.125));
+    }
+
+    #[test]
+    fn super_rate_steepens_the_ends() {
+        let curve = CURVES[3];
+        // 0.3 slope in the middle, much steeper near full stick
+        assert!(close(curve.apply(0.5), 0.5 * 0.3 / (1.0 - 0.35)));
+        let slope = |x: f32| (curve.apply(x + 0.01) - curve.apply(x)) / 0.01;
+        assert!(slope(0.0) < 0.5);
+        assert!(slope(0.98) > 3.0);
+        // clamped so it cant blow up
+        let silly = Curve {
+            super_rate: 5.0,
+            ..Curve::linear()
+        };
+        assert!(close(silly.apply(1.0), 1.0));
+        assert!(silly.apply(0.9).is_finite());
+    }
+
+    #[test]
+    fn channel_calibration() {
+        let standard = ChannelCalibration::standard();
+        assert_eq!(standard.centered(1500), 0.0);
+        assert_eq!(standard.centered(1000), -1.0);
+        assert_eq!(standard.centered(2000), 1.0);
+        assert_eq!(standard.centered(2100), 1.0);
+        assert_eq!(standard.centered(0), -1.0); // missing channel
+        assert_eq!(standard.unipolar(1250), 0.25);
+        // lopsided transmitter, center still reads 0 and both ends still reach 1
+        let lopsided = ChannelCalibration::new(1100, 1400, 1900);
+        assert_eq!(lopsided.centered(1400), 0.0);
+        assert_eq!(lopsided.centered(1100), -1.0);
+        assert_eq!(lopsided.centered(1900), 1.0);
+        assert_eq!(lopsided.centered(1250), -0.5);
+        assert_eq!(lopsided.centered(1650), 0.5);
+        let reversed = standard.reversed();
+        assert_eq!(reversed.centered(1750), -0.5);
+        assert_eq!(reversed.unipolar(1000), 1.0);
+        // a channel that never moved during calibration doesnt divide by zero
+        let stuck = ChannelCalibration::new(1500, 1500, 1500);
+        assert_eq!(stuck.centered(1500), 0.0);
+        assert_eq!(stuck.unipolar(1500), 0.0);
+    }
+
+    #[test]
+    fn switch_positions() {
+        let three = SwitchDecoder { positions: 3 };
+        assert_eq!(
+            [0.0, 0.2, 0.5, 0.8, 1.0].map(|x| three.position(x)),
+            [0, 0, 1, 2, 2]
+        );
+        let six = SwitchDecoder { positions: 6 };
+        assert_eq!(
+            [0.0, 0.2, 0.4, 0.6, 0.8, 1.0].map(|x| six.position(x)),
+            [0, 1, 2, 3, 4, 5]
+        );
+        // nonsense position counts dont index past the end
+        assert_eq!(SwitchDecoder { positions: 0 }.position(1.0), 0);
+        assert_eq!(three.position(7.0), 2);
+    }
+
+    fn frame(values: [(usize, u16); 6]) -> RadioFrame {
+        let mut raw = [1500; RADIO_CHANNELS];
+        for (ch, us) in values {
+            raw[ch] = us;
+        }
+        RadioFrame::from_raw(raw)
+    }
+
+    #[test]
+    fn command_follows_the_channel_map() {
+        // right, back, a quarter throttle, twist left, switch middle, aux up
+        let command = RadioConfig::standard().command(&frame([
+            (0, 2000),
+            (1, 1750),
+            (2, 1250),
+            (3, 1000),
+            (4, 1500),
+            (5, 2000),
+        ]));
+        assert_eq!(command.x_throttle, 1.0);
+        assert_eq!(command.y_throttle, 0.5);
+        assert_eq!(command.z_throttle, 0.25);
+        assert_eq!(command.twist_throttle, -1.0);
+        assert_eq!(command.mode_select, 1);
+        assert_eq!(command.aux, 1.0);
+        let taer = RadioConfig {
+            map: ChannelMap::taer(),
+            ..RadioConfig::standard()
+        };
+        let command = taer.command(&frame([
+            (0, 1250),
+            (1, 2000),
+            (2, 1750),
+            (3, 1000),
+            (4, 1000),
+            (5, 1000),
+        ]));
+        assert_eq!(command.z_throttle, 0.25);
+        assert_eq!(command.x_throttle, 1.0);
+        assert_eq!(command.y_throttle, 0.5);
+        assert_eq!(command.mode_select, 0);
+    }
+
+    #[test]
+    fn curves_only_touch_the_sticks_they_belong_to() {
+        let config = RadioConfig {
+            curves: [CURVES[2], Curve::linear(), CURVES[3]],
+            ..RadioConfig::standard()
+        };
+        let command = config.command(&frame([
+            (0, 1750),
+            (1, 1750),
+            (2, 1750),
+            (3, 1750),
+            (4, 1500),
+            (5, 1500),
+        ]));
+        assert_eq!(command.x_throttle, CURVES[2].apply(0.5));
+        assert_eq!(command.y_throttle, 0.5);
+        assert_eq!(command.twist_throttle, CURVES[3].apply(0.5));
+        // throttle is never curved
+        assert_eq!(command.z_throttle, 0.75);
+    }
+}
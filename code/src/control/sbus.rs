@@ -0,0 +1,248 @@
+// FrSky SBUS, 100000 baud 8E2 with the line inverted
+// frame is 0x0F, 16 channels of 11 bits packed lsb first into 22 bytes, a flags byte, then the footer
+// there is no checksum so the header and footer are all there is to sync on
+use super::radio::{RadioError, RadioFrame, RadioPacket, Receiver, RADIO_CHANNELS};
+use fugit::RateExtU32;
+use hal::uart::{DataBits, Parity, StopBits, UartConfig};
+use rp2040_hal as hal;
+
+const SBUS_HEADER: u8 = 0x0F;
+pub const SBUS_FRAME_LEN: usize = 25;
+const SBUS_CHANNELS: usize = 16; // 17 and 18 are the digital ones in the flags byte
+const SBUS_FLAG_CH17: u8 = 1 << 0;
+const SBUS_FLAG_CH18: u8 = 1 << 1;
+const SBUS_FLAG_FRAME_LOST: u8 = 1 << 2;
+const SBUS_FLAG_FAILSAFE: u8 = 1 << 3;
+const DIGITAL_LOW: u16 = 1000; // us
+const DIGITAL_HIGH: u16 = 2000;
+
+pub struct SbusReceiver {
+    buf: [u8; SBUS_FRAME_LEN],
+    pos: usize,
+}
+
+impl SbusReceiver {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; SBUS_FRAME_LEN],
+            pos: 0,
+        }
+    }
+}
+
+impl Default for SbusReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Receiver for SbusReceiver {
+    fn uart_config(&self) -> UartConfig {
+        UartConfig::new(
+            100000.Hz(),
+            DataBits::Eight,
+            Some(Parity::Even),
+            StopBits::Two,
+        )
+    }
+
+    fn inverted(&self) -> bool {
+        true
+    }
+
+    fn reset(&mut self) {
+        self.pos = 0;
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Result<RadioPacket, RadioError>> {
+        if self.pos == 0 && byte != SBUS_HEADER {
+            return None;
+        }
+        self.buf[self.pos] = byte;
+        self.pos += 1;
+        if self.pos < SBUS_FRAME_LEN {
+            return None;
+        }
+        self.reset();
+        let result = decode_sbus(&self.buf);
+        if result.is_err() {
+            // that header was really channel data, carry on from the next one without
+            // recursing in the interrupt. the rest is shorter than a frame so moving it is enough
+            if let Some(start) = (1..SBUS_FRAME_LEN).find(|&i| self.buf[i] == SBUS_HEADER) {
+                self.buf.copy_within(start.., 0);
+                self.pos = SBUS_FRAME_LEN - start;
+            }
+        }
+        Some(result.map(RadioPacket::Channels))
+    }
+}
+
+// 0x00 for plain SBUS, SBUS2 cycles the top nibble through its telemetry slots
+fn footer_ok(footer: u8) -> bool {
+    footer == 0x00 || footer & 0x0F == 0x04
+}
+
+// 172-1811 maps onto 987-2011 us, within a us of betaflight
+#[inline(always)]
+pub fn sbus_to_us(value: u16) -> u16 {
+    value * 5 / 8 + 880
+}
+
+pub fn decode_sbus(frame: &[u8; SBUS_FRAME_LEN]) -> Result<RadioFrame, RadioError> {
+    if frame[0] != SBUS_HEADER || !footer_ok(frame[SBUS_FRAME_LEN - 1]) {
+        return Err(RadioError::FramingError);
+    }
+    let mut raw = [0; RADIO_CHANNELS];
+    for (ch, us) in raw.iter_mut().enumerate().take(SBUS_CHANNELS) {
+        let bit = ch * 11;
+        let byte = 1 + bit / 8;
+        let bits =
+            frame[byte] as u32 | (frame[byte + 1] as u32) << 8 | (frame[byte + 2] as u32) << 16;
+        *us = sbus_to_us(((bits >> (bit % 8)) & 0x07FF) as u16);
+    }
+    let flags = frame[SBUS_FRAME_LEN - 2];
+    let digital = |mask: u8| {
+        if flags & mask != 0 {
+            DIGITAL_HIGH
+        } else {
+            DIGITAL_LOW
+        }
+    };
+    raw[16] = digital(SBUS_FLAG_CH17);
+    raw[17] = digital(SBUS_FLAG_CH18);
+    let mut decoded = RadioFrame::from_raw(raw);
+    decoded.frame_lost = flags & SBUS_FLAG_FRAME_LOST != 0;
+    decoded.failsafe = flags & SBUS_FLAG_FAILSAFE != 0;
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // packs 11 bit values the way a receiver does
+    fn frame(values: [u16; SBUS_CHANNELS], flags: u8) -> [u8; SBUS_FRAME_LEN] {
+        let mut frame = [0; SBUS_FRAME_LEN];
+        frame[0] = SBUS_HEADER;
+        for (ch, value) in values.iter().enumerate() {
+            for bit in 0..11 {
+                if value & (1 << bit) != 0 {
+                    let n = ch * 11 + bit;
+                    frame[1 + n / 8] |= 1 << (n % 8);
+                }
+            }
+        }
+        frame[SBUS_FRAME_LEN - 2] = flags;
+        frame
+    }
+
+    fn feed(receiver: &mut SbusReceiver, bytes: &[u8]) -> Vec<Result<RadioFrame, ()>> {
+        bytes
+            .iter()
+            .filter_map(|b| receiver.push(*b))
+            .map(|result| match result {
+                Ok(RadioPacket::Channels(frame)) => Ok(frame),
+                _ => Err(()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scales_like_betaflight() {
+        assert_eq!(sbus_to_us(172), 987);
+        assert_eq!(sbus_to_us(992), 1500);
+        assert_eq!(sbus_to_us(1811), 2011);
+    }
+
+    #[test]
+    fn unpacks_every_channel() {
+        let mut values = [0; SBUS_CHANNELS];
+        for (ch, value) in values.iter_mut().enumerate() {
+            *value = 172 + 100 * ch as u16;
+        }
+        let decoded = decode_sbus(&frame(values, 0)).ok().unwrap();
+        for (ch, value) in values.iter().enumerate() {
+            assert_eq!(decoded.raw[ch], sbus_to_us(*value), "channel {}", ch + 1);
+        }
+        // all ones in every bit still comes apart per channel
+        let decoded = decode_sbus(&frame([0x7FF; SBUS_CHANNELS], 0)).ok().unwrap();
+        assert!(decoded.raw[..SBUS_CHANNELS]
+            .iter()
+            .all(|us| *us == sbus_to_us(0x7FF)));
+    }
+
+    #[test]
+    fn flags() {
+        let values = [992; SBUS_CHANNELS];
+        let decoded = decode_sbus(&frame(values, 0)).ok().unwrap();
+        assert_eq!(&decoded.raw[16..], &[DIGITAL_LOW, DIGITAL_LOW]);
+        assert!(!decoded.frame_lost && !decoded.failsafe);
+        let decoded = decode_sbus(&frame(values, SBUS_FLAG_CH17)).ok().unwrap();
+        assert_eq!(&decoded.raw[16..], &[DIGITAL_HIGH, DIGITAL_LOW]);
+        let decoded = decode_sbus(&frame(values, SBUS_FLAG_CH18)).ok().unwrap();
+        assert_eq!(&decoded.raw[16..], &[DIGITAL_LOW, DIGITAL_HIGH]);
+        let decoded = decode_sbus(&frame(values, SBUS_FLAG_FRAME_LOST))
+            .ok()
+            .unwrap();
+        assert!(decoded.frame_lost && !decoded.failsafe);
+        let decoded = decode_sbus(&frame(values, SBUS_FLAG_FAILSAFE))
+            .ok()
+            .unwrap();
+        assert!(decoded.failsafe);
+    }
+
+    #[test]
+    fn footers() {
+        let mut sbus2 = frame([992; SBUS_CHANNELS], 0);
+        for slot in [0x04, 0x14, 0x24, 0x34] {
+            sbus2[SBUS_FRAME_LEN - 1] = slot;
+            assert!(decode_sbus(&sbus2).is_ok());
+        }
+        sbus2[SBUS_FRAME_LEN - 1] = 0x0F;
+        assert!(matches!(decode_sbus(&sbus2), Err(RadioError::FramingError)));
+        let mut header = frame([992; SBUS_CHANNELS], 0);
+        header[0] = 0x0E;
+        assert!(matches!(
+            decode_sbus(&header),
+            Err(RadioError::FramingError)
+        ));
+    }
+
+    #[test]
+    fn stream_with_junk_and_a_split_frame() {
+        let mut receiver = SbusReceiver::new();
+        let first = frame([500; SBUS_CHANNELS], 0);
+        let second = frame([1500; SBUS_CHANNELS], 0);
+        let mut stream = vec![0x00, 0xFF, 0x12];
+        stream.extend_from_slice(&first);
+        stream.extend_from_slice(&second);
+        let (head, tail) = stream.split_at(20);
+        let mut frames = feed(&mut receiver, head);
+        frames.extend(feed(&mut receiver, tail));
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].unwrap().raw[0], sbus_to_us(500));
+        assert_eq!(frames[1].unwrap().raw[0], sbus_to_us(1500));
+    }
+
+    #[test]
+    fn resyncs_off_a_header_inside_a_frame() {
+        // channel 1 at 0x70F puts a 0x0F on the wire right after the header
+        let mut values = [992; SBUS_CHANNELS];
+        values[0] = 0x70F;
+        let first = frame(values, 0);
+        assert_eq!(first[1], SBUS_HEADER);
+        // joined one byte late, so the first thing that looks like a header is channel data
+        let mut stream = first[1..].to_vec();
+        stream.extend_from_slice(&frame([300; SBUS_CHANNELS], 0));
+        stream.extend_from_slice(&frame([400; SBUS_CHANNELS], 0));
+        let mut receiver = SbusReceiver::new();
+        let frames = feed(&mut receiver, &stream);
+        assert!(frames[0].is_err());
+        let good: Vec<_> = frames
+            .iter()
+            .filter_map(|f| f.ok())
+            .map(|f| f.raw[0])
+            .collect();
+        assert_eq!(good, [sbus_to_us(300), sbus_to_us(400)]);
+    }
+}
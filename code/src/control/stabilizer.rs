@@ -0,0 +1,327 @@
+// the closed loops behind rate and angle mode, sticks plus what the imu says in,
+// [x, y, twist] corrections for the mixer out
+use super::radio::RadioCommand;
+use crate::math::functions::PID;
+
+// arbitrary max values
+pub const MAX_TWIST: f32 = 100.0; //dps
+pub const MAX_TILT: f32 = 20.0; //degrees
+
+// rate mode
+const MAX_RATE: [f32; 3] = [2.0 * MAX_TWIST, 2.0 * MAX_TWIST, MAX_TWIST]; // dps, x y twist
+const RATE_GAINS: [[f32; 3]; 3] = [
+    // p, i, d
+    [0.0020, 0.0010, 0.00005], // x
+    [0.0020, 0.0010, 0.00005], // y
+    [0.0040, 0.0020, 0.0],     // twist
+];
+const MAX_CORRECTION: f32 = 0.3; // most throttle a single axis can steal
+pub const IDLE_THROTTLE: f32 = 0.05; // below this nothing spins and the integrators reset
+
+// angle mode, outer loop turns degrees of error into dps
+const ANGLE_GAINS: [[f32; 2]; 2] = [
+    // p, i
+    [4.0, 0.5], // x
+    [4.0, 0.5], // y
+];
+
+pub struct Stabilizer {
+    rate_pids: [PID; 3],
+    angle_pids: [PID; 2],
+}
+
+impl Stabilizer {
+    pub fn new() -> Self {
+        Self {
+            rate_pids: RATE_GAINS.map(|[p, i, d]| PID::new(p, i, d)),
+            angle_pids: ANGLE_GAINS.map(|[p, i]| PID::new(p, i, 0.0)),
+        }
+    }
+
+    // rate (acro) mode: sticks set body rates, gyro closes the loop
+    // None below idle throttle, the motors should stop
+    pub fn rate(
+        &mut self,
+        command: &RadioCommand,
+        angular_velocity: [f32; 3],
+        dt: f32,
+    ) -> Option<[f32; 3]> {
+        if command.z_throttle < IDLE_THROTTLE {
+            self.reset();
+            return None;
+        }
+        let target_rate = [
+            command.x_throttle * MAX_RATE[0],
+            command.y_throttle * MAX_RATE[1],
+            command.twist_throttle * MAX_RATE[2],
+        ];
+        Some(self.rate_correction(target_rate, angular_velocity, dt))
+    }
+
+    // angle (self level) mode: outer loop on tilt feeds the rate loops, twist stick stays a rate command
+    pub fn angle(
+        &mut self,
+        command: &RadioCommand,
+        desired_angle: [f32; 2],
+        true_angle: [f32; 2],
+        angular_velocity: [f32; 3],
+        dt: f32,
+    ) -> Option<[f32; 3]> {
+        if command.z_throttle < IDLE_THROTTLE {
+            self.reset();
+            return None;
+        }
+        let mut target_rate = [0.0, 0.0, command.twist_throttle * MAX_RATE[2]];
+        for i in 0..2 {
+            let target = desired_angle[i].clamp(-MAX_TILT, MAX_TILT);
+            target_rate[i] = self.angle_pids[i]
+                .get_next(target - true_angle[i], dt)
+                .clamp(-MAX_RATE[i], MAX_RATE[i]);
+        }
+        Some(self.rate_correction(target_rate, angular_velocity, dt))
+    }
+
+    // runs the three rate loops; gyro x/y/z are assumed to line up with the x/y/twist sticks
+    fn rate_correction(
+        &mut self,
+        target_rate: [f32; 3],
+        angular_velocity: [f32; 3],
+        dt: f32,
+    ) -> [f32; 3] {
+        let mut correction = [0.0; 3];
+        for i in 0..3 {
+            let target = target_rate[i].clamp(-MAX_RATE[i], MAX_RATE[i]);
+            correction[i] = self.rate_pids[i]
+                .get_next(target - angular_velocity[i], dt)
+                .clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        }
+        correction
+    }
+
+    pub fn reset(&mut self) {
+        for pid in self.rate_pids.iter_mut() {
+            pid.reset();
+        }
+        for pid in self.angle_pids.iter_mut() {
+            pid.reset();
+        }
+    }
+}
+
+impl Default for Stabilizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1.0 / 1125.0;
+
+    fn sticks(x: f32, y: f32, twist: f32) -> RadioCommand {
+        RadioCommand {
+            z_throttle: 0.5,
+            y_throttle: y,
+            x_throttle: x,
+            twist_throttle: twist,
+            mode_select: 1,
+            aux: 0.0,
+        }
+    }
+
+    fn axis(i: usize, value: f32) -> [f32; 3] {
+        let mut v = [0.0; 3];
+        v[i] = value;
+        v
+    }
+
+    #[test]
+    fn rate_pushes_against_unwanted_rotation() {
+        for i in 0..3 {
+            let mut stabilizer = Stabilizer::new();
+            let correction = stabilizer
+                .rate(&sticks(0.0, 0.0, 0.0), axis(i, 50.0), DT)
+                .unwrap();
+            assert!(correction[i] < 0.0, "axis {} got {:?}", i, correction);
+            for j in (0..3).filter(|j| *j != i) {
+                assert_eq!(correction[j], 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn rate_follows_the_sticks() {
+        let cases = [
+            sticks(0.5, 0.0, 0.0),
+            sticks(0.0, 0.5, 0.0),
+            sticks(0.0, 0.0, 0.5),
+        ];
+        for (i, command) in cases.iter().enumerate() {
+            let mut stabilizer = Stabilizer::new();
+            let correction = stabilizer.rate(command, [0.0; 3], DT).unwrap();
+            assert!(correction[i] > 0.0, "axis {} got {:?}", i, correction);
+            // and backwards for the other stick direction
+            let reversed = RadioCommand {
+                x_throttle: -command.x_throttle,
+                y_throttle: -command.y_throttle,
+                twist_throttle: -command.twist_throttle,
+                ..*command
+            };
+            let mut stabilizer = Stabilizer::new();
+            let correction = stabilizer.rate(&reversed, [0.0; 3], DT).unwrap();
+            assert!(correction[i] < 0.0, "axis {} got {:?}", i, correction);
+        }
+    }
+
+    #[test]
+    fn rate_is_quiet_when_rotating_as_asked() {
+        let mut stabilizer = Stabilizer::new();
+        let gyro = [0.5 * MAX_RATE[0], -0.25 * MAX_RATE[1], MAX_RATE[2]];
+        for _ in 0..100 {
+            let correction = stabilizer.rate(&sticks(0.5, -0.25, 1.0), gyro, DT).unwrap();
+            assert!(
+                correction.iter().all(|c| c.abs() < 1e-6),
+                "{:?}",
+                correction
+            );
+        }
+    }
+
+    #[test]
+    fn rate_target_is_limited() {
+        // past full stick asks for no more than MAX_RATE
+        let mut stabilizer = Stabilizer::new();
+        let correction = stabilizer
+            .rate(&sticks(2.0, 0.0, 0.0), axis(0, MAX_RATE[0]), DT)
+            .unwrap();
+        assert!(correction[0].abs() < 1e-6);
+    }
+
+    #[test]
+    fn correction_is_limited() {
+        let mut stabilizer = Stabilizer::new();
+        for _ in 0..1000 {
+            let correction = stabilizer
+                .rate(&sticks(0.0, 0.0, 0.0), [-1000.0, 1000.0, -1000.0], DT)
+                .unwrap();
+            assert!(correction.iter().all(|c| c.abs() <= MAX_CORRECTION));
+        }
+    }
+
+    #[test]
+    fn idle_throttle_stops_and_resets() {
+        let mut stabilizer = Stabilizer::new();
+        for _ in 0..1000 {
+            stabilizer.rate(&sticks(0.0, 0.0, 0.0), axis(0, 20.0), DT);
+        }
+        let idle = RadioCommand {
+            z_throttle: 0.0,
+            ..sticks(0.0, 0.0, 0.0)
+        };
+        assert_eq!(stabilizer.rate(&idle, axis(0, 20.0), DT), None);
+        // the integral wound up above is gone
+        let correction = stabilizer
+            .rate(&sticks(0.0, 0.0, 0.0), [0.0; 3], DT)
+            .unwrap();
+        assert_eq!(correction, [0.0; 3]);
+    }
+
+    #[test]
+    fn angle_levels_out() {
+        // rolled and pitched positive with the sticks centered, both get pushed back
+        let mut stabilizer = Stabilizer::new();
+        let correction = stabilizer
+            .angle(&sticks(0.0, 0.0, 0.0), [0.0; 2], [10.0, 10.0], [0.0; 3], DT)
+            .unwrap();
+        assert!(
+            correction[0] < 0.0 && correction[1] < 0.0,
+            "{:?}",
+            correction
+        );
+        assert_eq!(correction[2], 0.0);
+        let mut stabilizer = Stabilizer::new();
+        let correction = stabilizer
+            .angle(
+                &sticks(0.0, 0.0, 0.0),
+                [0.0; 2],
+                [-10.0, -10.0],
+                [0.0; 3],
+                DT,
+            )
+            .unwrap();
+        assert!(
+            correction[0] > 0.0 && correction[1] > 0.0,
+            "{:?}",
+            correction
+        );
+    }
+
+    #[test]
+    fn angle_holds_the_asked_tilt() {
+        let mut stabilizer = Stabilizer::new();
+        let correction = stabilizer
+            .angle(
+                &sticks(0.0, 0.0, 0.0),
+                [15.0, -15.0],
+                [0.0; 2],
+                [0.0; 3],
+                DT,
+            )
+            .unwrap();
+        assert!(
+            correction[0] > 0.0 && correction[1] < 0.0,
+            "{:?}",
+            correction
+        );
+        // already there and not moving, nothing to do
+        let mut stabilizer = Stabilizer::new();
+        let correction = stabilizer
+            .angle(
+                &sticks(0.0, 0.0, 0.0),
+                [15.0, -15.0],
+                [15.0, -15.0],
+                [0.0; 3],
+                DT,
+            )
+            .unwrap();
+        assert_eq!(correction, [0.0; 3]);
+    }
+
+    #[test]
+    fn angle_target_is_limited() {
+        // asking for more than MAX_TILT is asking for MAX_TILT
+        let mut stabilizer = Stabilizer::new();
+        let correction = stabilizer
+            .angle(
+                &sticks(0.0, 0.0, 0.0),
+                [90.0, -90.0],
+                [MAX_TILT, -MAX_TILT],
+                [0.0; 3],
+                DT,
+            )
+            .unwrap();
+        assert_eq!(correction, [0.0; 3]);
+    }
+
+    #[test]
+    fn angle_twist_stays_a_rate() {
+        let mut stabilizer = Stabilizer::new();
+        let correction = stabilizer
+            .angle(&sticks(0.0, 0.0, 0.5), [0.0; 2], [0.0; 2], [0.0; 3], DT)
+            .unwrap();
+        assert!(correction[2] > 0.0);
+        let mut stabilizer = Stabilizer::new();
+        let correction = stabilizer
+            .angle(
+                &sticks(0.0, 0.0, 0.5),
+                [0.0; 2],
+                [0.0; 2],
+                axis(2, 0.5 * MAX_RATE[2]),
+                DT,
+            )
+            .unwrap();
+        assert_eq!(correction[2], 0.0);
+    }
+}
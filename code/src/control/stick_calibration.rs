@@ -0,0 +1,189 @@
+// learns the transmitter endpoints while disarmed
+// hold throttle up and twist left to start, move every stick to all its ends,
+// then let go of the sticks with throttle down and it finishes by itself
+use super::radio::{RadioFrame, RADIO_CHANNELS};
+use super::radio_config::{ChannelCalibration, RadioConfig};
+use defmt::Format;
+
+const START_HOLD_TICKS: u64 = 2_000_000;
+const SETTLE_TICKS: u64 = 1_000_000; // sticks have to sit centered this long to finish
+const TIMEOUT_TICKS: u64 = 30_000_000;
+const REPORT_TICKS: u64 = 1_000_000;
+const STICK_THRESHOLD: f32 = 0.9;
+const CENTER_TOLERANCE: f32 = 0.1; // of the swept span
+const MIN_SPAN: u16 = 600; // us, anything less and the stick wasnt moved
+
+// what a sane transmitter looks like, in us
+const MIN_RANGE: (u16, u16) = (800, 1200);
+const MAX_RANGE: (u16, u16) = (1800, 2200);
+const CENTER_RANGE: (u16, u16) = (1350, 1650);
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum CalibrationError {
+    Timeout,
+    NotMoved(usize),     // channel
+    BadEndpoints(usize), // channel
+}
+
+// no heap to box the config on, and an event only lives for one loop
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum CalibrationEvent {
+    Started,
+    Progress([u16; 4]), // swept span of x y throttle twist, us
+    Finished(RadioConfig),
+    Failed(CalibrationError),
+}
+
+pub struct StickCalibration {
+    sweeping: bool,
+    gesture_start: Option<u64>,
+    start: u64,
+    last_report: u64,
+    settled_since: Option<u64>,
+    min: [u16; RADIO_CHANNELS],
+    max: [u16; RADIO_CHANNELS],
+}
+
+impl StickCalibration {
+    pub fn new() -> Self {
+        Self {
+            sweeping: false,
+            gesture_start: None,
+            start: 0,
+            last_report: 0,
+            settled_since: None,
+            min: [u16::MAX; RADIO_CHANNELS],
+            max: [0; RADIO_CHANNELS],
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.sweeping
+    }
+
+    pub fn abort(&mut self) {
+        self.sweeping = false;
+        self.gesture_start = None;
+    }
+
+    // only call while disarmed, now is in timer ticks
+    pub fn update(
+        &mut self,
+        now: u64,
+        frame: &RadioFrame,
+        config: &RadioConfig,
+    ) -> Option<CalibrationEvent> {
+        if !self.sweeping {
+            return self.check_gesture(now, frame, config);
+        }
+        for ch in 0..RADIO_CHANNELS {
+            self.min[ch] = self.min[ch].min(frame.raw[ch]);
+            self.max[ch] = self.max[ch].max(frame.raw[ch]);
+        }
+        let map = config.map;
+        let sticks = [map.x, map.y, map.z, map.twist];
+        if now - self.start > TIMEOUT_TICKS {
+            self.sweeping = false;
+            return Some(CalibrationEvent::Failed(CalibrationError::Timeout));
+        }
+        // throttle at the bottom and everything else back in the middle of where it went
+        let span = |ch: usize| self.max[ch].saturating_sub(self.min[ch]);
+        let near = |ch: usize, target: u16| {
+            (frame.raw[ch] as f32 - target as f32).abs() < span(ch) as f32 * CENTER_TOLERANCE
+        };
+        let settled = sticks.iter().all(|ch| span(*ch) >= MIN_SPAN)
+            && near(map.z, self.min[map.z])
+            && [map.x, map.y, map.twist]
+                .iter()
+                .all(|ch| near(*ch, self.min[*ch] + span(*ch) / 2));
+        if !settled {
+            self.settled_since = None;
+        } else if self.settled_since.is_none() {
+            self.settled_since = Some(now);
+        }
+        if let Some(since) = self.settled_since {
+            if now - since > SETTLE_TICKS {
+                self.sweeping = false;
+                return Some(match self.result(frame, config) {
+                    Ok(config) => CalibrationEvent::Finished(config),
+                    Err(e) => CalibrationEvent::Failed(e),
+                });
+            }
+        }
+        if now - self.last_report > REPORT_TICKS {
+            self.last_report = now;
+            return Some(CalibrationEvent::Progress(sticks.map(span)));
+        }
+        None
+    }
+
+    fn check_gesture(
+        &mut self,
+        now: u64,
+        frame: &RadioFrame,
+        config: &RadioConfig,
+    ) -> Option<CalibrationEvent> {
+        let command = config.command(frame);
+        if command.z_throttle < STICK_THRESHOLD || command.twist_throttle > -STICK_THRESHOLD {
+            self.gesture_start = None;
+            return None;
+        }
+        let start = *self.gesture_start.get_or_insert(now);
+        if now - start < START_HOLD_TICKS {
+            return None;
+        }
+        self.gesture_start = None;
+        self.sweeping = true;
+        self.start = now;
+        self.last_report = now;
+        self.settled_since = None;
+        self.min = frame.raw;
+        self.max = frame.raw;
+        Some(CalibrationEvent::Started)
+    }
+
+    // sticks get min/max/where they came to rest, switches get min/max if they were flicked
+    fn result(
+        &self,
+        frame: &RadioFrame,
+        config: &RadioConfig,
+    ) -> Result<RadioConfig, CalibrationError> {
+        let map = config.map;
+        let mut new_config = *config;
+        for ch in [map.x, map.y, map.z, map.twist, map.mode, map.aux] {
+            let (min, max) = (self.min[ch], self.max[ch]);
+            let is_stick = ch != map.mode && ch != map.aux;
+            if max - min < MIN_SPAN {
+                if is_stick {
+                    return Err(CalibrationError::NotMoved(ch));
+                }
+                continue;
+            }
+            let center = if is_stick && ch != map.z {
+                frame.raw[ch]
+            } else {
+                min + (max - min) / 2
+            };
+            if !(MIN_RANGE.0..=MIN_RANGE.1).contains(&min)
+                || !(MAX_RANGE.0..=MAX_RANGE.1).contains(&max)
+                || !(CENTER_RANGE.0..=CENTER_RANGE.1).contains(&center)
+            {
+                return Err(CalibrationError::BadEndpoints(ch));
+            }
+            new_config.calibration[ch] = ChannelCalibration {
+                min,
+                center,
+                max,
+                reversed: config.calibration[ch].reversed,
+            };
+        }
+        Ok(new_config)
+    }
+}
+
+impl Default for StickCalibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,14 @@
+// everything the firmware is made of except main.rs, split out so the logic can be
+// built and tested on the host (cargo test-host)
+#![cfg_attr(not(test), no_std)]
+#![allow(incomplete_features)]
+#![feature(generic_const_exprs)]
+pub mod control;
+pub mod math;
+pub mod motors;
+pub mod sensors;
+pub mod sync;
+
+// defmt needs somewhere to log to in the test binary
+#[cfg(test)]
+use defmt_rtt as _;
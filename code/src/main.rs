@@ -1,32 +1,69 @@
 #![no_std]
 #![no_main]
-#![feature(generic_const_exprs)]
-mod control;
-mod math;
-mod sensors;
-use crate::sensors::imu::Accelerometer;
-use crate::sensors::imu::Gyroscope;
-use control::radio::Radio;
-use control::FlightSystem;
-use core::fmt::Write;
-use defmt::*;
 use defmt_rtt as _;
-use embedded_hal::PwmPin;
+use drone::control::flight_system::FlightConfig;
+use drone::control::ibus::IbusReceiver;
+use drone::control::ibus_telemetry::{start_ibus_telemetry, IbusSensor};
+use drone::control::radio::{Protocol, Radio};
+use drone::control::radio_config::{Curve, RadioConfig};
+use drone::control::{FlightSystem, Mixer};
+use drone::math::attitude::TiltSource;
+use drone::motors::dshot::{DShotCommand, DShotSpeed};
+use drone::motors::pwm::MotorProtocol;
+use drone::motors::{DShotMotors, PwmMotors};
+use drone::sensors::battery::Battery;
+use drone::sensors::data_ready::ImuInterrupt;
+use drone::sensors::imu::IMU_ADDR;
+use drone::sensors::transport::I2cBus;
+use drone::sensors::{AccelerometerSetting, GyroSetting, ICM_20948};
+use fugit::RateExtU32;
+use hal::i2c::I2C;
 use hal::pac;
 use hal::pwm::Slices;
 use hal::Clock;
 use panic_probe as _;
 use rp2040_hal as hal;
 use rp2040_hal::multicore::Multicore;
-use sensors::imu::{Sensor, ICM_20948};
-use crate::sensors::{AccelerometerSetting, GyroSetting};
 
 #[link_section = ".boot2"]
 #[used]
 pub static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
 
 const EXT_CLK_HZ: u32 = 12_000_000;
-const MOTOR_HZ: u32 = 1_000_000;
+// what the motors boot with, flight_system::request_motor_protocol changes it while disarmed
+const MOTOR_PROTOCOL: MotorProtocol = MotorProtocol::StandardPwm;
+// DShot off PIO0 instead of analog pwm, same pins either way
+const USE_DSHOT: bool = false;
+const DSHOT_SPEED: DShotSpeed = DShotSpeed::DShot600;
+const DSHOT_BIDIRECTIONAL: bool = true; // eRPM telemetry back from the escs
+const MOTOR_POLES: u8 = 14;
+// sent to every esc before flying, SpinDirectionReversed then SaveSettings flips them for good
+const ESC_COMMANDS: &[DShotCommand] = &[DShotCommand::Beep1];
+// mode switch off, rate, self level. put DroneCommand::FullManual in a position for no feedback at all
+const FLIGHT_CONFIG: FlightConfig = FlightConfig {
+    tilt_source: TiltSource::Mahony, // or Kalman, per axis with gyro bias tracking
+    ..FlightConfig::standard()
+};
+// Ibus(IbusReceiver::new()), Sbus(SbusReceiver::new()) or Crsf(CrsfReceiver::new()), all on gpio4/5
+const RECEIVER: Protocol = Protocol::Ibus(IbusReceiver::new());
+const STICK_CURVE: Curve = Curve {
+    deadband: 0.02,
+    expo: 0.2,
+    super_rate: 0.0,
+};
+const RADIO_CONFIG: RadioConfig = RadioConfig {
+    curves: [STICK_CURVE; 3],
+    ..RadioConfig::standard()
+};
+// shows up on the transmitter in this order
+const TELEMETRY_SENSORS: &[IbusSensor] = &[
+    IbusSensor::BatteryVoltage,
+    IbusSensor::Armed,
+    IbusSensor::FlightMode,
+    IbusSensor::Roll,
+    IbusSensor::Pitch,
+    IbusSensor::Heading,
+];
 
 #[rp2040_hal::entry]
 fn main() -> ! {
@@ -53,57 +90,72 @@ fn main() -> ! {
     .ok()
     .unwrap();
     let timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS);
-    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
-    let mut imu = ICM_20948::new(
-        AccelerometerSetting::r4g,
-        GyroSetting::r250dps,
+    let delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    let i2c = I2C::i2c0(
         pac.I2C0,
         pins.gpio24.into_mode(),
         pins.gpio25.into_mode(),
+        400.kHz(),
         &mut pac.RESETS,
+        clocks.system_clock.freq(),
+    );
+    // over SPI instead (up to 7 MHz, mode 3): SpiBus::new(spi, cs) in place of the I2cBus
+    // any FlightImu flies, an MPU_6050::new(.., I2cBus::new(i2c, MPU_ADDR)) just has no compass
+    let imu = ICM_20948::new(
+        AccelerometerSetting::r4g,
+        GyroSetting::r250dps,
+        I2cBus::new(i2c, IMU_ADDR),
     );
-    let mut radio = Radio::new(
+    let imu_interrupt = ImuInterrupt::new(pins.gpio21.into_mode());
+    let radio = Radio::new(
         pac.UART1,
         pins.gpio4.into_mode(),
         pins.gpio5.into_mode(),
         &mut pac.RESETS,
         clocks.peripheral_clock.freq(),
+        RECEIVER,
+        RADIO_CONFIG,
+    );
+    let battery = Battery::new(pac.ADC, pins.gpio26.into_floating_input(), &mut pac.RESETS);
+    start_ibus_telemetry(
+        pac.UART0,
+        pins.gpio16.into_mode(),
+        pins.gpio17.into_mode(),
+        &mut pac.RESETS,
+        clocks.peripheral_clock.freq(),
+        TELEMETRY_SENSORS,
     );
+    let mut mc = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
+    let cores = mc.cores();
+    let core1 = &mut cores[1];
+    if USE_DSHOT {
+        let motors = DShotMotors::new(
+            pac.PIO0,
+            pins.gpio0.into_mode(),
+            pins.gpio2.into_mode(),
+            pins.gpio20.into_mode(),
+            pins.gpio22.into_mode(),
+            DSHOT_SPEED,
+            DSHOT_BIDIRECTIONAL,
+            MOTOR_POLES,
+            clocks.system_clock.freq(),
+            &mut pac.RESETS,
+        );
+        let mut flight_system = FlightSystem::new(motors, Mixer::quad_x(), FLIGHT_CONFIG, timer);
+        for command in ESC_COMMANDS {
+            flight_system.special_command(*command);
+        }
+        flight_system.start(delay, imu, imu_interrupt, radio, battery, core1);
+    }
     let mut p0 = slices.pwm0;
     let mut p1 = slices.pwm1;
     let mut p2 = slices.pwm2;
     let mut p3 = slices.pwm3;
-    // standard 1-2 ms pwm
-    let pwm_int: u8 = 4;
-    let pwm_frac: u8 = 12;
-
-    // multishot
-    // let pwm_int: u8 = 0;
-    // let pwm_frac: u8 = 1;
-
-    p0.set_div_int(pwm_int);
-    p1.set_div_int(pwm_int);
-    p2.set_div_int(pwm_int);
-    p3.set_div_int(pwm_int);
-    p0.set_div_frac(pwm_frac);
-    p1.set_div_frac(pwm_frac);
-    p2.set_div_frac(pwm_frac);
-    p3.set_div_frac(pwm_frac);
-    p0.enable();
-    p1.enable();
-    p2.enable();
-    p3.enable();
-    let mut m0 = p0.channel_a;
-    let mut m1 = p1.channel_a;
-    let mut m2 = p2.channel_a;
-    let mut m3 = p3.channel_a;
-    let _ = m0.output_to(pins.gpio0);
-    let _ = m1.output_to(pins.gpio2);
-    let _ = m2.output_to(pins.gpio20);
-    let _ = m3.output_to(pins.gpio22);
-    let mut mc = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
-    let cores = mc.cores();
-    let core1 = &mut cores[1];
-    let mut flight_system = FlightSystem::new(m0, m1, m2, m3, timer);
-    flight_system.start(delay, imu, radio, core1);
+    let _ = p0.channel_a.output_to(pins.gpio0);
+    let _ = p1.channel_a.output_to(pins.gpio2);
+    let _ = p2.channel_a.output_to(pins.gpio20);
+    let _ = p3.channel_a.output_to(pins.gpio22);
+    let motors = PwmMotors::new(p0, p1, p2, p3, MOTOR_PROTOCOL, clocks.system_clock.freq());
+    let mut flight_system = FlightSystem::new(motors, Mixer::quad_x(), FLIGHT_CONFIG, timer);
+    flight_system.start(delay, imu, imu_interrupt, radio, battery, core1);
 }
@@ -0,0 +1,466 @@
+// Mahony complementary filter
+// gyro in dps, accelerometer and magnetometer in whatever units (they get normalized)
+use crate::math::functions::{accelerometer_tilt, KalmanFilter, RAD2DEGF};
+use libm::{asinf, atan2f, cosf, sinf, sqrtf};
+
+const DEG2RADF: f32 = 1.0 / RAD2DEGF;
+
+pub struct AttitudeEstimator {
+    pub k_p: f32,
+    pub k_i: f32,
+    q: [f32; 4], // w x y z, body to earth
+    integral: [f32; 3],
+}
+
+impl AttitudeEstimator {
+    pub fn new(k_p: f32, k_i: f32) -> Self {
+        Self {
+            k_p,
+            k_i,
+            q: [1.0, 0.0, 0.0, 0.0],
+            integral: [0.0; 3],
+        }
+    }
+
+    // start from the accelerometer tilt so it doesnt have to converge from level
+    pub fn from_acc(k_p: f32, k_i: f32, acc: [f32; 3]) -> Self {
+        let mut estimator = Self::new(k_p, k_i);
+        let [roll, pitch] = accelerometer_tilt(acc, false);
+        estimator.q = euler_to_quaternion([roll, pitch, 0.0]);
+        estimator
+    }
+
+    pub fn update(&mut self, gyr: [f32; 3], acc: [f32; 3], dt: f32) {
+        let error = match normalize(acc) {
+            Some(a) => self.gravity_error(a),
+            None => [0.0; 3],
+        };
+        self.integrate(gyr, error, dt);
+    }
+
+    pub fn update_with_mag(&mut self, gyr: [f32; 3], acc: [f32; 3], mag: [f32; 3], dt: f32) {
+        let a = match normalize(acc) {
+            Some(a) => a,
+            None => return self.integrate(gyr, [0.0; 3], dt),
+        };
+        let mut error = self.gravity_error(a);
+        if let Some(m) = normalize(mag) {
+            let heading_error = self.heading_error(m);
+            for i in 0..3 {
+                error[i] += heading_error[i];
+            }
+        }
+        self.integrate(gyr, error, dt);
+    }
+
+    pub fn quaternion(&self) -> [f32; 4] {
+        self.q
+    }
+
+    // roll, pitch, yaw
+    pub fn euler(&self, degrees: bool) -> [f32; 3] {
+        let [q0, q1, q2, q3] = self.q;
+        let mut angles = [
+            atan2f(q0 * q1 + q2 * q3, 0.5 - q1 * q1 - q2 * q2),
+            asinf((-2.0 * (q1 * q3 - q0 * q2)).clamp(-1.0, 1.0)),
+            atan2f(q1 * q2 + q0 * q3, 0.5 - q2 * q2 - q3 * q3),
+        ];
+        if degrees {
+            for angle in angles.iter_mut() {
+                *angle *= RAD2DEGF;
+            }
+        }
+        angles
+    }
+
+    // unit vector pointing up in the body frame, what a still accelerometer should read
+    pub fn gravity(&self) -> [f32; 3] {
+        let [q0, q1, q2, q3] = self.q;
+        [
+            2.0 * (q1 * q3 - q0 * q2),
+            2.0 * (q0 * q1 + q2 * q3),
+            q0 * q0 - q1 * q1 - q2 * q2 + q3 * q3,
+        ]
+    }
+
+    // half of the cross product between measured and estimated gravity
+    fn gravity_error(&self, a: [f32; 3]) -> [f32; 3] {
+        let [q0, q1, q2, q3] = self.q;
+        let v = [
+            q1 * q3 - q0 * q2,
+            q0 * q1 + q2 * q3,
+            q0 * q0 - 0.5 + q3 * q3,
+        ];
+        cross(a, v)
+    }
+
+    // same thing for the earth magnetic field, flattened onto north and down
+    fn heading_error(&self, m: [f32; 3]) -> [f32; 3] {
+        let [q0, q1, q2, q3] = self.q;
+        let hx = 2.0
+            * (m[0] * (0.5 - q2 * q2 - q3 * q3)
+                + m[1] * (q1 * q2 - q0 * q3)
+                + m[2] * (q1 * q3 + q0 * q2));
+        let hy = 2.0
+            * (m[0] * (q1 * q2 + q0 * q3)
+                + m[1] * (0.5 - q1 * q1 - q3 * q3)
+                + m[2] * (q2 * q3 - q0 * q1));
+        let bx = sqrtf(hx * hx + hy * hy);
+        let bz = 2.0
+            * (m[0] * (q1 * q3 - q0 * q2)
+                + m[1] * (q2 * q3 + q0 * q1)
+                + m[2] * (0.5 - q1 * q1 - q2 * q2));
+        let w = [
+            bx * (0.5 - q2 * q2 - q3 * q3) + bz * (q1 * q3 - q0 * q2),
+            bx * (q1 * q2 - q0 * q3) + bz * (q0 * q1 + q2 * q3),
+            bx * (q0 * q2 + q1 * q3) + bz * (0.5 - q1 * q1 - q2 * q2),
+        ];
+        cross(m, w)
+    }
+
+    fn integrate(&mut self, gyr: [f32; 3], error: [f32; 3], dt: f32) {
+        let mut g = [gyr[0] * DEG2RADF, gyr[1] * DEG2RADF, gyr[2] * DEG2RADF];
+        for i in 0..3 {
+            if self.k_i > 0.0 {
+                self.integral[i] += 2.0 * self.k_i * error[i] * dt;
+            } else {
+                self.integral[i] = 0.0;
+            }
+            g[i] += self.integral[i] + 2.0 * self.k_p * error[i];
+            g[i] *= 0.5 * dt;
+        }
+        let [q0, q1, q2, q3] = self.q;
+        let q = [
+            q0 - q1 * g[0] - q2 * g[1] - q3 * g[2],
+            q1 + q0 * g[0] + q2 * g[2] - q3 * g[1],
+            q2 + q0 * g[1] - q1 * g[2] + q3 * g[0],
+            q3 + q0 * g[2] + q1 * g[1] - q2 * g[0],
+        ];
+        let norm = 1.0 / sqrtf(q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]);
+        self.q = q.map(|x| x * norm);
+    }
+}
+
+// one kalman filter per tilt axis, tracks gyro bias alongside the angle
+// degrees and dps throughout
+pub struct TiltEstimator {
+    filters: [KalmanFilter; 2],
+}
+
+impl TiltEstimator {
+    pub fn new(q_angle: f32, q_bias: f32, r_measure: f32) -> Self {
+        Self {
+            filters: [
+                KalmanFilter::new(q_angle, q_bias, r_measure),
+                KalmanFilter::new(q_angle, q_bias, r_measure),
+            ],
+        }
+    }
+
+    pub fn from_acc(q_angle: f32, q_bias: f32, r_measure: f32, acc: [f32; 3]) -> Self {
+        let mut estimator = Self::new(q_angle, q_bias, r_measure);
+        let measured = Self::measure(acc);
+        for (filter, angle) in estimator.filters.iter_mut().zip(measured) {
+            filter.x = angle;
+        }
+        estimator
+    }
+
+    pub fn push(&mut self, gyr: [f32; 3], acc: [f32; 3], dt: f32) {
+        let measured = Self::measure(acc);
+        for i in 0..2 {
+            self.filters[i].push(measured[i], gyr[i], dt);
+        }
+    }
+
+    // roll, pitch
+    pub fn angle(&self) -> [f32; 2] {
+        [self.filters[0].x, self.filters[1].x]
+    }
+
+    pub fn bias(&self) -> [f32; 2] {
+        [self.filters[0].bias(), self.filters[1].bias()]
+    }
+
+    // same roll and pitch the mahony estimator reports, so switching TiltSource doesnt move level
+    #[inline(always)]
+    fn measure(acc: [f32; 3]) -> [f32; 2] {
+        accelerometer_tilt(acc, true)
+    }
+}
+
+// which estimator gets to write true_angle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TiltSource {
+    Mahony,
+    Kalman,
+}
+
+impl TiltSource {
+    // roll, pitch in degrees
+    pub fn angle(&self, mahony: &AttitudeEstimator, kalman: &TiltEstimator) -> [f32; 2] {
+        match self {
+            Self::Mahony => {
+                let [roll, pitch, _] = mahony.euler(true);
+                [roll, pitch]
+            }
+            Self::Kalman => kalman.angle(),
+        }
+    }
+}
+
+// zyx order, radians
+pub fn euler_to_quaternion(euler: [f32; 3]) -> [f32; 4] {
+    let (sr, cr) = (sinf(euler[0] * 0.5), cosf(euler[0] * 0.5));
+    let (sp, cp) = (sinf(euler[1] * 0.5), cosf(euler[1] * 0.5));
+    let (sy, cy) = (sinf(euler[2] * 0.5), cosf(euler[2] * 0.5));
+    [
+        cr * cp * cy + sr * sp * sy,
+        sr * cp * cy - cr * sp * sy,
+        cr * sp * cy + sr * cp * sy,
+        cr * cp * sy - sr * sp * cy,
+    ]
+}
+
+#[inline(always)]
+fn normalize(v: [f32; 3]) -> Option<[f32; 3]> {
+    let norm = sqrtf(v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+    if norm == 0.0 {
+        None
+    } else {
+        Some([v[0] / norm, v[1] / norm, v[2] / norm])
+    }
+}
+
+#[inline(always)]
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1.0 / 1125.0;
+
+    // what a still accelerometer reads with the body at this roll and pitch, degrees
+    fn gravity_at(roll: f32, pitch: f32) -> [f32; 3] {
+        let (r, p) = (roll * DEG2RADF, pitch * DEG2RADF);
+        [-sinf(p), sinf(r) * cosf(p), cosf(r) * cosf(p)]
+    }
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        (a - b).abs() < tolerance
+    }
+
+    #[test]
+    fn starts_at_the_accelerometer_tilt() {
+        let estimator = AttitudeEstimator::from_acc(1.0, 0.0, gravity_at(30.0, -20.0));
+        let [roll, pitch, yaw] = estimator.euler(true);
+        assert!(
+            close(roll, 30.0, 0.01) && close(pitch, -20.0, 0.01),
+            "{} {}",
+            roll,
+            pitch
+        );
+        assert!(close(yaw, 0.0, 0.01));
+        let up = estimator.gravity();
+        let expected = gravity_at(30.0, -20.0);
+        for i in 0..3 {
+            assert!(close(up[i], expected[i], 1e-4), "{:?} {:?}", up, expected);
+        }
+    }
+
+    #[test]
+    fn integrates_the_gyro_on_every_axis() {
+        // no accelerometer correction, 45 dps for a second turns 45 degrees
+        for axis in 0..3 {
+            let mut estimator = AttitudeEstimator::new(0.0, 0.0);
+            let mut gyr = [0.0; 3];
+            gyr[axis] = 45.0;
+            for _ in 0..1125 {
+                estimator.update(gyr, [0.0, 0.0, 0.0], DT);
+            }
+            let euler = estimator.euler(true);
+            for i in 0..3 {
+                let expected = if i == axis { 45.0 } else { 0.0 };
+                assert!(
+                    close(euler[i], expected, 0.1),
+                    "axis {} gave {:?}",
+                    axis,
+                    euler
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn accelerometer_pulls_it_to_the_real_tilt() {
+        let mut estimator = AttitudeEstimator::new(1.0, 0.0);
+        for _ in 0..10 * 1125 {
+            estimator.update([0.0; 3], gravity_at(-25.0, 15.0), DT);
+        }
+        let [roll, pitch, _] = estimator.euler(true);
+        assert!(
+            close(roll, -25.0, 0.1) && close(pitch, 15.0, 0.1),
+            "{} {}",
+            roll,
+            pitch
+        );
+    }
+
+    #[test]
+    fn follows_a_rotation_and_stays_a_unit_quaternion() {
+        // rolling at 20 dps with the accelerometer agreeing the whole way
+        let mut estimator = AttitudeEstimator::new(1.0, 0.05);
+        for n in 1..=1125 {
+            let roll = 20.0 * n as f32 * DT;
+            estimator.update([20.0, 0.0, 0.0], gravity_at(roll, 0.0), DT);
+        }
+        let [roll, pitch, _] = estimator.euler(true);
+        assert!(
+            close(roll, 20.0, 0.1) && close(pitch, 0.0, 0.1),
+            "{} {}",
+            roll,
+            pitch
+        );
+        let q = estimator.quaternion();
+        let norm = q.iter().map(|x| x * x).sum::<f32>();
+        assert!(close(norm, 1.0, 1e-5));
+    }
+
+    #[test]
+    fn integral_eats_gyro_bias() {
+        let mut estimator = AttitudeEstimator::new(1.0, 0.05);
+        for _ in 0..60 * 1125 {
+            estimator.update([2.0, -1.5, 0.0], gravity_at(0.0, 0.0), DT);
+        }
+        let [roll, pitch, _] = estimator.euler(true);
+        // proportional only would sit a degree or so off, the integral takes it to level
+        assert!(
+            close(roll, 0.0, 0.1) && close(pitch, 0.0, 0.1),
+            "{} {}",
+            roll,
+            pitch
+        );
+    }
+
+    #[test]
+    fn magnetometer_sets_yaw() {
+        // level, field pointing north and down, body turned 30 degrees
+        let mut estimator = AttitudeEstimator::new(1.0, 0.0);
+        let yaw = 30.0 * DEG2RADF;
+        let mag = [cosf(yaw) * 0.5, -sinf(yaw) * 0.5, -0.8];
+        for _ in 0..30 * 1125 {
+            estimator.update_with_mag([0.0; 3], gravity_at(0.0, 0.0), mag, DT);
+        }
+        let [roll, pitch, yaw] = estimator.euler(true);
+        assert!(
+            close(roll, 0.0, 0.1) && close(pitch, 0.0, 0.1),
+            "{} {} {}",
+            roll,
+            pitch,
+            yaw
+        );
+        assert!(close(yaw, 30.0, 0.5), "{}", yaw);
+    }
+
+    // same gains core1 runs the kalman filters with
+    fn kalman(acc: [f32; 3]) -> TiltEstimator {
+        TiltEstimator::from_acc(0.001, 0.003, 0.03, acc)
+    }
+
+    #[test]
+    fn kalman_starts_at_the_accelerometer_tilt() {
+        let estimator = kalman(gravity_at(30.0, -20.0));
+        let [roll, pitch] = estimator.angle();
+        assert!(
+            close(roll, 30.0, 0.01) && close(pitch, -20.0, 0.01),
+            "{} {}",
+            roll,
+            pitch
+        );
+        assert_eq!(estimator.bias(), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn kalman_accelerometer_pulls_it_to_the_real_tilt() {
+        let mut estimator = TiltEstimator::new(0.001, 0.003, 0.03);
+        for _ in 0..10 * 1125 {
+            estimator.push([0.0; 3], gravity_at(-25.0, 15.0), DT);
+        }
+        let [roll, pitch] = estimator.angle();
+        assert!(
+            close(roll, -25.0, 0.1) && close(pitch, 15.0, 0.1),
+            "{} {}",
+            roll,
+            pitch
+        );
+    }
+
+    #[test]
+    fn kalman_learns_the_gyro_bias() {
+        // sitting still with a gyro that reads 3 and -2 dps, the bias ends up there and the
+        // angle doesnt walk off
+        let mut estimator = kalman(gravity_at(10.0, 5.0));
+        for _ in 0..30 * 1125 {
+            estimator.push([3.0, -2.0, 0.0], gravity_at(10.0, 5.0), DT);
+        }
+        let [roll_bias, pitch_bias] = estimator.bias();
+        assert!(
+            close(roll_bias, 3.0, 0.1) && close(pitch_bias, -2.0, 0.1),
+            "{} {}",
+            roll_bias,
+            pitch_bias
+        );
+        let [roll, pitch] = estimator.angle();
+        assert!(
+            close(roll, 10.0, 0.1) && close(pitch, 5.0, 0.1),
+            "{} {}",
+            roll,
+            pitch
+        );
+    }
+
+    #[test]
+    fn kalman_follows_a_rotation() {
+        // pitching at 20 dps with the accelerometer agreeing the whole way
+        let mut estimator = kalman(gravity_at(0.0, 0.0));
+        for n in 1..=1125 {
+            let pitch = 20.0 * n as f32 * DT;
+            estimator.push([0.0, 20.0, 0.0], gravity_at(0.0, pitch), DT);
+        }
+        let [roll, pitch] = estimator.angle();
+        assert!(
+            close(roll, 0.0, 0.1) && close(pitch, 20.0, 0.5),
+            "{} {}",
+            roll,
+            pitch
+        );
+    }
+
+    #[test]
+    fn tilt_source_picks_the_estimator() {
+        // point the two at different tilts so it is obvious which one answered
+        let mahony = AttitudeEstimator::from_acc(1.0, 0.0, gravity_at(30.0, -20.0));
+        let kalman = kalman(gravity_at(-10.0, 5.0));
+        let [roll, pitch] = TiltSource::Mahony.angle(&mahony, &kalman);
+        assert!(
+            close(roll, 30.0, 0.01) && close(pitch, -20.0, 0.01),
+            "{} {}",
+            roll,
+            pitch
+        );
+        let [roll, pitch] = TiltSource::Kalman.angle(&mahony, &kalman);
+        assert!(
+            close(roll, -10.0, 0.01) && close(pitch, 5.0, 0.01),
+            "{} {}",
+            roll,
+            pitch
+        );
+    }
+}
@@ -1,7 +1,5 @@
-use defmt::info;
-use defmt::Format;
 use libm;
-pub const PI: f64 = 3.141592653589793238462643383279506939937;
+pub const PI: f64 = core::f64::consts::PI;
 pub const RAD2DEGF: f32 = 180.0 / PI as f32;
 pub fn cartesian_to_polar<const N: usize>(cart: [f32; N], degrees: bool) -> [f32; N]
 where
@@ -14,10 +12,7 @@ where
 }
 #[inline(always)]
 pub fn cartesian_to_polar_magnitude<const N: usize>(cart: [f32; N]) -> f32 {
-    let mut mag: f32 = 0.0;
-    for i in 0..N {
-        mag += cart[i] * cart[i];
-    }
+    let mag: f32 = cart.iter().map(|c| c * c).sum();
     libm::sqrtf(mag)
 }
 // ik this is a weird implementation but it makes stuff make sense
@@ -33,6 +28,42 @@ pub fn cartesian_to_polar_theta<const N: usize>(cart: [f32; N], degrees: bool) -
     theta
 }
 
+// roll and pitch from gravity alone, same sign convention as the gyro
+#[inline(always)]
+pub fn accelerometer_tilt(acc: [f32; 3], degrees: bool) -> [f32; 2] {
+    let mut tilt = [
+        libm::atan2f(acc[1], acc[2]),
+        libm::atan2f(-acc[0], libm::sqrtf(acc[1] * acc[1] + acc[2] * acc[2])),
+    ];
+    if degrees {
+        tilt[0] *= RAD2DEGF;
+        tilt[1] *= RAD2DEGF;
+    }
+    tilt
+}
+
+// compass heading in degrees 0 to 360, tilt is [roll, pitch] in degrees like accelerometer_tilt
+// gives, mag has to be in the accelerometer's frame
+pub fn tilt_compensated_heading(mag: [f32; 3], tilt: [f32; 2]) -> f32 {
+    let (sr, cr) = (
+        libm::sinf(tilt[0] / RAD2DEGF),
+        libm::cosf(tilt[0] / RAD2DEGF),
+    );
+    let (sp, cp) = (
+        libm::sinf(tilt[1] / RAD2DEGF),
+        libm::cosf(tilt[1] / RAD2DEGF),
+    );
+    // rotate the field back to level, then its horizontal part points north
+    let x = mag[0] * cp + mag[1] * sp * sr + mag[2] * sp * cr;
+    let y = mag[2] * sr - mag[1] * cr;
+    let heading = libm::atan2f(y, x) * RAD2DEGF;
+    if heading < 0.0 {
+        heading + 360.0
+    } else {
+        heading
+    }
+}
+
 pub struct PD {
     pub k_p: f32,
     pub k_d: f32,
@@ -59,6 +90,10 @@ impl PD {
         self.last_error = error;
         self.k_p * error + self.k_d * v
     }
+    pub fn reset(&mut self) {
+        self.last_error = 0.0;
+        self.last_v = 0.0;
+    }
 }
 
 pub struct PID {
@@ -79,6 +114,10 @@ impl PID {
         self.integral += self.k_i * dt * error;
         self.pd.get_next(error, dt) + self.integral
     }
+    pub fn reset(&mut self) {
+        self.pd.reset();
+        self.integral = 0.0;
+    }
 }
 
 pub struct KalmanFilter {
@@ -128,15 +167,97 @@ impl KalmanFilter {
         self.p[1][0] -= k[1] * p00_temp;
         self.p[1][1] -= k[1] * p01_temp;
     }
+    pub fn bias(&self) -> f32 {
+        self.bias
+    }
 }
 
+// second order notch, rbj cookbook coefficients. retuning keeps the history so the
+// centre can follow something that moves every sample
+pub struct Notch {
+    q: f32,
+    b: [f32; 3], // b0, b1, b2 over a0
+    a: [f32; 2], // a1, a2 over a0
+    x: [f32; 2],
+    y: [f32; 2],
+    enabled: bool,
+}
 
+impl Notch {
+    pub fn new(q: f32) -> Self {
+        Self {
+            q,
+            b: [1.0, 0.0, 0.0],
+            a: [0.0, 0.0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+            enabled: false,
+        }
+    }
+    // anything not between 0 and nyquist passes straight through
+    pub fn set(&mut self, centre_hz: f32, sample_hz: f32) {
+        self.enabled = centre_hz > 0.0 && centre_hz < 0.5 * sample_hz;
+        if !self.enabled {
+            return;
+        }
+        let w0 = 2.0 * PI as f32 * centre_hz / sample_hz;
+        let cos_w0 = libm::cosf(w0);
+        let alpha = libm::sinf(w0) / (2.0 * self.q);
+        let a0 = 1.0 + alpha;
+        self.b = [1.0 / a0, -2.0 * cos_w0 / a0, 1.0 / a0];
+        self.a = [-2.0 * cos_w0 / a0, (1.0 - alpha) / a0];
+    }
+    pub fn push(&mut self, x: f32) -> f32 {
+        let y = if self.enabled {
+            self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+                - self.a[0] * self.y[0]
+                - self.a[1] * self.y[1]
+        } else {
+            x
+        };
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
 
+// one notch per motor per gyro axis, each sat on that motors rotation frequency
+// only the fundamental, the harmonics are above nyquist at imu rates
+const RPM_NOTCH_Q: f32 = 5.0;
+const RPM_NOTCH_MIN_HZ: f32 = 80.0; // slower than this is idle, and too close to what the pilot flies
 
+pub struct RpmFilter<const M: usize> {
+    notches: [[Notch; 3]; M],
+}
 
+impl<const M: usize> RpmFilter<M> {
+    pub fn new() -> Self {
+        Self {
+            notches: core::array::from_fn(|_| core::array::from_fn(|_| Notch::new(RPM_NOTCH_Q))),
+        }
+    }
+    // rpm of 0 (unknown or stopped) leaves that motors notches open
+    pub fn push(&mut self, gyr: [f32; 3], rpm: [u32; M], sample_hz: f32) -> [f32; 3] {
+        let mut filtered = gyr;
+        for (motor, notches) in self.notches.iter_mut().enumerate() {
+            let mut centre_hz = rpm[motor] as f32 / 60.0;
+            if centre_hz < RPM_NOTCH_MIN_HZ {
+                centre_hz = 0.0;
+            }
+            for axis in 0..3 {
+                notches[axis].set(centre_hz, sample_hz);
+                filtered[axis] = notches[axis].push(filtered[axis]);
+            }
+        }
+        filtered
+    }
+}
 
-
-
+impl<const M: usize> Default for RpmFilter<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 // useful for calibrating constants
 pub struct MaxOverN<const N: usize> {
@@ -163,4 +284,165 @@ impl<const N: usize> MaxOverN<N> {
         }
         max
     }
-}
\ No newline at end of file
+}
+
+impl<const N: usize> Default for MaxOverN<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_terms() {
+        let mut p = PID::new(2.0, 0.0, 0.0);
+        assert_eq!(p.get_next(1.5, 0.01), 3.0);
+
+        // integral keeps adding k_i * dt * error
+        let mut i = PID::new(0.0, 10.0, 0.0);
+        assert!((i.get_next(1.0, 0.1) - 1.0).abs() < 1e-6);
+        assert!((i.get_next(1.0, 0.1) - 2.0).abs() < 1e-6);
+        assert!((i.get_next(-1.0, 0.1) - 1.0).abs() < 1e-6);
+
+        // derivative is the change in error over dt, and holds over a zero dt
+        let mut d = PID::new(0.0, 0.0, 1.0);
+        assert!((d.get_next(1.0, 0.5) - 2.0).abs() < 1e-6);
+        assert!((d.get_next(2.0, 0.5) - 2.0).abs() < 1e-6);
+        assert!((d.get_next(5.0, 0.0) - 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn pid_reset() {
+        let mut pid = PID::new(1.0, 1.0, 1.0);
+        pid.get_next(3.0, 0.5);
+        pid.reset();
+        let mut fresh = PID::new(1.0, 1.0, 1.0);
+        assert_eq!(pid.get_next(1.0, 0.5), fresh.get_next(1.0, 0.5));
+    }
+
+    // biggest output over the last second of a sine at freq_hz through f
+    fn sine_through(freq_hz: f32, mut f: impl FnMut(f32) -> f32) -> f32 {
+        const SAMPLE_HZ: f32 = 1125.0;
+        let mut peak: f32 = 0.0;
+        for n in 0..3 * 1125 {
+            let x = libm::sinf(2.0 * PI as f32 * freq_hz * n as f32 / SAMPLE_HZ);
+            let y = f(x);
+            if n >= 2 * 1125 {
+                peak = peak.max(y.abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn notch_removes_its_centre_and_keeps_the_rest() {
+        let mut notch = Notch::new(5.0);
+        notch.set(300.0, 1125.0);
+        assert!(sine_through(300.0, |x| notch.push(x)) < 0.01);
+        let mut notch = Notch::new(5.0);
+        notch.set(300.0, 1125.0);
+        assert!(sine_through(20.0, |x| notch.push(x)) > 0.98);
+        // and dc comes through untouched
+        let mut notch = Notch::new(5.0);
+        notch.set(300.0, 1125.0);
+        let mut y = 0.0;
+        for _ in 0..1000 {
+            y = notch.push(1.0);
+        }
+        assert!((y - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn notch_passes_through_when_off() {
+        let mut notch = Notch::new(5.0);
+        assert_eq!(notch.push(0.7), 0.7);
+        // past nyquist it cant do anything useful
+        notch.set(600.0, 1125.0);
+        assert_eq!(notch.push(-0.3), -0.3);
+        notch.set(0.0, 1125.0);
+        assert_eq!(notch.push(0.2), 0.2);
+    }
+
+    #[test]
+    fn rpm_filter_follows_each_motor() {
+        // motor 2 at 15000 rpm shakes the gyro at 250 Hz on every axis
+        let rpm = [0, 9000, 15000, 9000];
+        for axis in 0..3 {
+            let mut filter: RpmFilter<4> = RpmFilter::new();
+            let peak = sine_through(250.0, |x| {
+                let mut gyr = [0.0; 3];
+                gyr[axis] = x;
+                filter.push(gyr, rpm, 1125.0)[axis]
+            });
+            assert!(peak < 0.05, "axis {} peak {}", axis, peak);
+        }
+        // slow stick inputs dont get touched much
+        let mut filter: RpmFilter<4> = RpmFilter::new();
+        let peak = sine_through(5.0, |x| filter.push([x; 3], rpm, 1125.0)[0]);
+        assert!(peak > 0.95, "{}", peak);
+    }
+
+    #[test]
+    fn rpm_filter_is_open_at_idle() {
+        // no telemetry or barely turning, nothing changes
+        let mut filter: RpmFilter<4> = RpmFilter::new();
+        for n in 0..100 {
+            let gyr = [n as f32, -(n as f32), 0.5];
+            assert_eq!(filter.push(gyr, [0, 0, 3000, 0], 1125.0), gyr);
+        }
+    }
+
+    // what the magnetometer reads with the nose at heading, banked by roll and pitch,
+    // same zyx order as the attitude estimator. field dips 60 degrees down
+    fn field_at(heading: f32, roll: f32, pitch: f32) -> [f32; 3] {
+        let (sy, cy) = (heading / RAD2DEGF).sin_cos();
+        let (sp, cp) = (pitch / RAD2DEGF).sin_cos();
+        let (sr, cr) = (roll / RAD2DEGF).sin_cos();
+        let (h, v) = (25.0, 43.0);
+        let level = [h * cy, -h * sy, v];
+        let pitched = [
+            cp * level[0] - sp * level[2],
+            level[1],
+            sp * level[0] + cp * level[2],
+        ];
+        [
+            pitched[0],
+            cr * pitched[1] + sr * pitched[2],
+            -sr * pitched[1] + cr * pitched[2],
+        ]
+    }
+
+    fn heading_error(a: f32, b: f32) -> f32 {
+        let d = (a - b).abs();
+        d.min(360.0 - d)
+    }
+
+    #[test]
+    fn heading_level() {
+        for heading in [0.0, 30.0, 90.0, 179.0, 181.0, 270.0, 345.0] {
+            let got = tilt_compensated_heading(field_at(heading, 0.0, 0.0), [0.0, 0.0]);
+            assert!((0.0..360.0).contains(&got));
+            assert!(heading_error(got, heading) < 0.01, "{} vs {}", got, heading);
+        }
+    }
+
+    #[test]
+    fn heading_tilted() {
+        for heading in [0.0, 45.0, 120.0, 200.0, 300.0] {
+            for (roll, pitch) in [(20.0, 0.0), (0.0, -25.0), (30.0, 15.0), (-40.0, 35.0)] {
+                let got = tilt_compensated_heading(field_at(heading, roll, pitch), [roll, pitch]);
+                assert!(
+                    heading_error(got, heading) < 0.01,
+                    "{} at roll {} pitch {} came out {}",
+                    heading,
+                    roll,
+                    pitch,
+                    got
+                );
+            }
+        }
+    }
+}
@@ -1 +1,2 @@
+pub mod attitude;
 pub mod functions;
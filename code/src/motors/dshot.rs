@@ -0,0 +1,381 @@
+// DShot150/300/600 on PIO0, one state machine per motor
+// frame is 11 bits of value, 1 telemetry request bit and a 4 bit crc, sent msb first
+// bidirectional mode inverts the line and the crc, then listens for an eRPM reply
+use super::telemetry::{decode_rpm, TelemetryError};
+use super::MotorOutput;
+use embedded_hal::blocking::delay::DelayUs;
+use fugit::HertzU32;
+use hal::gpio::bank0::BankPinId;
+use hal::gpio::{FunctionPio0, Pin, PinId};
+use hal::pac::{self, PIO0};
+use hal::pio::{PIOBuilder, PIOExt, PinDir, PinState, Rx, ShiftDirection, Tx, SM0, SM1, SM2, SM3};
+use rp2040_hal as hal;
+
+pub const DSHOT_MIN_THROTTLE: u16 = 48; // 0-47 are commands
+pub const DSHOT_MAX_THROTTLE: u16 = 2047;
+const CYCLES_PER_BIT: u32 = 8; // has to match the pio program
+const BIDIRECTIONAL_CYCLES_PER_BIT: u32 = 40; // reply bits are 32 cycles
+const REPLY_TIMEOUT_US: u32 = 60; // esc starts answering about 30us after the frame
+const COMMAND_REPEATS: u8 = 10; // settings commands have to be seen at least 6 times
+const COMMAND_GAP_US: u32 = 1000;
+
+#[derive(Clone, Copy)]
+pub enum DShotSpeed {
+    DShot150,
+    DShot300,
+    DShot600,
+}
+
+impl DShotSpeed {
+    pub fn bitrate(&self) -> u32 {
+        match self {
+            Self::DShot150 => 150_000,
+            Self::DShot300 => 300_000,
+            Self::DShot600 => 600_000,
+        }
+    }
+}
+
+// only do anything while the motors are stopped
+#[derive(Clone, Copy, PartialEq)]
+pub enum DShotCommand {
+    MotorStop = 0,
+    Beep1 = 1,
+    Beep2 = 2,
+    Beep3 = 3,
+    Beep4 = 4,
+    Beep5 = 5,
+    EscInfo = 6,
+    SpinDirection1 = 7,
+    SpinDirection2 = 8,
+    ThreeDModeOff = 9,
+    ThreeDModeOn = 10,
+    SettingsRequest = 11,
+    SaveSettings = 12,
+    SpinDirectionNormal = 20,
+    SpinDirectionReversed = 21,
+}
+
+impl DShotCommand {
+    pub fn value(&self) -> u16 {
+        *self as u16
+    }
+    fn repeats(&self) -> u8 {
+        match self {
+            Self::MotorStop
+            | Self::Beep1
+            | Self::Beep2
+            | Self::Beep3
+            | Self::Beep4
+            | Self::Beep5
+            | Self::EscInfo => 1,
+            _ => COMMAND_REPEATS,
+        }
+    }
+}
+
+#[inline(always)]
+pub fn dshot_crc(packet: u16) -> u16 {
+    (packet ^ (packet >> 4) ^ (packet >> 8)) & 0x0F
+}
+
+#[inline(always)]
+pub fn encode_frame(value: u16, telemetry: bool) -> u16 {
+    let packet = ((value & 0x07FF) << 1) | telemetry as u16;
+    (packet << 4) | dshot_crc(packet)
+}
+
+// same thing with the crc inverted, tells the esc to answer with eRPM
+#[inline(always)]
+pub fn encode_bidirectional_frame(value: u16, telemetry: bool) -> u16 {
+    let packet = ((value & 0x07FF) << 1) | telemetry as u16;
+    (packet << 4) | (!dshot_crc(packet) & 0x0F)
+}
+
+// 0 stops the motor, anything above maps onto 48-2047
+#[inline(always)]
+pub fn throttle_value(speed: f32) -> u16 {
+    if speed <= 0.0 {
+        DShotCommand::MotorStop.value()
+    } else {
+        DSHOT_MIN_THROTTLE
+            + (speed.min(1.0) * (DSHOT_MAX_THROTTLE - DSHOT_MIN_THROTTLE) as f32) as u16
+    }
+}
+
+// 3 cycles high, 3 cycles of data, 2 cycles low
+// so a 0 is 37.5% high and a 1 is 75% high
+fn dshot_program() -> pio::Program<32> {
+    let mut a = pio::Assembler::<32>::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut bit_loop = a.label();
+    a.bind(&mut wrap_target);
+    a.pull(false, true);
+    a.bind(&mut bit_loop);
+    a.set_with_delay(pio::SetDestination::PINS, 1, 2);
+    a.out_with_delay(pio::OutDestination::PINS, 1, 2);
+    a.set(pio::SetDestination::PINS, 0);
+    a.jmp(
+        pio::JmpCondition::OutputShiftRegisterNotEmpty,
+        &mut bit_loop,
+    );
+    a.nop_with_delay(31); // gap between frames
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+// idles high: 15 cycles low, 15 cycles of (inverted) data, 10 cycles high
+// the pushed word is the inverted frame in the top half and the reply timeout in the bottom half
+// then the pin turns around, waits for the start bit and samples 21 bits 32 cycles apart
+fn bidirectional_program() -> pio::Program<32> {
+    let mut a = pio::Assembler::<32>::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut tx_bit = a.label();
+    let mut wait_start = a.label();
+    let mut still_idle = a.label();
+    let mut got_start = a.label();
+    let mut rx_bit = a.label();
+    a.bind(&mut wrap_target);
+    a.pull(false, true);
+    a.set(pio::SetDestination::PINDIRS, 1);
+    a.set(pio::SetDestination::Y, 15);
+    a.bind(&mut tx_bit);
+    a.set_with_delay(pio::SetDestination::PINS, 0, 14);
+    a.out_with_delay(pio::OutDestination::PINS, 1, 14);
+    a.set_with_delay(pio::SetDestination::PINS, 1, 7);
+    a.jmp_with_delay(pio::JmpCondition::YDecNonZero, &mut tx_bit, 1);
+    a.out(pio::OutDestination::X, 16);
+    a.set(pio::SetDestination::PINDIRS, 0);
+    a.bind(&mut wait_start);
+    a.jmp(pio::JmpCondition::PinHigh, &mut still_idle);
+    a.jmp(pio::JmpCondition::Always, &mut got_start);
+    a.bind(&mut still_idle);
+    a.jmp(pio::JmpCondition::XDecNonZero, &mut wait_start);
+    a.jmp(pio::JmpCondition::Always, &mut wrap_target); // esc never answered
+    a.bind(&mut got_start);
+    a.set_with_delay(pio::SetDestination::Y, 20, 15); // into the middle of the start bit
+    a.bind(&mut rx_bit);
+    a.in_with_delay(pio::InSource::PINS, 1, 30);
+    a.jmp(pio::JmpCondition::YDecNonZero, &mut rx_bit);
+    a.push(false, false);
+    a.bind(&mut wrap_source);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+pub struct DShotMotors {
+    fl: Tx<(PIO0, SM0)>,
+    bl: Tx<(PIO0, SM1)>,
+    br: Tx<(PIO0, SM2)>,
+    fr: Tx<(PIO0, SM3)>,
+    fl_rx: Rx<(PIO0, SM0)>,
+    bl_rx: Rx<(PIO0, SM1)>,
+    br_rx: Rx<(PIO0, SM2)>,
+    fr_rx: Rx<(PIO0, SM3)>,
+    bidirectional: bool,
+    pole_count: u8,
+    reply_timeout: u16,
+}
+
+impl DShotMotors {
+    // one argument per motor pin, bundling them up wouldnt make the call any shorter
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<FL, BL, BR, FR>(
+        pio0: pac::PIO0,
+        _fl_pin: Pin<FL, FunctionPio0>,
+        _bl_pin: Pin<BL, FunctionPio0>,
+        _br_pin: Pin<BR, FunctionPio0>,
+        _fr_pin: Pin<FR, FunctionPio0>,
+        speed: DShotSpeed,
+        bidirectional: bool,
+        pole_count: u8, // only matters for bidirectional
+        system_clock: HertzU32,
+        resets: &mut pac::RESETS,
+    ) -> Self
+    where
+        FL: PinId + BankPinId,
+        BL: PinId + BankPinId,
+        BR: PinId + BankPinId,
+        FR: PinId + BankPinId,
+    {
+        let (program, cycles_per_bit) = if bidirectional {
+            (bidirectional_program(), BIDIRECTIONAL_CYCLES_PER_BIT)
+        } else {
+            (dshot_program(), CYCLES_PER_BIT)
+        };
+
+        let (mut pio, sm0, sm1, sm2, sm3) = pio0.split(resets);
+        let installed = pio.install(&program).unwrap();
+
+        let sm_clock = speed.bitrate() * cycles_per_bit;
+        let div_int = (system_clock.to_Hz() / sm_clock) as u16;
+        let div_frac = ((system_clock.to_Hz() % sm_clock) * 256 / sm_clock) as u8;
+        // two cycles per go around the wait loop
+        let reply_timeout = (REPLY_TIMEOUT_US * (sm_clock / 1_000_000) / 2) as u16;
+
+        // every state machine gets the same program, just a different pin
+        macro_rules! dshot_sm {
+            ($sm:expr, $program:expr, $pin:expr) => {{
+                let (mut sm, rx, tx) = PIOBuilder::from_program($program)
+                    .set_pins($pin, 1)
+                    .out_pins($pin, 1)
+                    .in_pin_base($pin)
+                    .jmp_pin($pin)
+                    .out_shift_direction(ShiftDirection::Left)
+                    .in_shift_direction(ShiftDirection::Left)
+                    .autopull(false)
+                    .autopush(false)
+                    .pull_threshold(if bidirectional { 0 } else { 16 }) // 0 means 32
+                    .clock_divisor_fixed_point(div_int, div_frac)
+                    .build($sm);
+                let idle = if bidirectional {
+                    PinState::High
+                } else {
+                    PinState::Low
+                };
+                sm.set_pins([($pin, idle)]);
+                sm.set_pindirs([($pin, PinDir::Output)]);
+                sm.start();
+                (tx, rx)
+            }};
+        }
+        // safety: all four run the same program and none of them get uninstalled
+        let (fl, fl_rx) = dshot_sm!(sm0, unsafe { installed.share() }, FL::DYN.num);
+        let (bl, bl_rx) = dshot_sm!(sm1, unsafe { installed.share() }, BL::DYN.num);
+        let (br, br_rx) = dshot_sm!(sm2, unsafe { installed.share() }, BR::DYN.num);
+        let (fr, fr_rx) = dshot_sm!(sm3, installed, FR::DYN.num);
+        Self {
+            fl,
+            bl,
+            br,
+            fr,
+            fl_rx,
+            bl_rx,
+            br_rx,
+            fr_rx,
+            bidirectional,
+            pole_count,
+            reply_timeout,
+        }
+    }
+
+    #[inline(always)]
+    fn encode(&self, value: u16, telemetry: bool) -> u16 {
+        if self.bidirectional {
+            encode_bidirectional_frame(value, telemetry)
+        } else {
+            encode_frame(value, telemetry)
+        }
+    }
+
+    // clockwise starting at Front Left, drops the frame if the fifo is full
+    pub fn send_frames(&mut self, frames: [u16; 4]) {
+        let words = if self.bidirectional {
+            frames.map(|frame| ((!frame as u32) << 16) | self.reply_timeout as u32)
+        } else {
+            frames.map(|frame| (frame as u32) << 16)
+        };
+        self.fl.write(words[0]);
+        self.fr.write(words[1]);
+        self.br.write(words[2]);
+        self.bl.write(words[3]);
+    }
+}
+
+impl MotorOutput for DShotMotors {
+    fn set_speeds(&mut self, speeds: [f32; 4]) {
+        self.send_frames(speeds.map(|speed| self.encode(throttle_value(speed), false)));
+    }
+
+    fn special_command<D: DelayUs<u32>>(&mut self, command: DShotCommand, delay: &mut D) {
+        let frame = self.encode(command.value(), true);
+        for _ in 0..command.repeats() {
+            self.send_frames([frame; 4]);
+            delay.delay_us(COMMAND_GAP_US);
+        }
+    }
+
+    fn read_rpm(&mut self) -> Option<[Result<u32, TelemetryError>; 4]> {
+        if !self.bidirectional {
+            return None;
+        }
+        let pole_count = self.pole_count;
+        // only the newest reply matters
+        let mut raw = [None; 4];
+        while let Some(r) = self.fl_rx.read() {
+            raw[0] = Some(r);
+        }
+        while let Some(r) = self.fr_rx.read() {
+            raw[1] = Some(r);
+        }
+        while let Some(r) = self.br_rx.read() {
+            raw[2] = Some(r);
+        }
+        while let Some(r) = self.bl_rx.read() {
+            raw[3] = Some(r);
+        }
+        Some(raw.map(|r| match r {
+            Some(r) => decode_rpm(r, pole_count),
+            None => Err(TelemetryError::NoReply),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_matches_the_spec_example() {
+        // throttle 1046 without telemetry is 1000001011000110
+        assert_eq!(encode_frame(1046, false), 0b1000_0010_1100_0110);
+        assert_eq!(dshot_crc(1046 << 1), 0b0110);
+    }
+
+    #[test]
+    fn crc_checks_out_for_every_value() {
+        // xoring the three data nibbles and the crc together comes out 0, inverted its 0xF
+        for value in 0..=DSHOT_MAX_THROTTLE {
+            for telemetry in [false, true] {
+                for (frame, expected) in [
+                    (encode_frame(value, telemetry), 0x0),
+                    (encode_bidirectional_frame(value, telemetry), 0xF),
+                ] {
+                    assert_eq!(frame >> 5, value);
+                    assert_eq!((frame >> 4) & 1 == 1, telemetry);
+                    let nibbles = (frame >> 12) ^ (frame >> 8) ^ (frame >> 4) ^ frame;
+                    assert_eq!(nibbles & 0x0F, expected, "{:#06x}", frame);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn value_is_only_11_bits() {
+        assert_eq!(
+            encode_frame(0xFFFF, false),
+            encode_frame(DSHOT_MAX_THROTTLE, false)
+        );
+    }
+
+    #[test]
+    fn throttle_range() {
+        assert_eq!(throttle_value(0.0), 0);
+        assert_eq!(throttle_value(-1.0), 0);
+        assert_eq!(throttle_value(0.0001), DSHOT_MIN_THROTTLE);
+        assert_eq!(throttle_value(0.5), DSHOT_MIN_THROTTLE + 999);
+        assert_eq!(throttle_value(1.0), DSHOT_MAX_THROTTLE);
+        assert_eq!(throttle_value(2.0), DSHOT_MAX_THROTTLE);
+        // never lands on a command
+        assert!((1..=1000).all(|n| throttle_value(n as f32 / 1000.0) >= DSHOT_MIN_THROTTLE));
+    }
+
+    #[test]
+    fn settings_commands_repeat() {
+        assert_eq!(DShotCommand::Beep1.repeats(), 1);
+        assert_eq!(DShotCommand::SaveSettings.repeats(), COMMAND_REPEATS);
+        const { assert!(COMMAND_REPEATS >= 6) };
+        assert_eq!(DShotCommand::SpinDirectionReversed.value(), 21);
+    }
+}
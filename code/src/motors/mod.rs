@@ -0,0 +1,29 @@
+pub mod dshot;
+pub mod pwm;
+pub mod telemetry;
+use dshot::DShotCommand;
+pub use dshot::DShotMotors;
+use embedded_hal::blocking::delay::DelayUs;
+use pwm::MotorProtocol;
+pub use pwm::PwmMotors;
+use telemetry::TelemetryError;
+
+// anything that can spin the four motors
+pub trait MotorOutput {
+    // 0 to 1, clockwise starting at Front Left
+    fn set_speeds(&mut self, speeds: [f32; 4]);
+    fn stop(&mut self) {
+        self.set_speeds([0.0; 4]);
+    }
+    // newest rpm per motor since the last call, None if the backend cant measure it
+    fn read_rpm(&mut self) -> Option<[Result<u32, TelemetryError>; 4]> {
+        None
+    }
+    // beeps, spin direction and the like, only with the motors stopped. analog escs cant take them
+    fn special_command<D: DelayUs<u32>>(&mut self, _command: DShotCommand, _delay: &mut D) {}
+    // false if the backend has no say in it, like dshot. only while disarmed, escs usually
+    // need a power cycle to pick up a new protocol too
+    fn set_protocol(&mut self, _protocol: MotorProtocol) -> bool {
+        false
+    }
+}
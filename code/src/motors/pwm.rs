@@ -0,0 +1,262 @@
+// analog esc pwm off the hardware pwm slices
+use super::MotorOutput;
+use defmt::Format;
+use embedded_hal::PwmPin;
+use fugit::HertzU32;
+use hal::pwm::{FreeRunning, Pwm0, Pwm1, Pwm2, Pwm3, Slice};
+use rp2040_hal as hal;
+
+#[derive(Clone, Copy, PartialEq, Debug, Format)]
+pub enum MotorProtocol {
+    StandardPwm, // 1-2 ms
+    Oneshot125,  // 125-250 us
+    Oneshot42,   // 42-84 us
+    Multishot,   // 5-25 us
+}
+
+// everything a slice needs, divider is int + frac/16
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PwmTiming {
+    pub div_int: u8,
+    pub div_frac: u8,
+    pub top: u16,
+    pub min_duty: u16,
+    pub max_duty: u16,
+}
+
+impl PwmTiming {
+    // 0-1 onto the pulse range
+    pub fn duty(&self, speed: f32) -> u16 {
+        let range = (self.max_duty - self.min_duty) as f32;
+        (speed.clamp(0.0, 1.0) * range) as u16 + self.min_duty
+    }
+}
+
+impl MotorProtocol {
+    // shortest and longest pulse in ns
+    pub fn pulse_range_ns(&self) -> (u32, u32) {
+        match self {
+            Self::StandardPwm => (1_000_000, 2_000_000),
+            Self::Oneshot125 => (125_000, 250_000),
+            Self::Oneshot42 => (42_000, 84_000),
+            Self::Multishot => (5_000, 25_000),
+        }
+    }
+
+    // leaves some low time after the longest pulse
+    pub fn period_ns(&self) -> u32 {
+        match self {
+            Self::StandardPwm => 2_500_000, // 400 Hz
+            Self::Oneshot125 => 500_000,    // 2 kHz
+            Self::Oneshot42 => 125_000,     // 8 kHz
+            Self::Multishot => 40_000,      // 25 kHz
+        }
+    }
+
+    pub fn timing(&self, system_clock: HertzU32) -> PwmTiming {
+        let clock = system_clock.to_Hz() as u64;
+        let counts = clock * self.period_ns() as u64 / 1_000_000_000;
+        // smallest divider (in 16ths) that still fits the period in 16 bits
+        let div16 = (counts * 16).div_ceil(0x10000).clamp(16, 255 * 16 + 15);
+        let ticks = |ns: u32| clock * ns as u64 * 16 / div16 / 1_000_000_000;
+        let (min, max) = self.pulse_range_ns();
+        PwmTiming {
+            div_int: (div16 / 16) as u8,
+            div_frac: (div16 % 16) as u8,
+            top: (ticks(self.period_ns()) - 1).min(0xFFFF) as u16,
+            min_duty: ticks(min) as u16,
+            max_duty: ticks(max) as u16,
+        }
+    }
+}
+
+// the protocol the slices are running, apart from the slices so switching can be tested
+#[derive(Clone, Copy)]
+pub struct PwmConfig {
+    system_clock: HertzU32,
+    protocol: MotorProtocol,
+    timing: PwmTiming,
+}
+
+impl PwmConfig {
+    pub fn new(protocol: MotorProtocol, system_clock: HertzU32) -> Self {
+        Self {
+            system_clock,
+            protocol,
+            timing: protocol.timing(system_clock),
+        }
+    }
+
+    pub fn protocol(&self) -> MotorProtocol {
+        self.protocol
+    }
+
+    pub fn timing(&self) -> PwmTiming {
+        self.timing
+    }
+
+    // the timing the slices need now, None if it was already running that
+    pub fn set_protocol(&mut self, protocol: MotorProtocol) -> Option<PwmTiming> {
+        if protocol == self.protocol {
+            return None;
+        }
+        self.protocol = protocol;
+        self.timing = protocol.timing(self.system_clock);
+        Some(self.timing)
+    }
+
+    pub fn duty(&self, speed: f32) -> u16 {
+        self.timing.duty(speed)
+    }
+}
+
+pub struct PwmMotors {
+    fl: Slice<Pwm0, FreeRunning>,
+    bl: Slice<Pwm1, FreeRunning>,
+    br: Slice<Pwm2, FreeRunning>,
+    fr: Slice<Pwm3, FreeRunning>,
+    config: PwmConfig,
+}
+
+impl PwmMotors {
+    // motors go on channel a of each slice
+    pub fn new(
+        fl: Slice<Pwm0, FreeRunning>,
+        bl: Slice<Pwm1, FreeRunning>,
+        br: Slice<Pwm2, FreeRunning>,
+        fr: Slice<Pwm3, FreeRunning>,
+        protocol: MotorProtocol,
+        system_clock: HertzU32,
+    ) -> Self {
+        let mut motors = Self {
+            fl,
+            bl,
+            br,
+            fr,
+            config: PwmConfig::new(protocol, system_clock),
+        };
+        motors.configure(motors.config.timing());
+        motors
+    }
+
+    fn configure(&mut self, timing: PwmTiming) {
+        macro_rules! configure {
+            ($slice:expr) => {
+                $slice.disable();
+                $slice.set_div_int(timing.div_int);
+                $slice.set_div_frac(timing.div_frac);
+                $slice.set_top(timing.top);
+                $slice.channel_a.set_duty(timing.min_duty);
+                $slice.enable();
+            };
+        }
+        configure!(self.fl);
+        configure!(self.bl);
+        configure!(self.br);
+        configure!(self.fr);
+    }
+}
+
+impl MotorOutput for PwmMotors {
+    fn set_speeds(&mut self, speeds: [f32; 4]) {
+        let speedsu16 = speeds.map(|speed| self.config.duty(speed));
+        self.fl.channel_a.set_duty(speedsu16[0]);
+        self.fr.channel_a.set_duty(speedsu16[1]);
+        self.br.channel_a.set_duty(speedsu16[2]);
+        self.bl.channel_a.set_duty(speedsu16[3]);
+    }
+
+    fn set_protocol(&mut self, protocol: MotorProtocol) -> bool {
+        if let Some(timing) = self.config.set_protocol(protocol) {
+            self.configure(timing);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::RateExtU32;
+
+    const PROTOCOLS: [MotorProtocol; 4] = [
+        MotorProtocol::StandardPwm,
+        MotorProtocol::Oneshot125,
+        MotorProtocol::Oneshot42,
+        MotorProtocol::Multishot,
+    ];
+
+    // how long a count of the slice lasts, ns
+    fn tick_ns(timing: &PwmTiming, clock_hz: u32) -> f64 {
+        (timing.div_int as f64 + timing.div_frac as f64 / 16.0) * 1e9 / clock_hz as f64
+    }
+
+    #[test]
+    fn pulses_come_out_the_right_length() {
+        for clock_hz in [125_000_000, 133_000_000, 48_000_000, 12_000_000] {
+            for protocol in PROTOCOLS {
+                let timing = protocol.timing(clock_hz.Hz());
+                let tick = tick_ns(&timing, clock_hz);
+                let (min, max) = protocol.pulse_range_ns();
+                let period = (timing.top as f64 + 1.0) * tick;
+                assert!(
+                    (period - protocol.period_ns() as f64).abs() <= tick,
+                    "{} Hz",
+                    clock_hz
+                );
+                assert!((timing.min_duty as f64 * tick - min as f64).abs() <= tick);
+                assert!((timing.max_duty as f64 * tick - max as f64).abs() <= tick);
+                // the longest pulse still ends before the next period starts
+                assert!(timing.max_duty <= timing.top);
+                assert!(timing.div_int >= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn uses_the_finest_divider_that_fits() {
+        // 400 Hz at 125 MHz needs dividing, the fast ones run straight off the clock
+        let standard = MotorProtocol::StandardPwm.timing(125.MHz());
+        assert_eq!((standard.div_int, standard.div_frac), (4, 13));
+        let multishot = MotorProtocol::Multishot.timing(125.MHz());
+        assert_eq!((multishot.div_int, multishot.div_frac), (1, 0));
+        assert_eq!(multishot.top, 4999);
+        // lots of resolution left everywhere at 125 MHz
+        for protocol in PROTOCOLS {
+            let timing = protocol.timing(125.MHz());
+            assert!(timing.max_duty - timing.min_duty >= 1000);
+        }
+    }
+
+    #[test]
+    fn duty_covers_the_pulse_range() {
+        for protocol in PROTOCOLS {
+            let timing = protocol.timing(125.MHz());
+            assert_eq!(timing.duty(0.0), timing.min_duty);
+            assert_eq!(timing.duty(1.0), timing.max_duty);
+            assert_eq!(timing.duty(-0.5), timing.min_duty);
+            assert_eq!(timing.duty(3.0), timing.max_duty);
+            let half = timing.duty(0.5);
+            let middle = (timing.min_duty as u32 + timing.max_duty as u32) / 2;
+            assert!((half as u32).abs_diff(middle) <= 1);
+        }
+    }
+
+    #[test]
+    fn switching_protocol_moves_the_duty_window() {
+        let mut config = PwmConfig::new(MotorProtocol::StandardPwm, 125.MHz());
+        let standard = (config.duty(0.0), config.duty(1.0));
+        assert_eq!(config.set_protocol(MotorProtocol::StandardPwm), None);
+        let oneshot = config.set_protocol(MotorProtocol::Oneshot125).unwrap();
+        assert_eq!(oneshot, MotorProtocol::Oneshot125.timing(125.MHz()));
+        assert_eq!(config.protocol(), MotorProtocol::Oneshot125);
+        assert_eq!(
+            (config.duty(0.0), config.duty(1.0)),
+            (oneshot.min_duty, oneshot.max_duty)
+        );
+        assert_ne!((config.duty(0.0), config.duty(1.0)), standard);
+        // and back again
+        config.set_protocol(MotorProtocol::StandardPwm).unwrap();
+        assert_eq!((config.duty(0.0), config.duty(1.0)), standard);
+    }
+}
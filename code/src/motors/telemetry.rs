@@ -0,0 +1,149 @@
+// bidirectional DShot eRPM replies
+// the esc answers every frame with 21 bits at 5/4 of the frame bitrate:
+// a start bit then 20 bits of GCR, where every 1 is a transition on the line
+// GCR decodes to 16 bits: 12 bits of eRPM period (3 bit shift, 9 bit mantissa) and a 4 bit crc
+use defmt::Format;
+
+const INVALID: u8 = 0xFF;
+// 5 bit GCR symbol to nibble
+const GCR_DECODE: [u8; 32] = [
+    INVALID, INVALID, INVALID, INVALID, INVALID, INVALID, INVALID, INVALID, //
+    INVALID, 0x9, 0xA, 0xB, INVALID, 0xD, 0xE, 0xF, //
+    INVALID, INVALID, 0x2, 0x3, INVALID, 0x5, 0x6, 0x7, //
+    INVALID, 0x0, 0x8, 0x1, INVALID, 0x4, 0xC, INVALID, //
+];
+const STOPPED: u16 = 0x0FFF; // longest possible period, motor isnt turning
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum TelemetryError {
+    NoReply,
+    InvalidSymbol,
+    ChecksumError,
+}
+
+// raw is the 21 sampled line levels, first one (the start bit) in bit 20
+pub fn decode_reply(raw: u32) -> Result<u16, TelemetryError> {
+    let gcr = (raw ^ (raw >> 1)) & 0x000F_FFFF;
+    let mut decoded: u16 = 0;
+    for i in 0..4 {
+        let nibble = GCR_DECODE[((gcr >> (5 * i)) & 0x1F) as usize];
+        if nibble == INVALID {
+            return Err(TelemetryError::InvalidSymbol);
+        }
+        decoded |= (nibble as u16) << (4 * i);
+    }
+    let mut checksum = decoded ^ (decoded >> 8);
+    checksum ^= checksum >> 4;
+    if checksum & 0x0F != 0x0F {
+        return Err(TelemetryError::ChecksumError);
+    }
+    Ok(decoded >> 4)
+}
+
+// 12 bit value from decode_reply to electrical rpm
+pub fn value_to_erpm(value: u16) -> u32 {
+    if value == STOPPED {
+        return 0;
+    }
+    let period_us = ((value & 0x01FF) as u32) << (value >> 9);
+    // a zero period is a corrupt reply that made it past the crc
+    60_000_000u32.checked_div(period_us).unwrap_or(0)
+}
+
+// electrical rpm is mechanical rpm times the number of pole pairs
+#[inline(always)]
+pub fn erpm_to_rpm(erpm: u32, pole_count: u8) -> u32 {
+    erpm * 2 / pole_count.max(2) as u32
+}
+
+pub fn decode_rpm(raw: u32, pole_count: u8) -> Result<u32, TelemetryError> {
+    Ok(erpm_to_rpm(value_to_erpm(decode_reply(raw)?), pole_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crc(value: u16) -> u16 {
+        !(value ^ (value >> 4) ^ (value >> 8)) & 0x0F
+    }
+
+    // what an esc would put on the line for a 12 bit value, start bit low in bit 20
+    fn line_levels(value: u16) -> u32 {
+        encode_word((value << 4) | crc(value))
+    }
+
+    fn encode_word(word: u16) -> u32 {
+        let mut gcr: u32 = 0;
+        for i in 0..4 {
+            let nibble = ((word >> (4 * i)) & 0x0F) as u8;
+            let symbol = GCR_DECODE.iter().position(|n| *n == nibble).unwrap() as u32;
+            gcr |= symbol << (5 * i);
+        }
+        // every 1 flips the line
+        let mut level = 0;
+        let mut raw = 0;
+        for i in (0..20).rev() {
+            level ^= (gcr >> i) & 1;
+            raw |= level << i;
+        }
+        raw
+    }
+
+    #[test]
+    fn gcr_table_is_a_bijection() {
+        for nibble in 0..16u8 {
+            assert_eq!(GCR_DECODE.iter().filter(|n| **n == nibble).count(), 1);
+        }
+        assert_eq!(GCR_DECODE.iter().filter(|n| **n == INVALID).count(), 16);
+    }
+
+    #[test]
+    fn decodes_every_value() {
+        for value in 0..=0x0FFF {
+            assert_eq!(decode_reply(line_levels(value)), Ok(value));
+            // inverting the whole line is the same transitions
+            assert_eq!(decode_reply(!line_levels(value) & 0x001F_FFFF), Ok(value));
+        }
+    }
+
+    #[test]
+    fn catches_bad_replies() {
+        // line never moved, all zero symbols
+        assert_eq!(decode_reply(0), Err(TelemetryError::InvalidSymbol));
+        // one flipped sample breaks the symbol or the crc, never slips through
+        for value in [0x123, 0x0FFF, 0x2FA] {
+            for bit in 0..20 {
+                let raw = line_levels(value) ^ (1 << bit);
+                assert!(decode_reply(raw).is_err(), "{:#x} bit {}", value, bit);
+            }
+        }
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        // good symbols, crc off by one
+        let value: u16 = 0x123;
+        let raw = encode_word((value << 4) | (crc(value) ^ 1));
+        assert_eq!(decode_reply(raw), Err(TelemetryError::ChecksumError));
+    }
+
+    #[test]
+    fn erpm_from_the_period() {
+        // 250 << 1 = 500 us per electrical revolution
+        assert_eq!(value_to_erpm((1 << 9) | 250), 120_000);
+        assert_eq!(value_to_erpm(250), 240_000);
+        assert_eq!(value_to_erpm(STOPPED), 0);
+        assert_eq!(value_to_erpm(0), 0);
+    }
+
+    #[test]
+    fn rpm_from_the_pole_count() {
+        assert_eq!(erpm_to_rpm(120_000, 14), 17_142);
+        assert_eq!(erpm_to_rpm(120_000, 12), 20_000);
+        // nonsense pole counts count as one pair
+        assert_eq!(erpm_to_rpm(120_000, 0), 120_000);
+        assert_eq!(decode_rpm(line_levels((1 << 9) | 250), 14), Ok(17_142));
+        assert_eq!(decode_rpm(0, 14), Err(TelemetryError::InvalidSymbol));
+    }
+}
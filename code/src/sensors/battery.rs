@@ -0,0 +1,46 @@
+// battery voltage off a resistor divider into ADC0 (gpio26)
+use embedded_hal::adc::OneShot;
+use hal::adc::Adc;
+use hal::gpio::bank0::Gpio26;
+use hal::gpio::{FloatingInput, Pin};
+use hal::pac;
+use rp2040_hal as hal;
+
+const ADC_REFERENCE: f32 = 3.3;
+const ADC_MAX: f32 = 4096.0; // 12 bit
+const DIVIDER_RATIO: f32 = 11.0; // 10k over 1k
+const FILTER: f32 = 0.05; // how much of each new sample goes in, the adc is noisy and motors make it worse
+
+pub struct Battery {
+    adc: Adc,
+    pin: Pin<Gpio26, FloatingInput>,
+    voltage: f32,
+}
+
+impl Battery {
+    pub fn new(adc: pac::ADC, pin: Pin<Gpio26, FloatingInput>, resets: &mut pac::RESETS) -> Self {
+        let mut battery = Self {
+            adc: Adc::new(adc, resets),
+            pin,
+            voltage: 0.0,
+        };
+        battery.voltage = battery.sample().unwrap_or(0.0);
+        battery
+    }
+
+    fn sample(&mut self) -> Option<f32> {
+        let raw: u16 = self.adc.read(&mut self.pin).ok()?;
+        Some(raw as f32 * ADC_REFERENCE / ADC_MAX * DIVIDER_RATIO)
+    }
+
+    pub fn update(&mut self) {
+        if let Some(v) = self.sample() {
+            self.voltage += (v - self.voltage) * FILTER;
+        }
+    }
+
+    // volts, filtered
+    pub fn voltage(&self) -> f32 {
+        self.voltage
+    }
+}
@@ -0,0 +1,687 @@
+// imu offsets: gyro bias from sitting still at boot, accelerometer offset/scale from
+// resting on all six sides, magnetometer hard/soft iron from an ellipsoid fit,
+// kept in the last flash sector so it survives a power cycle
+use crate::math::functions::cartesian_to_polar_magnitude;
+use defmt::Format;
+use rp2040_hal::rom_data;
+
+const STILL_SAMPLES: u32 = 500; // readings in a row that have to agree
+const STILL_GYRO_RANGE: f32 = 3.0; // dps, peak to peak on any axis
+const STILL_ACC_RANGE: f32 = 0.05; // g, peak to peak of the magnitude
+const FACE_MIN: f32 = 0.7; // g, the axis pointing down has to read at least this
+const FACE_MAX_OTHER: f32 = 0.3; // g, and the other two at most this
+const MAX_OFFSET: f32 = 0.3; // g
+const SCALE_RANGE: (f32, f32) = (0.8, 1.2);
+const MIN_MAG_SAMPLES: u32 = 300;
+const FIT_SCALE: f64 = 0.01; // uT into the fit, keeps the sums of 4th powers sane
+const FIELD_RANGE: (f32, f32) = (20.0, 80.0); // uT, earth is 25 to 65 everywhere
+const SINGULAR: f64 = 1e-12;
+const JACOBI_SWEEPS: usize = 10;
+
+// flash layout, has to match the CALIBRATION region in memory.x
+const XIP_BASE: u32 = 0x1000_0000;
+const FLASH_SIZE: u32 = 2048 * 1024;
+const SECTOR_SIZE: u32 = 4096;
+const PAGE_SIZE: usize = 256;
+const CALIBRATION_OFFSET: u32 = FLASH_SIZE - SECTOR_SIZE; // from the start of flash
+const SECTOR_ERASE_CMD: u8 = 0x20;
+const MAGIC: u32 = 0x4C41_4349; // "ICAL"
+const VERSION: u32 = 2; // bump whenever ImuCalibration changes shape
+const FLOATS: usize = 3 + 3 + 3 + 3 + 9;
+const RECORD_LEN: usize = 4 + 4 + FLOATS * 4 + 4; // magic, version, floats, crc
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct ImuCalibration {
+    pub gyro_bias: [f32; 3],  // dps, subtracted
+    pub acc_offset: [f32; 3], // g, subtracted before scaling
+    pub acc_scale: [f32; 3],
+    pub mag_offset: [f32; 3], // uT, hard iron
+    pub mag_soft_iron: [[f32; 3]; 3],
+}
+
+impl ImuCalibration {
+    pub const fn identity() -> Self {
+        Self {
+            gyro_bias: [0.0; 3],
+            acc_offset: [0.0; 3],
+            acc_scale: [1.0; 3],
+            mag_offset: [0.0; 3],
+            mag_soft_iron: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+
+    pub fn apply_gyr(&self, gyr: [f32; 3]) -> [f32; 3] {
+        [0, 1, 2].map(|i| gyr[i] - self.gyro_bias[i])
+    }
+
+    pub fn apply_acc(&self, acc: [f32; 3]) -> [f32; 3] {
+        [0, 1, 2].map(|i| (acc[i] - self.acc_offset[i]) * self.acc_scale[i])
+    }
+
+    pub fn apply_mag(&self, mag: [f32; 3]) -> [f32; 3] {
+        let centered = [0, 1, 2].map(|i| mag[i] - self.mag_offset[i]);
+        self.mag_soft_iron
+            .map(|row| row[0] * centered[0] + row[1] * centered[1] + row[2] * centered[2])
+    }
+
+    fn floats(&self) -> [f32; FLOATS] {
+        let mut floats = [0.0; FLOATS];
+        let parts = self
+            .gyro_bias
+            .iter()
+            .chain(self.acc_offset.iter())
+            .chain(self.acc_scale.iter())
+            .chain(self.mag_offset.iter())
+            .chain(self.mag_soft_iron.iter().flatten());
+        for (to, from) in floats.iter_mut().zip(parts) {
+            *to = *from;
+        }
+        floats
+    }
+
+    fn from_floats(f: [f32; FLOATS]) -> Self {
+        Self {
+            gyro_bias: [f[0], f[1], f[2]],
+            acc_offset: [f[3], f[4], f[5]],
+            acc_scale: [f[6], f[7], f[8]],
+            mag_offset: [f[9], f[10], f[11]],
+            mag_soft_iron: [
+                [f[12], f[13], f[14]],
+                [f[15], f[16], f[17]],
+                [f[18], f[19], f[20]],
+            ],
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut bytes = [0; RECORD_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        for (i, f) in self.floats().iter().enumerate() {
+            bytes[8 + i * 4..12 + i * 4].copy_from_slice(&f.to_le_bytes());
+        }
+        let crc = crc32(&bytes[..RECORD_LEN - 4]);
+        bytes[RECORD_LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    // None for erased flash, a different version or a bad checksum
+    pub fn from_bytes(bytes: &[u8; RECORD_LEN]) -> Option<Self> {
+        let word = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        if word(0) != MAGIC
+            || word(4) != VERSION
+            || word(RECORD_LEN - 4) != crc32(&bytes[..RECORD_LEN - 4])
+        {
+            return None;
+        }
+        let mut floats = [0.0; FLOATS];
+        for (i, f) in floats.iter_mut().enumerate() {
+            *f = f32::from_bits(word(8 + i * 4));
+        }
+        Some(Self::from_floats(floats))
+    }
+}
+
+// plain crc32 (zlib), bit at a time since it only runs at boot
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// averages readings while nothing moves, any bump starts it over
+pub struct StillAverage {
+    samples: u32,
+    gyr_sum: [f32; 3],
+    acc_sum: [f32; 3],
+    gyr_min: [f32; 3],
+    gyr_max: [f32; 3],
+    acc_min: f32,
+    acc_max: f32,
+}
+
+impl StillAverage {
+    pub fn new() -> Self {
+        Self {
+            samples: 0,
+            gyr_sum: [0.0; 3],
+            acc_sum: [0.0; 3],
+            gyr_min: [f32::MAX; 3],
+            gyr_max: [f32::MIN; 3],
+            acc_min: f32::MAX,
+            acc_max: f32::MIN,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    // Some((mean gyro, mean acc)) once STILL_SAMPLES quiet readings are in, then starts over
+    pub fn push(&mut self, gyr: [f32; 3], acc: [f32; 3]) -> Option<([f32; 3], [f32; 3])> {
+        let magnitude = cartesian_to_polar_magnitude(acc);
+        for (i, g) in gyr.iter().enumerate() {
+            self.gyr_min[i] = self.gyr_min[i].min(*g);
+            self.gyr_max[i] = self.gyr_max[i].max(*g);
+        }
+        self.acc_min = self.acc_min.min(magnitude);
+        self.acc_max = self.acc_max.max(magnitude);
+        let moved = (0..3).any(|i| self.gyr_max[i] - self.gyr_min[i] > STILL_GYRO_RANGE)
+            || self.acc_max - self.acc_min > STILL_ACC_RANGE;
+        if moved {
+            self.reset();
+            return None;
+        }
+        for i in 0..3 {
+            self.gyr_sum[i] += gyr[i];
+            self.acc_sum[i] += acc[i];
+        }
+        self.samples += 1;
+        if self.samples < STILL_SAMPLES {
+            return None;
+        }
+        let n = self.samples as f32;
+        let result = (self.gyr_sum.map(|s| s / n), self.acc_sum.map(|s| s / n));
+        self.reset();
+        Some(result)
+    }
+}
+
+impl Default for StillAverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum AccelCalibrationError {
+    BadOffset(usize), // axis
+    BadScale(usize),  // axis
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum AccelCalibrationEvent {
+    Face(usize, u8), // face that just got recorded (x+ x- y+ y- z+ z-), how many are done
+    Finished(ImuCalibration),
+    Failed(AccelCalibrationError),
+}
+
+// six position accelerometer calibration, set the board on each side and hold it still
+// feed it readings with no accelerometer calibration applied
+pub struct AccelCalibration {
+    still: StillAverage,
+    faces: [Option<f32>; 6], // g along the axis pointing down (or up)
+    gyr_sum: [f32; 3],
+}
+
+impl AccelCalibration {
+    pub fn new() -> Self {
+        Self {
+            still: StillAverage::new(),
+            faces: [None; 6],
+            gyr_sum: [0.0; 3],
+        }
+    }
+
+    pub fn done(&self) -> u8 {
+        self.faces.iter().filter(|f| f.is_some()).count() as u8
+    }
+
+    pub fn push(&mut self, gyr: [f32; 3], acc: [f32; 3]) -> Option<AccelCalibrationEvent> {
+        let (gyr, acc) = self.still.push(gyr, acc)?;
+        let face = face(acc)?;
+        if self.faces[face].is_some() {
+            return None;
+        }
+        self.faces[face] = Some(acc[face / 2]);
+        for (sum, g) in self.gyr_sum.iter_mut().zip(gyr) {
+            *sum += g;
+        }
+        let done = self.done();
+        if done < 6 {
+            return Some(AccelCalibrationEvent::Face(face, done));
+        }
+        Some(match self.result() {
+            Ok(calibration) => AccelCalibrationEvent::Finished(calibration),
+            Err(e) => AccelCalibrationEvent::Failed(e),
+        })
+    }
+
+    // each axis should read +1 and -1, whatever it reads instead is offset and scale
+    fn result(&self) -> Result<ImuCalibration, AccelCalibrationError> {
+        let mut calibration = ImuCalibration {
+            gyro_bias: self.gyr_sum.map(|s| s / 6.0),
+            ..ImuCalibration::identity()
+        };
+        for axis in 0..3 {
+            let up = self.faces[axis * 2].unwrap_or(1.0);
+            let down = self.faces[axis * 2 + 1].unwrap_or(-1.0);
+            let offset = (up + down) / 2.0;
+            let scale = 2.0 / (up - down);
+            if offset.abs() > MAX_OFFSET {
+                return Err(AccelCalibrationError::BadOffset(axis));
+            }
+            if !(SCALE_RANGE.0..=SCALE_RANGE.1).contains(&scale) {
+                return Err(AccelCalibrationError::BadScale(axis));
+            }
+            calibration.acc_offset[axis] = offset;
+            calibration.acc_scale[axis] = scale;
+        }
+        Ok(calibration)
+    }
+}
+
+impl Default for AccelCalibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// which side the board is sitting on, None if its tilted
+fn face(acc: [f32; 3]) -> Option<usize> {
+    let axis = (0..3).max_by(|a, b| acc[*a].abs().total_cmp(&acc[*b].abs()))?;
+    let others_flat = (0..3)
+        .filter(|i| *i != axis)
+        .all(|i| acc[i].abs() < FACE_MAX_OTHER);
+    if acc[axis].abs() < FACE_MIN || !others_flat {
+        return None;
+    }
+    Some(axis * 2 + (acc[axis] < 0.0) as usize)
+}
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub enum MagCalibrationError {
+    NotEnoughSamples,
+    BadFit,        // didnt get turned enough to see the whole ellipsoid
+    BadField(f32), // uT, something magnetic is too close
+}
+
+// least squares fit of a x^2 + b y^2 + c z^2 + 2f yz + 2g xz + 2h xy + 2p x + 2q y + 2r z = 1
+// to everything the magnetometer saw while being turned every which way, only the
+// normal equations are kept so it doesnt need to remember the samples
+pub struct EllipsoidFit {
+    ata: [[f64; 9]; 9],
+    atb: [f64; 9],
+    samples: u32,
+}
+
+impl EllipsoidFit {
+    pub fn new() -> Self {
+        Self {
+            ata: [[0.0; 9]; 9],
+            atb: [0.0; 9],
+            samples: 0,
+        }
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    // uT with no magnetometer calibration applied
+    pub fn push(&mut self, mag: [f32; 3]) {
+        let [x, y, z] = mag.map(|m| m as f64 * FIT_SCALE);
+        let row = [
+            x * x,
+            y * y,
+            z * z,
+            2.0 * y * z,
+            2.0 * x * z,
+            2.0 * x * y,
+            2.0 * x,
+            2.0 * y,
+            2.0 * z,
+        ];
+        for i in 0..9 {
+            for j in 0..9 {
+                self.ata[i][j] += row[i] * row[j];
+            }
+            self.atb[i] += row[i];
+        }
+        self.samples += 1;
+    }
+
+    // hard iron offset and the soft iron matrix that turns the ellipsoid back into a
+    // sphere the size of the local field
+    pub fn result(&self) -> Result<([f32; 3], [[f32; 3]; 3]), MagCalibrationError> {
+        if self.samples < MIN_MAG_SAMPLES {
+            return Err(MagCalibrationError::NotEnoughSamples);
+        }
+        let [a, b, c, f, g, h, p, q, r] =
+            solve(self.ata, self.atb).ok_or(MagCalibrationError::BadFit)?;
+        let m = [[a, h, g], [h, b, f], [g, f, c]];
+        let m_inv = inverse3(m).ok_or(MagCalibrationError::BadFit)?;
+        let center = [0, 1, 2].map(|i| -(m_inv[i][0] * p + m_inv[i][1] * q + m_inv[i][2] * r));
+        // (x - center)' m (x - center) = k, k comes out negative if the origin is outside
+        let k = 1.0
+            + (0..3)
+                .map(|i| (0..3).map(|j| center[i] * m[i][j] * center[j]).sum::<f64>())
+                .sum::<f64>();
+        if k.abs() < SINGULAR {
+            return Err(MagCalibrationError::BadFit);
+        }
+        let (values, vectors) = symmetric_eigen(m.map(|row| row.map(|v| v / k)));
+        if values.iter().any(|v| *v <= 0.0) {
+            return Err(MagCalibrationError::BadFit);
+        }
+        // geometric mean of the semi axes, so the correction doesnt change the field strength
+        let radius = libm::pow(values[0] * values[1] * values[2], -1.0 / 6.0);
+        let field = (radius / FIT_SCALE) as f32;
+        if !(FIELD_RANGE.0..=FIELD_RANGE.1).contains(&field) {
+            return Err(MagCalibrationError::BadField(field));
+        }
+        // v sqrt(values) v' squashes the ellipsoid to a unit sphere
+        let mut soft_iron = [[0.0; 3]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                soft_iron[i][j] = (0..3)
+                    .map(|n| vectors[i][n] * libm::sqrt(values[n]) * vectors[j][n])
+                    .sum::<f64>() as f32
+                    * radius as f32;
+            }
+        }
+        Ok((center.map(|c| (c / FIT_SCALE) as f32), soft_iron))
+    }
+}
+
+impl Default for EllipsoidFit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// gaussian elimination with partial pivoting, None if its singular
+fn solve<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    for col in 0..N {
+        let pivot = (col..N).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))?;
+        if a[pivot][col].abs() < SINGULAR {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..N {
+            let factor = a[row][col] / a[col][col];
+            let pivot_row = a[col];
+            for (x, p) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *x -= factor * p;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+    let mut x = [0.0; N];
+    for row in (0..N).rev() {
+        let rest: f64 = (row + 1..N).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - rest) / a[row][row];
+    }
+    Some(x)
+}
+
+fn inverse3(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum::<f64>();
+    if det.abs() < SINGULAR {
+        return None;
+    }
+    // transpose of the cofactors
+    Some([0, 1, 2].map(|r| [0, 1, 2].map(|c| cofactor(c, r) / det)))
+}
+
+// jacobi rotations, eigenvalues and eigenvectors (as columns) of a symmetric 3x3
+fn symmetric_eigen(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for _ in 0..JACOBI_SWEEPS {
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < SINGULAR {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + libm::sqrt(theta * theta + 1.0));
+            let c = 1.0 / libm::sqrt(t * t + 1.0);
+            let s = t * c;
+            for row in &mut a {
+                let (kp, kq) = (row[p], row[q]);
+                row[p] = c * kp - s * kq;
+                row[q] = s * kp + c * kq;
+            }
+            let (row_p, row_q) = (a[p], a[q]);
+            a[p] = core::array::from_fn(|k| c * row_p[k] - s * row_q[k]);
+            a[q] = core::array::from_fn(|k| s * row_p[k] + c * row_q[k]);
+            for row in &mut v {
+                let (kp, kq) = (row[p], row[q]);
+                row[p] = c * kp - s * kq;
+                row[q] = s * kp + c * kq;
+            }
+        }
+    }
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+pub fn load() -> Option<ImuCalibration> {
+    let record = (XIP_BASE + CALIBRATION_OFFSET) as *const [u8; RECORD_LEN];
+    ImuCalibration::from_bytes(&unsafe { core::ptr::read_volatile(record) })
+}
+
+// rom flash routines, looked up while flash can still be read
+struct FlashRom {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+// boot2 sets up fast xip, a copy in ram gets it back after writing
+static mut BOOT2_COPY: [u32; 64] = [0; 64];
+
+// nothing can run from flash while this is going, so only call it with the other core not started
+pub fn save(calibration: &ImuCalibration) {
+    let mut page = [0xFF; PAGE_SIZE];
+    page[..RECORD_LEN].copy_from_slice(&calibration.to_bytes());
+    let rom = FlashRom {
+        connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+        flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+        flash_range_erase: rom_data::flash_range_erase::ptr(),
+        flash_range_program: rom_data::flash_range_program::ptr(),
+        flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+    };
+    cortex_m::interrupt::free(|_| unsafe {
+        BOOT2_COPY = core::ptr::read_volatile(XIP_BASE as *const [u32; 64]);
+        write_sector(&rom, &page);
+    });
+}
+
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write_sector(rom: &FlashRom, page: &[u8; PAGE_SIZE]) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_erase)(
+        CALIBRATION_OFFSET,
+        SECTOR_SIZE as usize,
+        SECTOR_SIZE,
+        SECTOR_ERASE_CMD,
+    );
+    (rom.flash_range_program)(CALIBRATION_OFFSET, page.as_ptr(), PAGE_SIZE);
+    (rom.flash_flush_cache)();
+    // thumb bit set
+    let boot2: extern "C" fn() = core::mem::transmute(core::ptr::addr_of!(BOOT2_COPY) as usize + 1);
+    boot2();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mul(a: [[f64; 3]; 3], b: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+        [0, 1, 2].map(|i| [0, 1, 2].map(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+    }
+
+    fn transpose(a: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+        [0, 1, 2].map(|i| [0, 1, 2].map(|j| a[j][i]))
+    }
+
+    fn assert_close(a: [[f64; 3]; 3], b: [[f64; 3]; 3], tolerance: f64) {
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!((a[i][j] - b[i][j]).abs() < tolerance, "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    const IDENTITY: [[f64; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    // rotation about some axis that isnt lined up with anything
+    fn rotation(angle: f64) -> [[f64; 3]; 3] {
+        let n = 1.0 / 14f64.sqrt();
+        let [x, y, z] = [1.0 * n, 2.0 * n, 3.0 * n];
+        let (s, c) = angle.to_radians().sin_cos();
+        let t = 1.0 - c;
+        [
+            [t * x * x + c, t * x * y - s * z, t * x * z + s * y],
+            [t * x * y + s * z, t * y * y + c, t * y * z - s * x],
+            [t * x * z - s * y, t * y * z + s * x, t * z * z + c],
+        ]
+    }
+
+    // evenly spread unit vectors, what turning the board every which way looks like
+    fn sphere(n: usize) -> Vec<[f64; 3]> {
+        let golden = std::f64::consts::PI * (3.0 - 5f64.sqrt());
+        (0..n)
+            .map(|i| {
+                let z = 1.0 - 2.0 * (i as f64 + 0.5) / n as f64;
+                let r = (1.0 - z * z).sqrt();
+                let (s, c) = (golden * i as f64).sin_cos();
+                [r * c, r * s, z]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn solves_a_known_system() {
+        let a = [[2.0, 1.0, -1.0], [-3.0, -1.0, 2.0], [-2.0, 1.0, 2.0]];
+        let x = solve(a, [8.0, -11.0, -3.0]).unwrap();
+        for (got, expected) in x.iter().zip([2.0, 3.0, -1.0]) {
+            assert!((got - expected).abs() < 1e-12);
+        }
+        // needs the pivoting, the first pivot is zero
+        let x = solve([[0.0, 1.0], [1.0, 0.0]], [3.0, 4.0]).unwrap();
+        assert_eq!(x, [4.0, 3.0]);
+        // third row is the sum of the first two
+        let singular = [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0], [5.0, 7.0, 9.0]];
+        assert!(solve(singular, [1.0, 2.0, 3.0]).is_none());
+    }
+
+    #[test]
+    fn inverse() {
+        let m = [[4.0, 1.0, 2.0], [1.0, 3.0, 0.5], [2.0, 0.5, 5.0]];
+        assert_close(mul(m, inverse3(m).unwrap()), IDENTITY, 1e-12);
+        let rotated = rotation(40.0);
+        assert_close(inverse3(rotated).unwrap(), transpose(rotated), 1e-12);
+        assert!(inverse3([[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 1.0, 1.0]]).is_none());
+    }
+
+    #[test]
+    fn eigen() {
+        let a = [[4.0, 1.0, 2.0], [1.0, 3.0, 0.5], [2.0, 0.5, 5.0]];
+        let (values, v) = symmetric_eigen(a);
+        // a v = lambda v for every column
+        for n in 0..3 {
+            for i in 0..3 {
+                let av: f64 = (0..3).map(|k| a[i][k] * v[k][n]).sum();
+                assert!((av - values[n] * v[i][n]).abs() < 1e-9);
+            }
+        }
+        assert_close(mul(transpose(v), v), IDENTITY, 1e-12);
+        assert!((values.iter().sum::<f64>() - 12.0).abs() < 1e-9);
+        // already diagonal comes straight back
+        let (values, v) = symmetric_eigen([[2.0, 0.0, 0.0], [0.0, 7.0, 0.0], [0.0, 0.0, 1.0]]);
+        assert_eq!(values, [2.0, 7.0, 1.0]);
+        assert_eq!(v, IDENTITY);
+    }
+
+    #[test]
+    fn fits_a_rotated_offset_ellipsoid() {
+        const FIELD: f64 = 48.0; // uT
+        let offset = [25.0, -40.0, 12.0];
+        // symmetric distortion, stretched 1.25 along one axis and squashed 0.8 along another
+        let r = rotation(35.0);
+        let stretch = [[1.25, 0.0, 0.0], [0.0, 0.8, 0.0], [0.0, 0.0, 1.05]];
+        let distortion = mul(mul(r, stretch), transpose(r));
+        let distort = |u: &[f64; 3]| {
+            [0, 1, 2].map(|i| {
+                let d: f64 = (0..3).map(|k| distortion[i][k] * u[k]).sum();
+                (d * FIELD + offset[i]) as f32
+            })
+        };
+        let mut fit = EllipsoidFit::new();
+        let truth = sphere(600);
+        for u in &truth {
+            fit.push(distort(u));
+        }
+        assert_eq!(fit.samples(), 600);
+        let (mag_offset, mag_soft_iron) = fit.result().unwrap();
+        for i in 0..3 {
+            assert!(
+                (mag_offset[i] as f64 - offset[i]).abs() < 0.05,
+                "{:?}",
+                mag_offset
+            );
+        }
+        // comes back as the original sphere, scaled to keep the volume
+        let radius = FIELD * (1.25 * 0.8 * 1.05f64).cbrt();
+        let calibration = ImuCalibration {
+            mag_offset,
+            mag_soft_iron,
+            ..ImuCalibration::identity()
+        };
+        for u in &truth {
+            let corrected = calibration.apply_mag(distort(u));
+            for i in 0..3 {
+                assert!((corrected[i] as f64 - radius * u[i]).abs() < 0.05);
+            }
+        }
+    }
+
+    #[test]
+    fn fit_errors() {
+        let mut fit = EllipsoidFit::new();
+        for u in sphere(MIN_MAG_SAMPLES as usize - 1) {
+            fit.push(u.map(|v| (v * 50.0) as f32));
+        }
+        assert_eq!(fit.result(), Err(MagCalibrationError::NotEnoughSamples));
+        fit.push([50.0, 0.0, 0.0]);
+        assert!(fit.result().is_ok());
+
+        // a fridge magnet next to it
+        let mut fit = EllipsoidFit::new();
+        for u in sphere(400) {
+            fit.push(u.map(|v| (v * 150.0) as f32));
+        }
+        assert!(
+            matches!(fit.result(), Err(MagCalibrationError::BadField(f)) if (f - 150.0).abs() < 0.5)
+        );
+
+        // only ever spun flat on the bench
+        let mut fit = EllipsoidFit::new();
+        for n in 0..400 {
+            let (s, c) = (n as f32).to_radians().sin_cos();
+            fit.push([30.0 * c, 30.0 * s, -35.0]);
+        }
+        assert_eq!(fit.result(), Err(MagCalibrationError::BadFit));
+    }
+}
@@ -0,0 +1,82 @@
+// ICM-20948 INT1 on gpio21, pulses once per accel/gyro sample (RAW_DATA_0_RDY)
+// the interrupt only notes when the sample happened, the read itself stays in core1's loop
+use crate::sync::clock;
+use core::cell::{Cell, RefCell};
+use critical_section::Mutex;
+use defmt::Format;
+use hal::gpio::bank0::Gpio21;
+use hal::gpio::{Interrupt, Pin, PullDownInput};
+use hal::pac::{self, interrupt};
+use rp2040_hal as hal;
+
+#[derive(Clone, Copy, Debug, Format, PartialEq)]
+pub struct DataReady {
+    pub timestamp: u64, // timer ticks at the newest edge
+    pub count: u32,     // edges since the last take, more than 1 means the loop fell behind
+}
+
+static DATA_READY_PIN: Mutex<RefCell<Option<Pin<Gpio21, PullDownInput>>>> =
+    Mutex::new(RefCell::new(None));
+static DATA_READY: Mutex<Cell<DataReady>> = Mutex::new(Cell::new(DataReady {
+    timestamp: 0,
+    count: 0,
+}));
+
+#[interrupt]
+fn IO_IRQ_BANK0() {
+    critical_section::with(|cs| {
+        if let Some(pin) = DATA_READY_PIN.borrow_ref_mut(cs).as_mut() {
+            if pin.interrupt_status(Interrupt::EdgeHigh) {
+                let now = clock::now();
+                pin.clear_interrupt(Interrupt::EdgeHigh);
+                let latest = DATA_READY.borrow(cs);
+                latest.set(DataReady {
+                    timestamp: now,
+                    count: latest.get().count.saturating_add(1),
+                });
+            }
+        }
+    });
+}
+
+pub struct ImuInterrupt {}
+
+impl ImuInterrupt {
+    // the pin interrupt stays off until listen, new runs on core0
+    pub fn new(pin: Pin<Gpio21, PullDownInput>) -> Self {
+        critical_section::with(|cs| {
+            DATA_READY_PIN.borrow(cs).replace(Some(pin));
+        });
+        Self {}
+    }
+
+    // call this from the core that reads the imu. INTE and INTS are per core and the hal
+    // picks them by whichever core is running, so enabling it on core0 would leave core1's
+    // IO_IRQ_BANK0 silent and the handler reading core1's status that never gets set
+    pub fn listen(&self) {
+        critical_section::with(|cs| {
+            if let Some(pin) = DATA_READY_PIN.borrow_ref_mut(cs).as_mut() {
+                pin.clear_interrupt(Interrupt::EdgeHigh);
+                pin.set_interrupt_enabled(Interrupt::EdgeHigh, true);
+            }
+        });
+        unsafe {
+            pac::NVIC::unmask(pac::Interrupt::IO_IRQ_BANK0);
+        }
+    }
+
+    // None if nothing new came in since last time
+    pub fn take(&mut self) -> Option<DataReady> {
+        let ready = critical_section::with(|cs| {
+            let latest = DATA_READY.borrow(cs);
+            let ready = latest.get();
+            latest.set(DataReady { count: 0, ..ready });
+            ready
+        });
+        if ready.count == 0 {
+            None
+        } else {
+            Some(ready)
+        }
+    }
+}
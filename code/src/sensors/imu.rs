@@ -1,41 +1,73 @@
+// the full register maps live here, not everything in them is used yet
+#![allow(dead_code)]
 use defmt::*;
-use defmt_rtt as _;
-use embedded_hal::prelude::_embedded_hal_blocking_i2c_Write;
-use embedded_hal::prelude::_embedded_hal_blocking_i2c_WriteRead;
-use fugit::RateExtU32;
-use hal::gpio::bank0::{Gpio24, Gpio25, Gpio4, Gpio5};
-use hal::gpio::Function;
-use hal::gpio::Pin;
-use hal::i2c::Error as I2CError;
-use hal::pac::I2C0;
-use hal::{i2c::I2C, pac};
-use panic_probe as _;
-
-use rp2040_hal as hal;
-
-pub trait Accelerometer {
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+use heapless::Vec;
+
+use super::calibration::ImuCalibration;
+use super::transport::{BusErrorInfo, RegisterBus};
+
+// what every reading can fail with, the error comes from whichever bus the driver sits on
+pub trait Imu {
+    type BusError: BusErrorInfo;
+}
+
+pub trait Accelerometer: Imu {
     fn get_acc(&self) -> [f32; 3];
-    fn update_raw_acc(&mut self) -> Result<(), IMUError>;
+    fn update_raw_acc(&mut self) -> Result<(), IMUError<Self::BusError>>;
 }
 
-pub trait Gyroscope {
+pub trait Gyroscope: Imu {
     fn get_gyr(&self) -> [f32; 3];
-    fn update_raw_gyr(&mut self) -> Result<(), IMUError>;
+    fn update_raw_gyr(&mut self) -> Result<(), IMUError<Self::BusError>>;
 }
 
-pub trait TemperatureSensor {
+pub trait TemperatureSensor: Imu {
     fn get_temp_c(&self) -> f32;
-    fn update_raw_temp(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<(), IMUError>;
+    fn update_raw_temp<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), IMUError<Self::BusError>>;
 }
 
-pub trait Magnetometer {
+pub trait Magnetometer: Imu {
     fn get_mag(&self) -> [f32; 3];
-    fn update_raw_mag(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<(), IMUError>;
+    fn update_raw_mag<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), IMUError<Self::BusError>>;
 }
 
-pub trait Sensor {
-    fn update_all(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<(), IMUError>;
+pub trait Sensor: Imu {
+    fn update_all<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), IMUError<Self::BusError>>;
+}
+// everything the flight system needs out of an imu, batches of samples off its fifo, somewhere
+// to keep the calibration and the compass if it has one
+pub trait FlightImu: Accelerometer + Gyroscope + Sensor {
+    fn init<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), IMUError<Self::BusError>>;
+    fn calibration(&self) -> ImuCalibration;
+    fn set_calibration(&mut self, calibration: ImuCalibration);
+    fn enable_fifo(&mut self, temperature: bool) -> Result<(), IMUError<Self::BusError>>;
+    fn read_fifo(
+        &mut self,
+        newest: u64,
+        samples: &mut Vec<ImuSample, FIFO_MAX_SAMPLES>,
+    ) -> Result<(), IMUError<Self::BusError>>;
+    // time between samples with the dividers init sets up, the data ready interrupt comes this often
+    fn sample_period_us(&self) -> f32;
+    // uT, None if there is no magnetometer
+    fn read_mag<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Option<Result<[f32; 3], IMUError<Self::BusError>>>;
 }
+
 #[derive(Debug)]
 pub enum IMUHardwareType {
     Gyro,
@@ -46,19 +78,21 @@ pub enum IMUHardwareType {
     Unknown,
 }
 
+// E is the bus error, see RegisterBus::Error
 #[derive(Debug)]
-pub enum IMUError {
-    ReadError(IMUHardwareType, u8, I2CError),
-    WriteError(IMUHardwareType, u8, u8, I2CError),
+pub enum IMUError<E> {
+    ReadError(IMUHardwareType, u8, E),
+    WriteError(IMUHardwareType, u8, u8, E),
     SetupError(&'static str),
     SyntaxError(&'static str, u8),
+    FifoOverflow, // samples were lost, the fifo has been reset
 }
 
-impl IMUError {
+impl<E: BusErrorInfo> IMUError<E> {
     pub fn info(self) {
         match self {
             IMUError::ReadError(typ, reg, err) => {
-                IMUError::i2c_error_info(err);
+                err.info();
                 match typ {
                     IMUHardwareType::Gyro => info!("failed to read gyro data"),
                     IMUHardwareType::Accelerometer => {
@@ -71,7 +105,7 @@ impl IMUError {
                 };
             }
             IMUError::WriteError(typ, data, reg, err) => {
-                IMUError::i2c_error_info(err);
+                err.info();
                 match typ {
                     IMUHardwareType::Memory => {
                         info!("failed to switch from bank {0} to {1}", data, reg)
@@ -81,46 +115,14 @@ impl IMUError {
             }
             IMUError::SyntaxError(msg, arg) => info!("{0} for input {1}", msg, arg),
             IMUError::SetupError(msg) => info!("{}", msg),
-        }
-    }
-    fn i2c_error_info(err: I2CError) {
-        match err {
-            I2CError::Abort(v) => {
-                if v & 1 << 12 != 0 {
-                    info!("ArbitrationLoss")
-                } else if v & 1 << 7 != 0 {
-                    info!("ABRT_SBYTE_ACKDET")
-                }
-                // ha::i2c::ErrorKind::Bus,
-                // if v & 1<<6 != 0 // ABRT_HS_ACKDET
-                // ha::i2c::ErrorKind::Bus,
-                // if v & 1<<4 != 0 // ABRT_GCALL_NOACK
-                // ha::i2c::ErrorKind::NoAcknowledge(eh1_0_alpha::i2c::NoAcknowledgeSource::Address),
-                // if v & 1<<3 != 0 // ABRT_TXDATA_NOACK
-                // ha::i2c::ErrorKind::NoAcknowledge(eh1_0_alpha::i2c::NoAcknowledgeSource::Data),
-                // if v & 1<<2 != 0 // ABRT_10ADDR2_NOACK
-                // ha::i2c::ErrorKind::NoAcknowledge(eh1_0_alpha::i2c::NoAcknowledgeSource::Address),
-                // if v & 1<<1 != 0 // ABRT_10ADDR1_NOACK
-                // ha::i2c::ErrorKind::NoAcknowledge(eh1_0_alpha::i2c::NoAcknowledgeSource::Address),
-                else if v & 1 << 0 != 0 {
-                    // ABRT_7B_ADDR_NOACK
-                    info!("no acknowledge from device")
-                } else {
-                    info!("Other abort")
-                };
-            }
-            I2CError::AddressReserved(addr) => info!("address {0} out of range", addr),
-            I2CError::AddressOutOfRange(addr) => info!("address {0} out of range", addr),
-            I2CError::InvalidReadBufferLength => info!("Invalid read buffer length"),
-            I2CError::InvalidWriteBufferLength => info!("Invalid write buffer length"),
-            _ => info!("other error"),
+            IMUError::FifoOverflow => info!("imu fifo overflowed, samples lost"),
         }
     }
 }
 
 const CHIP_ID: u8 = 0xEA;
-const IMU_ADDR: u8 = 0x68;
-const I2C_ADDR_ALT: u8 = 0x69;
+pub const IMU_ADDR: u8 = 0x68;
+pub const I2C_ADDR_ALT: u8 = 0x69; // AD0 pulled high
 const BANK_SEL: u8 = 0x7f;
 
 const I2C_MST_ODR_CONFIG: u8 = 0x00;
@@ -140,6 +142,25 @@ const USER_CTRL: u8 = 0x03;
 const PWR_MGMT_1: u8 = 0x06;
 const PWR_MGMT_2: u8 = 0x07;
 const INT_PIN_CFG: u8 = 0x0F;
+const INT_ENABLE_1: u8 = 0x11;
+const INT_STATUS_2: u8 = 0x1B;
+
+const FIFO_EN_1: u8 = 0x66;
+const FIFO_EN_2: u8 = 0x67;
+const FIFO_RST: u8 = 0x68;
+const FIFO_MODE: u8 = 0x69;
+const FIFO_COUNTH: u8 = 0x70;
+const FIFO_R_W: u8 = 0x72;
+const USER_CTRL_FIFO_EN: u8 = 0x40;
+const FIFO_EN_ACCEL_GYRO: u8 = 0b0001_1110;
+const FIFO_EN_TEMP: u8 = 0b0000_0001;
+const FIFO_OVERFLOW: u8 = 0b0001_1111; // any of the fifo overflow bits in INT_STATUS_2
+const FIFO_SIZE: usize = 512;
+const FIFO_PACKET: usize = 12; // accel then gyro, both big endian, then 2 bytes of temperature if its on
+pub const FIFO_MAX_SAMPLES: usize = FIFO_SIZE / FIFO_PACKET;
+// accel and gyro share a divider so every fifo packet has one of each, f = 1.125 kHz / (1 + div)
+const SAMPLE_DIV: u8 = 0;
+const SAMPLE_PERIOD: f32 = 1_000_000.0 * (1 + SAMPLE_DIV as u32) as f32 / 1125.0; // timer ticks
 
 const ACC_SMPLRT_DIV_1: u8 = 0x10;
 const ACC_SMPLRT_DIV_2: u8 = 0x11;
@@ -173,7 +194,12 @@ const MAG_CNTL2_MODE_CONT3: u8 = 6;
 const MAG_CNTL2_MODE_CONT4: u8 = 8;
 const MAG_CNTL2_MODE_TEST: u8 = 16;
 const MAG_CNTL3: u8 = 0x32;
+const MAG_MODE: u8 = MAG_CNTL2_MODE_CONT4; // 100 Hz
+const MAG_READ_LEN: u8 = 9; // st1, 6 data bytes, a dummy, st2. reading st2 unlocks the next sample
+const MAG_ODR_CONFIG: u8 = 0x03; // slave reads at 1.1 kHz / 2^3, a bit faster than the mag makes data
+const MAG_SENSITIVITY: f32 = 0.15; // uT per count
 
+#[allow(non_camel_case_types)]
 pub enum AccelerometerSetting {
     r2g,
     r4g,
@@ -190,8 +216,19 @@ impl AccelerometerSetting {
             Self::r16g => 16.0 / 32768.0,
         }
     }
+
+    // same 2 bit full scale select on the icm and the mpu, just shifted differently
+    pub fn full_scale_bits(&self) -> u8 {
+        match self {
+            Self::r2g => 0,
+            Self::r4g => 1,
+            Self::r8g => 2,
+            Self::r16g => 3,
+        }
+    }
 }
 
+#[allow(non_camel_case_types)]
 pub enum GyroSetting {
     r250dps,
     r500dps,
@@ -208,117 +245,75 @@ impl GyroSetting {
             Self::r2000dps => 2000.0 / 32768.0,
         }
     }
+
+    pub fn full_scale_bits(&self) -> u8 {
+        match self {
+            Self::r250dps => 0,
+            Self::r500dps => 1,
+            Self::r1000dps => 2,
+            Self::r2000dps => 3,
+        }
+    }
 }
 
-pub struct ICM_20948 {
+// one accel and gyro reading out of the fifo, timestamp in timer ticks
+#[derive(Clone, Copy, Debug, Format)]
+pub struct ImuSample {
+    pub acc: [f32; 3],
+    pub gyr: [f32; 3],
+    pub timestamp: u64,
+}
+
+#[allow(non_camel_case_types)]
+pub struct ICM_20948<B> {
     accelerometer_range: AccelerometerSetting,
     gyro_range: GyroSetting,
     bank: u8,
     raw_acc: [u8; 6],
-    last_acc: u64,
     raw_gyr: [u8; 6],
-    last_gyr: u64,
     raw_temp: [u8; 2],
     raw_mag: [u8; 6],
-    i2c: I2C<
-        I2C0,
-        (
-            Pin<Gpio24, Function<hal::gpio::I2C>>,
-            Pin<Gpio25, Function<hal::gpio::I2C>>,
-        ),
-    >,
+    fifo_packet: usize, // 0 while the fifo is off
+    calibration: ImuCalibration,
+    bus: B,
 }
 
-impl ICM_20948 {
+impl<B: RegisterBus> ICM_20948<B> {
+    // I2cBus::new(i2c, IMU_ADDR) or SpiBus for the fast way, main.rs builds it
     pub fn new(
         accelerometer_range: AccelerometerSetting,
         gyro_range: GyroSetting,
-        i2c0: pac::I2C0,
-        sda_pin: Pin<Gpio24, Function<hal::gpio::I2C>>,
-        scl_pin: Pin<Gpio25, Function<hal::gpio::I2C>>,
-        resets: &mut pac::RESETS,
+        mut bus: B,
     ) -> Self {
-        let mut i2c = I2C::i2c0(i2c0, sda_pin, scl_pin, 400.kHz(), resets, 125_000_000.Hz());
         let mut firstbank: [u8; 1] = [69; 1];
-        match i2c.write_read(IMU_ADDR, &[BANK_SEL], &mut firstbank) {
+        match bus.read_registers(BANK_SEL, &mut firstbank) {
             Ok(()) => (),
-            Err(e) => info!("no read imu"),
+            Err(_) => info!("no read imu"),
         };
         Self {
             accelerometer_range,
             gyro_range,
             bank: firstbank[0] << 2 >> 6,
             raw_acc: [0; 6],
-            last_acc: 0,
             raw_gyr: [0; 6],
-            last_gyr: 0,
             raw_temp: [0; 2],
             raw_mag: [0; 6],
-            i2c,
+            fifo_packet: 0,
+            calibration: ImuCalibration::identity(),
+            bus,
         }
     }
 
-    pub fn init(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<(), IMUError> {
-        delay.delay_ms(100);
-        self.switch_bank(0)?;
-        self.imu_write(PWR_MGMT_1, 0x80)?; // reset
-        delay.delay_ms(10);
-        if self.imu_read(WHO_AM_I)? != CHIP_ID {
-            return Err(IMUError::SetupError("IMU not found!!!"));
-        }
-
-        self.imu_write(PWR_MGMT_1, 0x01)?; // select best clock
-        self.imu_write(PWR_MGMT_2, 0x00)?; // enable gyro and accelerometer
-
-        self.switch_bank(2)?;
-        // chatgpt said these were good values for NBW
-        self.imu_write(GYRO_SR8_DIV, 0x00)?;
-        // f = 1.125 kHz / (1 + div as u8)
-        self.imu_write(GYRO_CONFIG_1, 0b00_111_00_1 | (match &self.gyro_range {
-            GyroSetting::r250dps => 0,
-            GyroSetting::r500dps => 1,
-            GyroSetting::r1000dps => 2,
-            GyroSetting::r2000dps => 3,
-        } << 1))?;
-        // 7:6 resv
-        // 5:3 look at table for NBW, 111 = 375Hz NBW
-        // 2:1 00 = +-250, 01 = 500, 10 = 1000, 11 = 2000
-        // 0   1 = enable low pass
-
-        self.imu_write(ACC_SMPLRT_DIV_1, 0x00)?; // msb
-        self.imu_write(ACC_SMPLRT_DIV_2, 0x00)?;
-        // f = 1.125 kHz / (1 + div as u16)
-        self.imu_write(ACC_CONFIG, 0b00_011_00_1 | (match &self.accelerometer_range {
-            AccelerometerSetting::r2g => 0,
-            AccelerometerSetting::r4g => 1,
-            AccelerometerSetting::r8g => 2,
-            AccelerometerSetting::r16g => 3,
-        } << 1))?;
-        // 7:6 resv
-        // 5:3 look at table for NBW, 011 = 69Hz NBW
-        // 2:1 00 = +-2g, 01 = 4, 10 = 8, 11 = 16
-        // 0   1 = enable low pass
-
+    fn reset_fifo(&mut self) -> Result<(), IMUError<B::Error>> {
         self.switch_bank(0)?;
-        self.imu_write(INT_PIN_CFG, 0x30)?;
-
-        self.switch_bank(3)?;
-        self.imu_write(I2C_MST_CTRL, 0x4D)?; // maybe make 0b1001111
-        self.imu_write(I2C_MST_DELAY_CTRL, 0x01)?; // maybe make 0
-
-        if self.mag_read(MAG_WIA, delay)? != MAG_CHIP_ID {
-            return Err(IMUError::SetupError("Unable to find magnetometer"));
-        }
-
-        self.mag_write(MAG_CNTL3, 0x01, delay)?; // mag reset
-        while self.mag_read(MAG_CNTL3, delay)? == 0x01 {
-            delay.delay_us(100);
-        }
+        self.imu_write(FIFO_RST, 0x1F)?;
+        self.imu_write(FIFO_RST, 0x00)?;
+        self.imu_read(INT_STATUS_2)?; // clears a stale overflow
         Ok(())
     }
 
     #[inline(always)]
-    fn switch_bank(&mut self, bank: u8) -> Result<(), IMUError> {
+    fn switch_bank(&mut self, bank: u8) -> Result<(), IMUError<B::Error>> {
         if bank > 3 {
             return Err(IMUError::SyntaxError("invalid bank", bank));
         }
@@ -343,8 +338,8 @@ impl ICM_20948 {
     }
 
     #[inline(always)]
-    fn imu_write(&mut self, register: u8, data: u8) -> Result<(), IMUError> {
-        let result = match self.i2c.write(IMU_ADDR, &[register, data]) {
+    fn imu_write(&mut self, register: u8, data: u8) -> Result<(), IMUError<B::Error>> {
+        match self.bus.write_register(register, data) {
             Ok(()) => Ok(()),
             Err(e) => Err(IMUError::WriteError(
                 IMUHardwareType::Unknown,
@@ -352,46 +347,51 @@ impl ICM_20948 {
                 register,
                 e,
             )),
-        };
-        result
+        }
     }
 
     #[inline(always)]
-    fn imu_read(&mut self, register: u8) -> Result<u8, IMUError> {
+    fn imu_read(&mut self, register: u8) -> Result<u8, IMUError<B::Error>> {
         let mut buf: [u8; 1] = [0; 1];
-        match self.i2c.write_read(IMU_ADDR, &[register], &mut buf) {
+        match self.bus.read_registers(register, &mut buf) {
             Ok(()) => Ok(buf[0]),
             Err(e) => Err(IMUError::ReadError(IMUHardwareType::Unknown, register, e)),
         }
     }
 
-    fn mag_ready(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<bool, IMUError> {
+    fn mag_ready<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<bool, IMUError<B::Error>> {
         Ok(self.mag_read(MAG_ST1, delay)? & 0x01 > 0)
     }
-    fn trigger_mag_io(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<(), IMUError> {
+    fn trigger_mag_io<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
         let user = self.imu_read(USER_CTRL)?;
         self.imu_write(USER_CTRL, user | 0x20)?;
         delay.delay_ms(5);
         self.imu_write(USER_CTRL, user)
     }
-    fn mag_write(
+    fn mag_write<D: DelayMs<u32> + DelayUs<u32>>(
         &mut self,
         register: u8,
         data: u8,
-        delay: &mut cortex_m::delay::Delay,
-    ) -> Result<(), IMUError> {
+        delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
         self.switch_bank(3)?;
         self.imu_write(I2C_SLV0_ADDR, MAG_I2C_ADDR)?;
         self.imu_write(I2C_SLV0_REG, register)?;
         self.imu_write(I2C_SLV0_DO, data)?;
         self.switch_bank(0)?;
-        Ok(self.trigger_mag_io(delay)?)
+        self.trigger_mag_io(delay)
     }
-    fn mag_read(
+    fn mag_read<D: DelayMs<u32> + DelayUs<u32>>(
         &mut self,
         register: u8,
-        delay: &mut cortex_m::delay::Delay,
-    ) -> Result<u8, IMUError> {
+        delay: &mut D,
+    ) -> Result<u8, IMUError<B::Error>> {
         self.switch_bank(3)?;
         self.imu_write(I2C_SLV0_ADDR, MAG_I2C_ADDR | 0x80)?;
         self.imu_write(I2C_SLV0_REG, register)?;
@@ -399,32 +399,29 @@ impl ICM_20948 {
         self.imu_write(I2C_SLV0_CTRL, 0x80 | 1)?; // read 1 byte
         self.switch_bank(0)?;
         self.trigger_mag_io(delay)?;
-        Ok(self.imu_read(EXT_SLV_SENS_DATA_00)?)
+        self.imu_read(EXT_SLV_SENS_DATA_00)
     }
-    fn mag_read_bytes<const length: usize>(
+    fn mag_read_bytes<const LENGTH: usize, D: DelayMs<u32> + DelayUs<u32>>(
         &mut self,
         register: u8,
-        delay: &mut cortex_m::delay::Delay,
-    ) -> Result<[u8; length], IMUError> {
-        if length > 24 {
+        delay: &mut D,
+    ) -> Result<[u8; LENGTH], IMUError<B::Error>> {
+        if LENGTH > 24 {
             return Err(IMUError::SyntaxError(
                 "invalid magnetometer read length!!",
-                length as u8,
+                LENGTH as u8,
             ));
         }
-        let mut buf: [u8; length] = [0; length];
+        let mut buf: [u8; LENGTH] = [0; LENGTH];
         self.switch_bank(3)?;
-        self.imu_write(I2C_SLV0_CTRL, (0x80 | 0x08 | length).try_into().unwrap())?;
+        self.imu_write(I2C_SLV0_CTRL, (0x80 | 0x08 | LENGTH).try_into().unwrap())?;
         self.imu_write(I2C_SLV0_ADDR, MAG_I2C_ADDR | 0x80)?;
         self.imu_write(I2C_SLV0_REG, register)?;
         self.imu_write(I2C_SLV0_DO, 0xff)?;
         self.switch_bank(0)?;
         self.trigger_mag_io(delay)?;
 
-        match self
-            .i2c
-            .write_read(IMU_ADDR, &[EXT_SLV_SENS_DATA_00], &mut buf)
-        {
+        match self.bus.read_registers(EXT_SLV_SENS_DATA_00, &mut buf) {
             Ok(()) => Ok(buf),
             Err(e) => Err(IMUError::ReadError(
                 IMUHardwareType::Magnetometer,
@@ -433,30 +430,16 @@ impl ICM_20948 {
             )),
         }
     }
-    #[inline]
-    pub fn update_raw_temp(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<(), IMUError> {
-        self.switch_bank(0)?;
-        match self
-            .i2c
-            .write_read(IMU_ADDR, &[TEMP_START], &mut self.raw_temp)
-        {
-            Ok(()) => Ok(()),
-            Err(e) => Err(IMUError::ReadError(
-                IMUHardwareType::Temperature,
-                TEMP_START,
-                e,
-            )),
-        }
-    }
 }
 
-impl Accelerometer for ICM_20948 {
-    fn update_raw_acc(&mut self) -> Result<(), IMUError> {
+impl<B: RegisterBus> Imu for ICM_20948<B> {
+    type BusError = B::Error;
+}
+
+impl<B: RegisterBus> Accelerometer for ICM_20948<B> {
+    fn update_raw_acc(&mut self) -> Result<(), IMUError<B::Error>> {
         self.switch_bank(0)?;
-        let result = match self
-            .i2c
-            .write_read(IMU_ADDR, &[ACC_START], &mut self.raw_acc)
-        {
+        let result = match self.bus.read_registers(ACC_START, &mut self.raw_acc) {
             Ok(()) => Ok(()),
             Err(e) => {
                 return Err(IMUError::ReadError(
@@ -471,22 +454,19 @@ impl Accelerometer for ICM_20948 {
     }
     fn get_acc(&self) -> [f32; 3] {
         let mult = self.accelerometer_range.get_mult();
-        // meters per sec
-        [
+        // g, not m/s^2. the failsafe landing check and accelerometer_tilt both expect g
+        self.calibration.apply_acc([
             f32::from(i16::from_be_bytes([self.raw_acc[0], self.raw_acc[1]])) * mult,
             f32::from(i16::from_be_bytes([self.raw_acc[2], self.raw_acc[3]])) * mult,
             f32::from(i16::from_be_bytes([self.raw_acc[4], self.raw_acc[5]])) * mult,
-        ]
+        ])
     }
 }
 
-impl Gyroscope for ICM_20948 {
-    fn update_raw_gyr(&mut self) -> Result<(), IMUError> {
+impl<B: RegisterBus> Gyroscope for ICM_20948<B> {
+    fn update_raw_gyr(&mut self) -> Result<(), IMUError<B::Error>> {
         self.switch_bank(0)?;
-        let result = match self
-            .i2c
-            .write_read(IMU_ADDR, &[GYR_START], &mut self.raw_gyr)
-        {
+        let result = match self.bus.read_registers(GYR_START, &mut self.raw_gyr) {
             Ok(()) => Ok(()),
             Err(e) => return Err(IMUError::ReadError(IMUHardwareType::Gyro, GYR_START, e)),
         };
@@ -496,127 +476,379 @@ impl Gyroscope for ICM_20948 {
     fn get_gyr(&self) -> [f32; 3] {
         let mult = self.gyro_range.get_mult();
         // degrees per sec
-        [
+        self.calibration.apply_gyr([
             f32::from(i16::from_be_bytes([self.raw_gyr[0], self.raw_gyr[1]])) * mult,
             f32::from(i16::from_be_bytes([self.raw_gyr[2], self.raw_gyr[3]])) * mult,
             f32::from(i16::from_be_bytes([self.raw_gyr[4], self.raw_gyr[5]])) * mult,
-        ]
+        ])
     }
 }
-impl Sensor for ICM_20948 {
-    fn update_all(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<(), IMUError> {
+impl<B: RegisterBus> Magnetometer for ICM_20948<B> {
+    fn update_raw_mag<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        _delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
+        self.switch_bank(0)?;
+        let mut buf = [0; MAG_READ_LEN as usize];
+        match self.bus.read_registers(EXT_SLV_SENS_DATA_00, &mut buf) {
+            Ok(()) => (),
+            Err(e) => {
+                return Err(IMUError::ReadError(
+                    IMUHardwareType::Magnetometer,
+                    EXT_SLV_SENS_DATA_00,
+                    e,
+                ))
+            }
+        };
+        // keep the last one if nothing new came in or the field was too strong to read
+        if buf[0] & MAG_ST1_DRDY != 0 && buf[8] & MAG_ST2_HOFL == 0 {
+            self.raw_mag.copy_from_slice(&buf[1..7]);
+        }
+        Ok(())
+    }
+    fn get_mag(&self) -> [f32; 3] {
+        let raw = |i: usize| {
+            f32::from(i16::from_le_bytes([
+                self.raw_mag[i * 2],
+                self.raw_mag[i * 2 + 1],
+            ])) * MAG_SENSITIVITY
+        };
+        // micro tesla, the ak09916 has y and z flipped compared to the accelerometer
+        self.calibration.apply_mag([raw(0), -raw(1), -raw(2)])
+    }
+}
+
+impl<B: RegisterBus> Sensor for ICM_20948<B> {
+    fn update_all<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        _delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
         self.update_raw_acc()?;
         self.update_raw_gyr()
     }
 }
 
-const MPU_ADDR: u8 = 0x68;
+impl<B: RegisterBus> TemperatureSensor for ICM_20948<B> {
+    fn get_temp_c(&self) -> f32 {
+        f32::from(i16::from_be_bytes(self.raw_temp)) / TEMPERATURE_SENSITIVITY
+            + TEMPERATURE_DEGREES_OFFSET as f32
+    }
+    fn update_raw_temp<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        _delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
+        self.switch_bank(0)?;
+        match self.bus.read_registers(TEMP_START, &mut self.raw_temp) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(IMUError::ReadError(
+                IMUHardwareType::Temperature,
+                TEMP_START,
+                e,
+            )),
+        }
+    }
+}
+
+impl<B: RegisterBus> FlightImu for ICM_20948<B> {
+    fn calibration(&self) -> ImuCalibration {
+        self.calibration
+    }
+
+    // applied to everything get_acc and get_gyr hand out from here on
+    fn set_calibration(&mut self, calibration: ImuCalibration) {
+        self.calibration = calibration;
+    }
+
+    // config bytes are grouped by register field
+    #[allow(clippy::unusual_byte_groupings)]
+    fn init<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
+        delay.delay_ms(100);
+        self.switch_bank(0)?;
+        self.imu_write(PWR_MGMT_1, 0x80)?; // reset
+        delay.delay_ms(10);
+        let interface = self.bus.user_ctrl();
+        if interface != 0 {
+            self.imu_write(USER_CTRL, interface)?;
+        }
+        if self.imu_read(WHO_AM_I)? != CHIP_ID {
+            return Err(IMUError::SetupError("IMU not found!!!"));
+        }
+
+        self.imu_write(PWR_MGMT_1, 0x01)?; // select best clock
+        self.imu_write(PWR_MGMT_2, 0x00)?; // enable gyro and accelerometer
+
+        self.switch_bank(2)?;
+        // chatgpt said these were good values for NBW
+        self.imu_write(GYRO_SR8_DIV, SAMPLE_DIV)?;
+        // f = 1.125 kHz / (1 + div as u8)
+        self.imu_write(
+            GYRO_CONFIG_1,
+            0b00_111_00_1 | self.gyro_range.full_scale_bits() << 1,
+        )?;
+        // 7:6 resv
+        // 5:3 look at table for NBW, 111 = 375Hz NBW
+        // 2:1 00 = +-250, 01 = 500, 10 = 1000, 11 = 2000
+        // 0   1 = enable low pass
+
+        self.imu_write(ACC_SMPLRT_DIV_1, 0x00)?; // msb
+        self.imu_write(ACC_SMPLRT_DIV_2, SAMPLE_DIV)?;
+        // f = 1.125 kHz / (1 + div as u16)
+        self.imu_write(
+            ACC_CONFIG,
+            0b00_011_00_1 | self.accelerometer_range.full_scale_bits() << 1,
+        )?;
+        // 7:6 resv
+        // 5:3 look at table for NBW, 011 = 69Hz NBW
+        // 2:1 00 = +-2g, 01 = 4, 10 = 8, 11 = 16
+        // 0   1 = enable low pass
+
+        self.switch_bank(0)?;
+        self.imu_write(INT_PIN_CFG, 0x00)?; // INT1 active high push pull, 50 us pulse per sample
+        self.imu_write(INT_ENABLE_1, 0x01)?; // RAW_DATA_0_RDY
+
+        self.switch_bank(3)?;
+        self.imu_write(I2C_MST_CTRL, 0x4D)?; // maybe make 0b1001111
+        self.imu_write(I2C_MST_DELAY_CTRL, 0x01)?; // maybe make 0
+
+        if self.mag_read(MAG_WIA, delay)? != MAG_CHIP_ID {
+            return Err(IMUError::SetupError("Unable to find magnetometer"));
+        }
+
+        self.mag_write(MAG_CNTL3, 0x01, delay)?; // mag reset
+        while self.mag_read(MAG_CNTL3, delay)? == 0x01 {
+            delay.delay_us(100);
+        }
+        self.mag_write(MAG_CNTL2, MAG_MODE, delay)?;
+        if self.mag_read(MAG_CNTL2, delay)? & MAG_CNTL2_MODE != MAG_MODE {
+            return Err(IMUError::SetupError(
+                "magnetometer wont go into continuous mode",
+            ));
+        }
+
+        // from here on slave 0 copies the mag into EXT_SLV_SENS_DATA by itself,
+        // so mag_read and mag_write cant be used anymore
+        self.switch_bank(3)?;
+        self.imu_write(I2C_MST_ODR_CONFIG, MAG_ODR_CONFIG)?;
+        self.imu_write(I2C_SLV0_ADDR, MAG_I2C_ADDR | 0x80)?;
+        self.imu_write(I2C_SLV0_REG, MAG_ST1)?;
+        self.imu_write(I2C_SLV0_CTRL, 0x80 | MAG_READ_LEN)?;
+        self.switch_bank(0)?;
+        let user = self.imu_read(USER_CTRL)?;
+        self.imu_write(USER_CTRL, user | 0x20)?; // i2c master stays on
+        Ok(())
+    }
+
+    // accel and gyro (and temperature if asked) go into the fifo every sample from here on
+    fn enable_fifo(&mut self, temperature: bool) -> Result<(), IMUError<B::Error>> {
+        self.switch_bank(0)?;
+        let user = self.imu_read(USER_CTRL)?;
+        self.imu_write(USER_CTRL, user | USER_CTRL_FIFO_EN)?;
+        self.imu_write(FIFO_EN_1, 0x00)?; // no i2c slaves, the mag is read on its own
+        self.imu_write(
+            FIFO_EN_2,
+            FIFO_EN_ACCEL_GYRO | if temperature { FIFO_EN_TEMP } else { 0 },
+        )?;
+        self.imu_write(FIFO_MODE, 0x00)?; // stream, overwrites the oldest when full
+        self.fifo_packet = FIFO_PACKET + if temperature { 2 } else { 0 };
+        self.reset_fifo()
+    }
+
+    // drains the fifo into samples, oldest first. newest is when the last sample was taken
+    // (the data ready interrupt knows), the rest are spaced one sample period before it
+    fn read_fifo(
+        &mut self,
+        newest: u64,
+        samples: &mut Vec<ImuSample, FIFO_MAX_SAMPLES>,
+    ) -> Result<(), IMUError<B::Error>> {
+        samples.clear();
+        if self.fifo_packet == 0 {
+            return Err(IMUError::SetupError("fifo is not enabled"));
+        }
+        self.switch_bank(0)?;
+        if self.imu_read(INT_STATUS_2)? & FIFO_OVERFLOW != 0 {
+            self.reset_fifo()?;
+            return Err(IMUError::FifoOverflow);
+        }
+        let mut count = [0; 2];
+        match self.bus.read_registers(FIFO_COUNTH, &mut count) {
+            Ok(()) => (),
+            Err(e) => return Err(IMUError::ReadError(IMUHardwareType::Memory, FIFO_COUNTH, e)),
+        };
+        // only whole packets, a half written one stays for next time
+        let packets = (u16::from_be_bytes(count) & 0x1FFF) as usize / self.fifo_packet;
+        let packets = packets.min(FIFO_SIZE / self.fifo_packet);
+        if packets == 0 {
+            return Ok(());
+        }
+        let mut buf = [0; FIFO_SIZE];
+        let len = packets * self.fifo_packet;
+        match self.bus.read_registers(FIFO_R_W, &mut buf[..len]) {
+            Ok(()) => (),
+            Err(e) => return Err(IMUError::ReadError(IMUHardwareType::Memory, FIFO_R_W, e)),
+        };
+        for (i, packet) in buf[..len].chunks_exact(self.fifo_packet).enumerate() {
+            self.raw_acc.copy_from_slice(&packet[0..6]);
+            self.raw_gyr.copy_from_slice(&packet[6..12]);
+            if self.fifo_packet > FIFO_PACKET {
+                self.raw_temp.copy_from_slice(&packet[12..14]);
+            }
+            let age = ((packets - 1 - i) as f32 * SAMPLE_PERIOD) as u64;
+            // cant fail, there are never more packets than fit
+            let _ = samples.push(ImuSample {
+                acc: self.get_acc(),
+                gyr: self.get_gyr(),
+                timestamp: newest.saturating_sub(age),
+            });
+        }
+        Ok(())
+    }
+
+    fn sample_period_us(&self) -> f32 {
+        SAMPLE_PERIOD
+    }
+
+    fn read_mag<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Option<Result<[f32; 3], IMUError<B::Error>>> {
+        Some(self.update_raw_mag(delay).map(|()| self.get_mag()))
+    }
+}
+
+pub const MPU_ADDR: u8 = 0x68;
 const MPU_ID: u8 = 0x68;
 const MPU_WHOAMI: u8 = 0x75;
+const MPU_SMPLRT_DIV: u8 = 0x19;
+const MPU_CONFIG: u8 = 0x1A;
+const MPU_GYRO_CONFIG: u8 = 0x1B;
+const MPU_ACCEL_CONFIG: u8 = 0x1C;
+const MPU_INT_PIN_CFG: u8 = 0x37;
+const MPU_INT_ENABLE: u8 = 0x38;
+const MPU_INT_STATUS: u8 = 0x3A;
+const MPU_USER_CTRL: u8 = 0x6A;
+const MPU_PWR_MGMT_1: u8 = 0x6B;
 const MPU_GYR_START: u8 = 0x43;
 const MPU_ACC_START: u8 = 0x3B;
-const MPU_ACC_DIV: f32 = 4.0 * 9.81;
-const MPU_GYR_DIV: f32 = 125.0;
+const MPU_TEMP_START: u8 = 0x41;
+const MPU_TEMP_SENSITIVITY: f32 = 340.0; // counts per degree
+const MPU_TEMP_OFFSET: f32 = 36.53;
+// 2 = 94 Hz accel / 98 Hz gyro bandwidth, about where the icm sits. anything but 0 makes it sample at 1 kHz
+const MPU_DLPF: u8 = 2;
+const MPU_SAMPLE_DIV: u8 = 0; // f = 1 kHz / (1 + div)
+const MPU_SAMPLE_PERIOD: f32 = 1000.0 * (1 + MPU_SAMPLE_DIV as u32) as f32; // timer ticks
+
+const MPU_FIFO_EN: u8 = 0x23;
+const MPU_FIFO_COUNTH: u8 = 0x72;
+const MPU_FIFO_R_W: u8 = 0x74;
+const MPU_FIFO_EN_ACCEL_GYRO: u8 = 0b0111_1000;
+const MPU_FIFO_EN_TEMP: u8 = 0b1000_0000;
+const MPU_USER_CTRL_FIFO_EN: u8 = 0x40;
+const MPU_USER_CTRL_FIFO_RESET: u8 = 0x04; // only works with FIFO_EN off, clears itself
+const MPU_INT_DATA_RDY: u8 = 0x01;
+const MPU_INT_FIFO_OFLOW: u8 = 0x10;
+// accel, then temperature if its on, then gyro. register order, unlike the icm
+const MPU_FIFO_PACKET: usize = 12;
 
-pub struct MPU_6050 {
+#[allow(non_camel_case_types)]
+pub struct MPU_6050<B> {
+    accelerometer_range: AccelerometerSetting,
+    gyro_range: GyroSetting,
     raw_acc: [u8; 6],
-    last_acc: u64,
     raw_gyr: [u8; 6],
-    last_gyr: u64,
     raw_temp: [u8; 2],
-    i2c: I2C<
-        I2C0,
-        (
-            Pin<Gpio4, Function<hal::gpio::I2C>>,
-            Pin<Gpio5, Function<hal::gpio::I2C>>,
-        ),
-    >,
+    fifo_packet: usize, // 0 while the fifo is off
+    calibration: ImuCalibration,
+    bus: B,
 }
 
-impl MPU_6050 {
+impl<B: RegisterBus> MPU_6050<B> {
     // i was too lazy to make nice errors for this one
-    pub fn new(
-        i2c0: pac::I2C0,
-        sda_pin: Pin<Gpio4, Function<hal::gpio::I2C>>,
-        scl_pin: Pin<Gpio5, Function<hal::gpio::I2C>>,
-        resets: &mut pac::RESETS,
-    ) -> Self {
-        let mut i2c = I2C::i2c0(i2c0, sda_pin, scl_pin, 400.kHz(), resets, 125_000_000.Hz());
-        let mut firstbank: [u8; 1] = [69; 1];
+    // I2cBus::new(i2c, MPU_ADDR), main.rs builds it
+    pub fn new(accelerometer_range: AccelerometerSetting, gyro_range: GyroSetting, bus: B) -> Self {
         Self {
+            accelerometer_range,
+            gyro_range,
             raw_acc: [0; 6],
-            last_acc: 0,
             raw_gyr: [0; 6],
-            last_gyr: 0,
             raw_temp: [0; 2],
-            i2c,
+            fifo_packet: 0,
+            calibration: ImuCalibration::identity(),
+            bus,
         }
     }
-    pub fn init(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<(), IMUError> {
-        self.write(0x6B, 0b10000000, delay)?;
-        delay.delay_ms(200);
-        self.write(0x6B, 0, delay)?;
-        if self.read_out::<1>(MPU_WHOAMI)?[0] != MPU_ID {
-            return Err(IMUError::SetupError("IMU not found!!!"));
-        }
-        self.write(0x37, 0b0110000, delay)?;
-        self.write(0x1B, 0, delay)?; // +- 250 dps
-        self.write(0x1C, 0b00001000, delay)?; // +- 4g
 
+    fn reset_fifo(&mut self) -> Result<(), IMUError<B::Error>> {
+        self.write_now(MPU_USER_CTRL, MPU_USER_CTRL_FIFO_RESET)?;
+        self.write_now(MPU_USER_CTRL, MPU_USER_CTRL_FIFO_EN)?;
+        self.read_out::<1>(MPU_INT_STATUS)?; // clears a stale overflow
         Ok(())
     }
 
-    fn read_out<const len: usize>(&mut self, reg: u8) -> Result<[u8; len], IMUError> {
-        let mut buf: [u8; len] = [0; len];
-        match self.i2c.write_read(MPU_ADDR, &[reg], &mut buf) {
+    fn read_out<const LEN: usize>(&mut self, reg: u8) -> Result<[u8; LEN], IMUError<B::Error>> {
+        let mut buf: [u8; LEN] = [0; LEN];
+        match self.bus.read_registers(reg, &mut buf) {
             Ok(()) => Ok(buf),
             Err(e) => Err(IMUError::ReadError(IMUHardwareType::Unknown, reg, e)),
         }
     }
 
     #[inline(always)]
-    fn read_buf<const len: usize>(&mut self, reg: u8, buf: &mut [u8; len]) -> Result<(), IMUError> {
-        match self.i2c.write_read(MPU_ADDR, &[reg], buf) {
+    fn read_buf<const LEN: usize>(
+        &mut self,
+        reg: u8,
+        buf: &mut [u8; LEN],
+    ) -> Result<(), IMUError<B::Error>> {
+        match self.bus.read_registers(reg, buf) {
             Ok(()) => Ok(()),
             Err(e) => Err(IMUError::ReadError(IMUHardwareType::Unknown, reg, e)),
         }
     }
 
     #[inline(always)]
-    fn write(
+    fn write<D: DelayMs<u32> + DelayUs<u32>>(
         &mut self,
         reg: u8,
         data: u8,
-        delay: &mut cortex_m::delay::Delay,
-    ) -> Result<(), IMUError> {
-        let results = match self.i2c.write(MPU_ADDR, &[reg, data]) {
-            Ok(()) => Ok(()),
-            Err(e) => Err(IMUError::WriteError(IMUHardwareType::Unknown, data, reg, e)),
-        };
+        delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
+        let results = self.write_now(reg, data);
         delay.delay_us(100);
         results
     }
+
+    // once its running it doesnt need the breather
+    fn write_now(&mut self, reg: u8, data: u8) -> Result<(), IMUError<B::Error>> {
+        match self.bus.write_register(reg, data) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(IMUError::WriteError(IMUHardwareType::Unknown, data, reg, e)),
+        }
+    }
 }
 
-impl Accelerometer for MPU_6050 {
+impl<B: RegisterBus> Imu for MPU_6050<B> {
+    type BusError = B::Error;
+}
+
+impl<B: RegisterBus> Accelerometer for MPU_6050<B> {
     fn get_acc(&self) -> [f32; 3] {
-        // meters per sec
-        [
-            f32::from(i16::from_be_bytes([self.raw_acc[0], self.raw_acc[1]])) * MPU_ACC_DIV,
-            f32::from(i16::from_be_bytes([self.raw_acc[2], self.raw_acc[3]])) * MPU_ACC_DIV,
-            f32::from(i16::from_be_bytes([self.raw_acc[4], self.raw_acc[5]])) * MPU_ACC_DIV,
-        ]
-    }
-    fn update_raw_acc(&mut self) -> Result<(), IMUError> {
-        match self
-            .i2c
-            .write_read(MPU_ADDR, &[MPU_ACC_START], &mut self.raw_acc)
-        {
+        let mult = self.accelerometer_range.get_mult();
+        // g, same as the icm
+        self.calibration.apply_acc([
+            f32::from(i16::from_be_bytes([self.raw_acc[0], self.raw_acc[1]])) * mult,
+            f32::from(i16::from_be_bytes([self.raw_acc[2], self.raw_acc[3]])) * mult,
+            f32::from(i16::from_be_bytes([self.raw_acc[4], self.raw_acc[5]])) * mult,
+        ])
+    }
+    fn update_raw_acc(&mut self) -> Result<(), IMUError<B::Error>> {
+        match self.bus.read_registers(MPU_ACC_START, &mut self.raw_acc) {
             Ok(()) => (),
             Err(e) => {
                 return Err(IMUError::ReadError(
                     IMUHardwareType::Accelerometer,
-                    ACC_START,
+                    MPU_ACC_START,
                     e,
                 ))
             }
@@ -625,31 +857,559 @@ impl Accelerometer for MPU_6050 {
     }
 }
 
-impl Gyroscope for MPU_6050 {
+impl<B: RegisterBus> Gyroscope for MPU_6050<B> {
     fn get_gyr(&self) -> [f32; 3] {
+        let mult = self.gyro_range.get_mult();
         // degrees per sec
-        [
-            f32::from(i16::from_be_bytes([self.raw_gyr[0], self.raw_gyr[1]])) * MPU_GYR_DIV,
-            f32::from(i16::from_be_bytes([self.raw_gyr[2], self.raw_gyr[3]])) * MPU_GYR_DIV,
-            f32::from(i16::from_be_bytes([self.raw_gyr[4], self.raw_gyr[5]])) * MPU_GYR_DIV,
-        ]
-    }
-    fn update_raw_gyr(&mut self) -> Result<(), IMUError> {
-        match self
-            .i2c
-            .write_read(MPU_ADDR, &[MPU_GYR_START], &mut self.raw_gyr)
-        {
+        self.calibration.apply_gyr([
+            f32::from(i16::from_be_bytes([self.raw_gyr[0], self.raw_gyr[1]])) * mult,
+            f32::from(i16::from_be_bytes([self.raw_gyr[2], self.raw_gyr[3]])) * mult,
+            f32::from(i16::from_be_bytes([self.raw_gyr[4], self.raw_gyr[5]])) * mult,
+        ])
+    }
+    fn update_raw_gyr(&mut self) -> Result<(), IMUError<B::Error>> {
+        match self.bus.read_registers(MPU_GYR_START, &mut self.raw_gyr) {
             Ok(()) => (),
-            Err(e) => return Err(IMUError::ReadError(IMUHardwareType::Gyro, GYR_START, e)),
+            Err(e) => return Err(IMUError::ReadError(IMUHardwareType::Gyro, MPU_GYR_START, e)),
         };
 
         Ok(())
     }
 }
 
-impl Sensor for MPU_6050 {
-    fn update_all(&mut self, delay: &mut cortex_m::delay::Delay) -> Result<(), IMUError> {
+impl<B: RegisterBus> TemperatureSensor for MPU_6050<B> {
+    fn get_temp_c(&self) -> f32 {
+        f32::from(i16::from_be_bytes(self.raw_temp)) / MPU_TEMP_SENSITIVITY + MPU_TEMP_OFFSET
+    }
+    fn update_raw_temp<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        _delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
+        match self.bus.read_registers(MPU_TEMP_START, &mut self.raw_temp) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(IMUError::ReadError(
+                IMUHardwareType::Temperature,
+                MPU_TEMP_START,
+                e,
+            )),
+        }
+    }
+}
+
+impl<B: RegisterBus> Sensor for MPU_6050<B> {
+    fn update_all<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        _delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
         self.update_raw_acc()?;
         self.update_raw_gyr()
     }
 }
+
+impl<B: RegisterBus> FlightImu for MPU_6050<B> {
+    fn calibration(&self) -> ImuCalibration {
+        self.calibration
+    }
+
+    // applied to everything get_acc and get_gyr hand out from here on
+    fn set_calibration(&mut self, calibration: ImuCalibration) {
+        self.calibration = calibration;
+    }
+
+    fn init<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), IMUError<B::Error>> {
+        self.write(MPU_PWR_MGMT_1, 0b10000000, delay)?; // reset
+        delay.delay_ms(200);
+        self.write(MPU_PWR_MGMT_1, 0x01, delay)?; // awake, clocked off the x gyro
+        if self.read_out::<1>(MPU_WHOAMI)?[0] != MPU_ID {
+            return Err(IMUError::SetupError("IMU not found!!!"));
+        }
+        // INT active high push pull, 50 us pulse per sample like the icm. latched it would
+        // stay high through every sample the loop is late for
+        self.write(MPU_INT_PIN_CFG, 0x00, delay)?;
+        self.write(MPU_INT_ENABLE, MPU_INT_DATA_RDY, delay)?;
+        self.write(MPU_CONFIG, MPU_DLPF, delay)?;
+        self.write(MPU_SMPLRT_DIV, MPU_SAMPLE_DIV, delay)?;
+        // 4:3 full scale, same encoding as the icm
+        self.write(
+            MPU_GYRO_CONFIG,
+            self.gyro_range.full_scale_bits() << 3,
+            delay,
+        )?;
+        self.write(
+            MPU_ACCEL_CONFIG,
+            self.accelerometer_range.full_scale_bits() << 3,
+            delay,
+        )?;
+
+        Ok(())
+    }
+
+    fn enable_fifo(&mut self, temperature: bool) -> Result<(), IMUError<B::Error>> {
+        let temp = if temperature { MPU_FIFO_EN_TEMP } else { 0 };
+        self.write_now(MPU_FIFO_EN, MPU_FIFO_EN_ACCEL_GYRO | temp)?;
+        self.fifo_packet = MPU_FIFO_PACKET + if temperature { 2 } else { 0 };
+        self.reset_fifo()
+    }
+
+    // same as the icm, except the fifo holds more than one read takes. the oldest come out
+    // first so they get aged by everything still waiting behind them
+    fn read_fifo(
+        &mut self,
+        newest: u64,
+        samples: &mut Vec<ImuSample, FIFO_MAX_SAMPLES>,
+    ) -> Result<(), IMUError<B::Error>> {
+        samples.clear();
+        if self.fifo_packet == 0 {
+            return Err(IMUError::SetupError("fifo is not enabled"));
+        }
+        if self.read_out::<1>(MPU_INT_STATUS)?[0] & MPU_INT_FIFO_OFLOW != 0 {
+            self.reset_fifo()?;
+            return Err(IMUError::FifoOverflow);
+        }
+        let count = u16::from_be_bytes(self.read_out::<2>(MPU_FIFO_COUNTH)?) as usize;
+        let waiting = count / self.fifo_packet;
+        let packets = waiting.min(FIFO_MAX_SAMPLES);
+        if packets == 0 {
+            return Ok(());
+        }
+        let mut buf = [0; FIFO_MAX_SAMPLES * (MPU_FIFO_PACKET + 2)];
+        let len = packets * self.fifo_packet;
+        match self.bus.read_registers(MPU_FIFO_R_W, &mut buf[..len]) {
+            Ok(()) => (),
+            Err(e) => {
+                return Err(IMUError::ReadError(
+                    IMUHardwareType::Memory,
+                    MPU_FIFO_R_W,
+                    e,
+                ))
+            }
+        };
+        let gyro = self.fifo_packet - 6;
+        for (i, packet) in buf[..len].chunks_exact(self.fifo_packet).enumerate() {
+            self.raw_acc.copy_from_slice(&packet[0..6]);
+            if self.fifo_packet > MPU_FIFO_PACKET {
+                self.raw_temp.copy_from_slice(&packet[6..8]);
+            }
+            self.raw_gyr.copy_from_slice(&packet[gyro..]);
+            let age = ((waiting - 1 - i) as f32 * MPU_SAMPLE_PERIOD) as u64;
+            // cant fail, packets is capped at what fits
+            let _ = samples.push(ImuSample {
+                acc: self.get_acc(),
+                gyr: self.get_gyr(),
+                timestamp: newest.saturating_sub(age),
+            });
+        }
+        Ok(())
+    }
+
+    fn sample_period_us(&self) -> f32 {
+        MPU_SAMPLE_PERIOD
+    }
+
+    fn read_mag<D: DelayMs<u32> + DelayUs<u32>>(
+        &mut self,
+        _delay: &mut D,
+    ) -> Option<Result<[f32; 3], IMUError<B::Error>>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::transport::mock::{Access, Chip, MockBus};
+    use super::super::transport::{I2cBus, SpiBus};
+    use super::*;
+    // not the defmt ones
+    use std::{assert, assert_eq};
+
+    fn icm<B: RegisterBus>(bus: B) -> ICM_20948<B> {
+        ICM_20948::new(AccelerometerSetting::r4g, GyroSetting::r1000dps, bus)
+    }
+
+    fn switches_banks<B: RegisterBus>(mock: &MockBus, mut imu: ICM_20948<B>) {
+        // picks up whatever bank the chip was left in
+        assert_eq!(imu.bank, 1);
+        for bank in [2, 3, 0, 1] {
+            imu.switch_bank(bank).unwrap();
+            assert_eq!(mock.chip().bank(), bank as usize);
+            imu.imu_write(0x10, 0xC0 | bank).unwrap();
+        }
+        for bank in 0..4 {
+            assert_eq!(mock.chip().get(bank, 0x10), 0xC0 | bank as u8);
+        }
+        // already there, nothing goes out
+        let before = mock.chip().log.len();
+        imu.switch_bank(1).unwrap();
+        assert_eq!(mock.chip().log.len(), before);
+        assert!(matches!(
+            imu.switch_bank(4),
+            Err(IMUError::SyntaxError(_, 4))
+        ));
+        assert_eq!(mock.chip().bank(), 1);
+    }
+
+    #[test]
+    fn switch_bank_over_i2c() {
+        let mock = MockBus::new();
+        mock.chip().set(0, BANK_SEL, 1 << 4);
+        switches_banks(&mock, icm(I2cBus::new(mock.i2c(IMU_ADDR), IMU_ADDR)));
+    }
+
+    #[test]
+    fn switch_bank_over_spi() {
+        let mock = MockBus::new();
+        mock.chip().set(0, BANK_SEL, 1 << 4);
+        let (spi, cs) = mock.spi();
+        switches_banks(&mock, icm(SpiBus::new(spi, cs)));
+    }
+
+    #[test]
+    fn i2c_stays_off_through_user_ctrl_changes() {
+        let mock = MockBus::new();
+        let (spi, cs) = mock.spi();
+        let mut imu = icm(SpiBus::new(spi, cs));
+        // what init leaves behind on spi, i2c interface off and the i2c master on
+        mock.chip().set(0, USER_CTRL, 0x10 | 0x20);
+        imu.enable_fifo(true).unwrap();
+        assert_eq!(
+            mock.chip().get(0, USER_CTRL),
+            0x10 | 0x20 | USER_CTRL_FIFO_EN
+        );
+        assert!(mock
+            .chip()
+            .writes()
+            .iter()
+            .filter(|(bank, register, _)| *bank == 0 && *register == USER_CTRL)
+            .all(|(_, _, data)| data & 0x10 != 0));
+        assert_eq!(
+            mock.chip().get(0, FIFO_EN_2),
+            FIFO_EN_ACCEL_GYRO | FIFO_EN_TEMP
+        );
+        assert_eq!(
+            mock.chip().log.last(),
+            Some(&Access::Read(0, INT_STATUS_2, 1))
+        );
+    }
+
+    // just enough of the icm for init, the reset and slave 0 of the i2c master
+    // talking to the ak09916 in aux
+    fn icm_20948(chip: &mut Chip, bank: usize, register: u8, data: u8) {
+        match (bank, register) {
+            (0, PWR_MGMT_1) if data & 0x80 != 0 => {
+                chip.banks = [[0; 128]; 4];
+                chip.set(0, WHO_AM_I, CHIP_ID);
+                chip.set(0, PWR_MGMT_1, 0x41);
+            }
+            (0, USER_CTRL) if data & 0x20 != 0 && chip.get(3, I2C_SLV0_CTRL) & 0x80 != 0 => {
+                let address = chip.get(3, I2C_SLV0_ADDR);
+                if address & 0x7F != MAG_I2C_ADDR {
+                    return;
+                }
+                let register = chip.get(3, I2C_SLV0_REG) as usize;
+                if address & 0x80 != 0 {
+                    let len = chip.get(3, I2C_SLV0_CTRL) & 0x0F;
+                    for i in 0..len {
+                        chip.set(0, EXT_SLV_SENS_DATA_00 + i, chip.aux[register + i as usize]);
+                    }
+                } else {
+                    chip.aux[register] = chip.get(3, I2C_SLV0_DO);
+                    chip.aux[MAG_CNTL3 as usize] = 0; // the soft reset is over straight away
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn icm_mock() -> MockBus {
+        let mock = MockBus::new();
+        mock.chip().on_write = icm_20948;
+        mock.chip().aux[MAG_WIA as usize] = MAG_CHIP_ID;
+        mock
+    }
+
+    // config bytes are grouped by register field, same as in init
+    #[allow(clippy::unusual_byte_groupings)]
+    fn inits<B: RegisterBus>(mock: &MockBus, mut imu: ICM_20948<B>, interface: u8) {
+        imu.init(&mut mock.delay()).unwrap();
+        let chip = mock.chip();
+        // waits for power up, and again after the reset before touching anything
+        assert_eq!(
+            chip.log[..4],
+            [
+                Access::Read(0, BANK_SEL, 1),
+                Access::Delay(100_000),
+                Access::Write(0, PWR_MGMT_1, 0x80),
+                Access::Delay(10_000),
+            ]
+        );
+        assert_eq!(chip.get(0, PWR_MGMT_1), 0x01);
+        assert_eq!(chip.get(0, PWR_MGMT_2), 0x00);
+        // r1000dps and r4g
+        assert_eq!(chip.get(2, GYRO_CONFIG_1), 0b00_111_10_1);
+        assert_eq!(chip.get(2, ACC_CONFIG), 0b00_011_01_1);
+        assert_eq!(chip.get(0, INT_ENABLE_1), 0x01);
+        assert_eq!(chip.aux[MAG_CNTL2 as usize], MAG_MODE);
+        // left reading the mag by itself
+        assert_eq!(chip.get(3, I2C_MST_ODR_CONFIG), MAG_ODR_CONFIG);
+        assert_eq!(chip.get(3, I2C_SLV0_ADDR), MAG_I2C_ADDR | 0x80);
+        assert_eq!(chip.get(3, I2C_SLV0_REG), MAG_ST1);
+        assert_eq!(chip.get(3, I2C_SLV0_CTRL), 0x80 | MAG_READ_LEN);
+        assert_eq!(chip.get(0, USER_CTRL), interface | 0x20);
+        // every USER_CTRL write after the reset keeps the interface bits
+        let user_ctrl: std::vec::Vec<u8> = chip
+            .writes()
+            .iter()
+            .filter(|(bank, register, _)| *bank == 0 && *register == USER_CTRL)
+            .map(|(_, _, data)| *data)
+            .collect();
+        assert!(user_ctrl.len() > 4);
+        assert!(user_ctrl.iter().all(|data| data & 0x10 == interface));
+        assert_eq!(imu.bank, 0);
+        assert_eq!(chip.bank(), 0);
+    }
+
+    #[test]
+    fn icm_init_over_i2c() {
+        let mock = icm_mock();
+        inits(&mock, icm(I2cBus::new(mock.i2c(IMU_ADDR), IMU_ADDR)), 0);
+    }
+
+    #[test]
+    fn icm_init_over_spi() {
+        let mock = icm_mock();
+        let (spi, cs) = mock.spi();
+        inits(&mock, icm(SpiBus::new(spi, cs)), 0x10);
+        // switched off before WHO_AM_I is even asked for
+        let log = &mock.chip().log;
+        let who_am_i = log
+            .iter()
+            .position(|a| *a == Access::Read(0, WHO_AM_I, 1))
+            .unwrap();
+        assert_eq!(log[who_am_i - 1], Access::Write(0, USER_CTRL, 0x10));
+    }
+
+    #[test]
+    fn icm_init_errors() {
+        // nothing there
+        let mock = MockBus::new();
+        let mut imu = icm(mock.clone());
+        assert!(matches!(
+            imu.init(&mut mock.delay()),
+            Err(IMUError::SetupError("IMU not found!!!"))
+        ));
+        // icm answers but the mag doesnt
+        let mock = icm_mock();
+        mock.chip().aux[MAG_WIA as usize] = 0;
+        let mut imu = icm(mock.clone());
+        assert!(matches!(
+            imu.init(&mut mock.delay()),
+            Err(IMUError::SetupError("Unable to find magnetometer"))
+        ));
+    }
+
+    fn mpu_6050(chip: &mut Chip, _bank: usize, register: u8, data: u8) {
+        if register == MPU_PWR_MGMT_1 && data & 0x80 != 0 {
+            chip.banks[0] = [0; 128];
+            chip.set(0, MPU_WHOAMI, MPU_ID);
+            chip.set(0, MPU_PWR_MGMT_1, 0x40);
+        }
+    }
+
+    #[test]
+    fn mpu_init() {
+        let mock = MockBus::new();
+        mock.chip().on_write = mpu_6050;
+        let mut mpu = MPU_6050::new(
+            AccelerometerSetting::r8g,
+            GyroSetting::r500dps,
+            I2cBus::new(mock.i2c(MPU_ADDR), MPU_ADDR),
+        );
+        mpu.init(&mut mock.delay()).unwrap();
+        let chip = mock.chip();
+        let writes = [
+            (MPU_PWR_MGMT_1, 0x80),
+            (MPU_PWR_MGMT_1, 0x01),
+            (MPU_INT_PIN_CFG, 0x00),
+            (MPU_INT_ENABLE, MPU_INT_DATA_RDY),
+            (MPU_CONFIG, MPU_DLPF),
+            (MPU_SMPLRT_DIV, MPU_SAMPLE_DIV),
+            (MPU_GYRO_CONFIG, 1 << 3),
+            (MPU_ACCEL_CONFIG, 2 << 3),
+        ];
+        assert_eq!(
+            chip.writes(),
+            writes.map(|(register, data)| (0, register, data))
+        );
+        // 200 ms for the reset, then 100 us after every write
+        assert_eq!(
+            chip.log[..3],
+            [
+                Access::Write(0, MPU_PWR_MGMT_1, 0x80),
+                Access::Delay(100),
+                Access::Delay(200_000),
+            ]
+        );
+        assert_eq!(chip.log.last(), Some(&Access::Delay(100)));
+        assert_eq!(chip.bank(), 0);
+
+        // nothing there
+        let mock = MockBus::new();
+        let mut mpu = MPU_6050::new(
+            AccelerometerSetting::r8g,
+            GyroSetting::r500dps,
+            mock.clone(),
+        );
+        assert!(matches!(
+            mpu.init(&mut mock.delay()),
+            Err(IMUError::SetupError("IMU not found!!!"))
+        ));
+    }
+
+    fn be(values: [i16; 3]) -> [u8; 6] {
+        let mut bytes = [0; 6];
+        for (i, v) in values.iter().enumerate() {
+            bytes[i * 2..i * 2 + 2].copy_from_slice(&v.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn set_count(mock: &MockBus, register: u8, count: usize) {
+        let [high, low] = (count as u16).to_be_bytes();
+        mock.chip().set(0, register, high);
+        mock.chip().set(0, register + 1, low);
+    }
+
+    #[test]
+    fn icm_fifo() {
+        let mock = MockBus::new();
+        let mut imu = icm(mock.clone());
+        let mut samples = Vec::new();
+        assert!(matches!(
+            imu.read_fifo(0, &mut samples),
+            Err(IMUError::SetupError(_))
+        ));
+        imu.enable_fifo(true).unwrap();
+        mock.chip().fifo_register = Some((0, FIFO_R_W));
+        for n in 1..=3 {
+            let mut chip = mock.chip();
+            chip.fifo.extend(be([n * 100, 0, 8192]));
+            chip.fifo.extend(be([0, -n * 200, 0]));
+            chip.fifo.extend(1335i16.to_be_bytes());
+        }
+        // and half of the next one still being written
+        mock.chip().fifo.extend([0xAA; 5]);
+        set_count(&mock, FIFO_COUNTH, 3 * 14 + 5);
+        imu.read_fifo(100_000, &mut samples).unwrap();
+        assert_eq!(samples.len(), 3);
+        let mult = AccelerometerSetting::r4g.get_mult();
+        for (i, sample) in samples.iter().enumerate() {
+            let n = i as f32 + 1.0;
+            assert_eq!(sample.acc, [n * 100.0 * mult, 0.0, 1.0]);
+            assert_eq!(
+                sample.gyr,
+                [0.0, -n * 200.0 * GyroSetting::r1000dps.get_mult(), 0.0]
+            );
+        }
+        let stamps: std::vec::Vec<u64> = samples.iter().map(|s| s.timestamp).collect();
+        assert_eq!(stamps, [100_000 - 1777, 100_000 - 888, 100_000]);
+        assert!((imu.sample_period_us() - 888.9).abs() < 0.1); // 1125 Hz
+        assert!((imu.get_temp_c() - 25.0).abs() < 0.01);
+        assert_eq!(mock.chip().fifo.len(), 5);
+
+        mock.chip().set(0, INT_STATUS_2, 0x01);
+        assert!(matches!(
+            imu.read_fifo(200_000, &mut samples),
+            Err(IMUError::FifoOverflow)
+        ));
+        assert!(samples.is_empty());
+        let writes = mock.chip().writes();
+        assert_eq!(
+            writes[writes.len() - 2..],
+            [(0, FIFO_RST, 0x1F), (0, FIFO_RST, 0x00)]
+        );
+    }
+
+    #[test]
+    fn icm_temperature() {
+        let mock = MockBus::new();
+        let mut imu = icm(mock.clone());
+        mock.chip().set(0, TEMP_START, 0x00);
+        mock.chip().set(0, TEMP_START + 1, 0x00);
+        imu.update_raw_temp(&mut mock.delay()).unwrap();
+        assert_eq!(imu.get_temp_c(), 21.0);
+        let [high, low] = (-3339i16).to_be_bytes();
+        mock.chip().set(0, TEMP_START, high);
+        mock.chip().set(0, TEMP_START + 1, low);
+        imu.update_raw_temp(&mut mock.delay()).unwrap();
+        assert!((imu.get_temp_c() - 11.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn mpu_fifo() {
+        let mock = MockBus::new();
+        let mut mpu = MPU_6050::new(
+            AccelerometerSetting::r8g,
+            GyroSetting::r500dps,
+            mock.clone(),
+        );
+        assert!(mpu.read_mag(&mut mock.delay()).is_none());
+        assert_eq!(mpu.sample_period_us(), 1000.0); // 1 kHz, not the icm's 1125
+        mpu.enable_fifo(false).unwrap();
+        // the reset only takes with the fifo off
+        assert_eq!(
+            mock.chip().writes(),
+            [
+                (0, MPU_FIFO_EN, MPU_FIFO_EN_ACCEL_GYRO),
+                (0, MPU_USER_CTRL, MPU_USER_CTRL_FIFO_RESET),
+                (0, MPU_USER_CTRL, MPU_USER_CTRL_FIFO_EN),
+            ]
+        );
+        // more waiting than one read takes, the oldest come out first
+        const WAITING: usize = FIFO_MAX_SAMPLES + 8;
+        mock.chip().fifo_register = Some((0, MPU_FIFO_R_W));
+        for n in 0..WAITING as i16 {
+            let mut chip = mock.chip();
+            chip.fifo.extend(be([n, 0, 4096]));
+            chip.fifo.extend(be([0, 0, n]));
+        }
+        set_count(&mock, MPU_FIFO_COUNTH, WAITING * 12);
+        let mut samples = Vec::new();
+        mpu.read_fifo(1_000_000, &mut samples).unwrap();
+        assert_eq!(samples.len(), FIFO_MAX_SAMPLES);
+        let mult = GyroSetting::r500dps.get_mult();
+        for (i, sample) in samples.iter().enumerate() {
+            assert_eq!(sample.acc[2], 1.0);
+            assert_eq!(sample.gyr[2], i as f32 * mult);
+            let age = (WAITING - 1 - i) as u64 * 1000;
+            assert_eq!(sample.timestamp, 1_000_000 - age);
+        }
+        assert_eq!(mock.chip().fifo.len(), 8 * 12);
+
+        // temperature sits between the accel and the gyro
+        mpu.enable_fifo(true).unwrap();
+        assert_eq!(
+            mock.chip().get(0, MPU_FIFO_EN),
+            MPU_FIFO_EN_ACCEL_GYRO | MPU_FIFO_EN_TEMP
+        );
+        mock.chip().fifo.clear();
+        mock.chip().fifo.extend(be([0, 4096, 0]));
+        mock.chip().fifo.extend(340i16.to_be_bytes());
+        mock.chip().fifo.extend(be([100, 0, 0]));
+        set_count(&mock, MPU_FIFO_COUNTH, 14);
+        mpu.read_fifo(5_000, &mut samples).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].acc, [0.0, 1.0, 0.0]);
+        assert_eq!(samples[0].gyr, [100.0 * mult, 0.0, 0.0]);
+        assert_eq!(samples[0].timestamp, 5_000);
+        assert!((mpu.get_temp_c() - (1.0 + MPU_TEMP_OFFSET)).abs() < 0.01);
+
+        mock.chip().set(0, MPU_INT_STATUS, MPU_INT_FIFO_OFLOW);
+        assert!(matches!(
+            mpu.read_fifo(6_000, &mut samples),
+            Err(IMUError::FifoOverflow)
+        ));
+    }
+}
@@ -1,4 +1,8 @@
+pub mod battery;
+pub mod calibration;
+pub mod data_ready;
 pub mod imu;
-pub use imu::GyroSetting;
+pub mod transport;
 pub use imu::AccelerometerSetting;
+pub use imu::GyroSetting;
 pub use imu::ICM_20948;
@@ -0,0 +1,486 @@
+// how the ICM-20948 registers get reached, the driver only ever reads and writes registers
+// so it doesnt care if thats over i2c or spi. banks are the driver's problem, BANK_SEL is
+// just another register and exists in every bank
+use core::convert::Infallible;
+use core::fmt::Debug;
+use defmt::info;
+use embedded_hal::blocking::i2c::{Write as I2cWrite, WriteRead};
+use embedded_hal::blocking::spi::{Transfer, Write as SpiWrite};
+use embedded_hal::digital::v2::OutputPin;
+use hal::i2c::Error as I2CError;
+use rp2040_hal as hal;
+
+const SPI_READ: u8 = 0x80; // top bit of the address byte
+const USER_CTRL_I2C_IF_DIS: u8 = 0x10;
+
+// E is whatever the i2c or spi peripheral fails with
+#[derive(Debug)]
+pub enum BusError<E> {
+    Bus(E),
+    Pin, // spi cs wouldnt move
+}
+
+// logs what went wrong on the bus, the i2c aborts are worth picking apart
+pub trait BusErrorInfo: Debug {
+    fn info(&self);
+}
+
+impl<E: BusErrorInfo> BusErrorInfo for BusError<E> {
+    fn info(&self) {
+        match self {
+            BusError::Bus(e) => e.info(),
+            BusError::Pin => info!("chip select pin error"),
+        }
+    }
+}
+
+// rp2040 spi and pins cant fail
+impl BusErrorInfo for Infallible {
+    fn info(&self) {
+        match *self {}
+    }
+}
+
+impl BusErrorInfo for I2CError {
+    fn info(&self) {
+        match *self {
+            I2CError::Abort(v) => {
+                if v & 1 << 12 != 0 {
+                    info!("ArbitrationLoss")
+                } else if v & 1 << 7 != 0 {
+                    info!("ABRT_SBYTE_ACKDET")
+                }
+                // ha::i2c::ErrorKind::Bus,
+                // if v & 1<<6 != 0 // ABRT_HS_ACKDET
+                // ha::i2c::ErrorKind::Bus,
+                // if v & 1<<4 != 0 // ABRT_GCALL_NOACK
+                // ha::i2c::ErrorKind::NoAcknowledge(eh1_0_alpha::i2c::NoAcknowledgeSource::Address),
+                // if v & 1<<3 != 0 // ABRT_TXDATA_NOACK
+                // ha::i2c::ErrorKind::NoAcknowledge(eh1_0_alpha::i2c::NoAcknowledgeSource::Data),
+                // if v & 1<<2 != 0 // ABRT_10ADDR2_NOACK
+                // ha::i2c::ErrorKind::NoAcknowledge(eh1_0_alpha::i2c::NoAcknowledgeSource::Address),
+                // if v & 1<<1 != 0 // ABRT_10ADDR1_NOACK
+                // ha::i2c::ErrorKind::NoAcknowledge(eh1_0_alpha::i2c::NoAcknowledgeSource::Address),
+                else if v & 1 << 0 != 0 {
+                    // ABRT_7B_ADDR_NOACK
+                    info!("no acknowledge from device")
+                } else {
+                    info!("Other abort")
+                };
+            }
+            I2CError::AddressReserved(addr) => info!("address {0} out of range", addr),
+            I2CError::AddressOutOfRange(addr) => info!("address {0} out of range", addr),
+            I2CError::InvalidReadBufferLength => info!("Invalid read buffer length"),
+            I2CError::InvalidWriteBufferLength => info!("Invalid write buffer length"),
+            _ => info!("other error"),
+        }
+    }
+}
+
+pub trait RegisterBus {
+    type Error: BusErrorInfo;
+    fn write_register(&mut self, register: u8, data: u8) -> Result<(), Self::Error>;
+    // reads buf.len() registers starting at register, the chip auto increments
+    fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Self::Error>;
+    // bits for USER_CTRL that this bus needs kept set
+    fn user_ctrl(&self) -> u8 {
+        0
+    }
+}
+
+pub struct I2cBus<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C> I2cBus<I2C> {
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+}
+
+impl<I2C, E> RegisterBus for I2cBus<I2C>
+where
+    I2C: I2cWrite<Error = E> + WriteRead<Error = E>,
+    E: BusErrorInfo,
+{
+    type Error = BusError<E>;
+
+    fn write_register(&mut self, register: u8, data: u8) -> Result<(), BusError<E>> {
+        self.i2c
+            .write(self.address, &[register, data])
+            .map_err(BusError::Bus)
+    }
+
+    fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), BusError<E>> {
+        self.i2c
+            .write_read(self.address, &[register], buf)
+            .map_err(BusError::Bus)
+    }
+}
+
+// spi mode 3, up to 7 MHz. cs is driven here, not by the spi peripheral, so bursts stay in one transaction
+pub struct SpiBus<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS> SpiBus<SPI, CS> {
+    pub fn new(spi: SPI, cs: CS) -> Self {
+        Self { spi, cs }
+    }
+
+    fn transaction<T, E>(
+        &mut self,
+        f: impl FnOnce(&mut SPI) -> Result<T, E>,
+    ) -> Result<T, BusError<E>>
+    where
+        CS: OutputPin,
+    {
+        self.cs.set_low().map_err(|_| BusError::Pin)?;
+        let result = f(&mut self.spi);
+        self.cs.set_high().map_err(|_| BusError::Pin)?;
+        result.map_err(BusError::Bus)
+    }
+}
+
+impl<SPI, CS, E> RegisterBus for SpiBus<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E> + SpiWrite<u8, Error = E>,
+    CS: OutputPin,
+    E: BusErrorInfo,
+{
+    type Error = BusError<E>;
+
+    fn write_register(&mut self, register: u8, data: u8) -> Result<(), BusError<E>> {
+        self.transaction(|spi| spi.write(&[register & !SPI_READ, data]))
+    }
+
+    fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), BusError<E>> {
+        self.transaction(|spi| {
+            spi.write(&[register | SPI_READ])?;
+            buf.fill(0);
+            spi.transfer(buf)?;
+            Ok(())
+        })
+    }
+
+    // otherwise the i2c side can mistake spi traffic for its own
+    fn user_ctrl(&self) -> u8 {
+        USER_CTRL_I2C_IF_DIS
+    }
+}
+
+// a pretend chip for the tests, 4 banks of 128 registers with BANK_SEL at the top of every one.
+// the same registers can be reached straight through RegisterBus or through the i2c and spi
+// transports, and a test can hook writes to make it act like a particular chip
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+    use std::cell::{RefCell, RefMut};
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    pub const BANK_SEL: u8 = 0x7F;
+    const REGISTERS: usize = 128;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Access {
+        Read(usize, u8, usize), // bank, first register, length
+        Write(usize, u8, u8),   // bank, register, data
+        Delay(u32),             // us
+    }
+
+    pub struct Chip {
+        pub banks: [[u8; REGISTERS]; 4],
+        pub log: Vec<Access>,
+        pub mosi: Vec<u8>, // everything the spi side sent
+        pub selected: bool,
+        pub aux: [u8; 256], // registers of whatever hangs off its auxiliary i2c
+        // reads of this bank and register drain fifo instead of auto incrementing
+        pub fifo_register: Option<(usize, u8)>,
+        pub fifo: VecDeque<u8>,
+        // runs after every write with the bank it landed in
+        pub on_write: fn(&mut Chip, usize, u8, u8),
+    }
+
+    impl Chip {
+        pub fn bank(&self) -> usize {
+            (self.banks[0][BANK_SEL as usize] >> 4 & 0x03) as usize
+        }
+
+        pub fn get(&self, bank: usize, register: u8) -> u8 {
+            self.banks[bank][register as usize]
+        }
+
+        pub fn set(&mut self, bank: usize, register: u8, data: u8) {
+            if register == BANK_SEL {
+                self.banks
+                    .iter_mut()
+                    .for_each(|b| b[BANK_SEL as usize] = data);
+            } else {
+                self.banks[bank][register as usize] = data;
+            }
+        }
+
+        pub fn write(&mut self, register: u8, data: u8) {
+            let bank = self.bank();
+            self.log.push(Access::Write(bank, register, data));
+            self.set(bank, register, data);
+            (self.on_write)(self, bank, register, data);
+        }
+
+        pub fn read(&mut self, register: u8, buf: &mut [u8]) {
+            let bank = self.bank();
+            self.log.push(Access::Read(bank, register, buf.len()));
+            if self.fifo_register == Some((bank, register)) {
+                buf.fill_with(|| self.fifo.pop_front().unwrap_or(0));
+                return;
+            }
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = self.banks[bank][register as usize + i];
+            }
+        }
+
+        pub fn writes(&self) -> Vec<(usize, u8, u8)> {
+            self.log
+                .iter()
+                .filter_map(|a| match *a {
+                    Access::Write(bank, register, data) => Some((bank, register, data)),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct MockBus(Rc<RefCell<Chip>>);
+
+    impl MockBus {
+        pub fn new() -> Self {
+            Self(Rc::new(RefCell::new(Chip {
+                banks: [[0; REGISTERS]; 4],
+                log: Vec::new(),
+                mosi: Vec::new(),
+                selected: false,
+                aux: [0; 256],
+                fifo_register: None,
+                fifo: VecDeque::new(),
+                on_write: |_, _, _, _| (),
+            })))
+        }
+
+        pub fn chip(&self) -> RefMut<'_, Chip> {
+            self.0.borrow_mut()
+        }
+
+        pub fn i2c(&self, address: u8) -> MockI2c {
+            MockI2c {
+                bus: self.clone(),
+                address,
+            }
+        }
+
+        pub fn spi(&self) -> (MockSpi, MockCs) {
+            (
+                MockSpi {
+                    bus: self.clone(),
+                    read: None,
+                },
+                MockCs(self.clone()),
+            )
+        }
+
+        pub fn delay(&self) -> MockDelay {
+            MockDelay(self.clone())
+        }
+    }
+
+    impl RegisterBus for MockBus {
+        type Error = Infallible;
+
+        fn write_register(&mut self, register: u8, data: u8) -> Result<(), Infallible> {
+            self.chip().write(register, data);
+            Ok(())
+        }
+
+        fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Infallible> {
+            self.chip().read(register, buf);
+            Ok(())
+        }
+    }
+
+    pub struct MockI2c {
+        bus: MockBus,
+        address: u8,
+    }
+
+    impl I2cWrite for MockI2c {
+        type Error = Infallible;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Infallible> {
+            assert_eq!(address, self.address, "wrong i2c address");
+            let mut chip = self.bus.chip();
+            for (i, data) in bytes[1..].iter().enumerate() {
+                chip.write(bytes[0] + i as u8, *data);
+            }
+            Ok(())
+        }
+    }
+
+    impl WriteRead for MockI2c {
+        type Error = Infallible;
+
+        fn write_read(
+            &mut self,
+            address: u8,
+            bytes: &[u8],
+            buf: &mut [u8],
+        ) -> Result<(), Infallible> {
+            assert_eq!(address, self.address, "wrong i2c address");
+            assert_eq!(bytes.len(), 1, "only the register goes out before a read");
+            self.bus.chip().read(bytes[0], buf);
+            Ok(())
+        }
+    }
+
+    // the first byte after cs goes low says which register and which way
+    pub struct MockSpi {
+        bus: MockBus,
+        read: Option<u8>, // register waiting for the clocks to read it out
+    }
+
+    impl SpiWrite<u8> for MockSpi {
+        type Error = Infallible;
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+            let mut chip = self.bus.chip();
+            assert!(chip.selected, "spi traffic with cs high");
+            assert!(self.read.is_none(), "read address sent twice");
+            chip.mosi.extend_from_slice(words);
+            let register = words[0] & !SPI_READ;
+            if words[0] & SPI_READ != 0 {
+                assert_eq!(words.len(), 1, "data sent after a read address");
+                self.read = Some(register);
+            } else {
+                for (i, data) in words[1..].iter().enumerate() {
+                    chip.write(register + i as u8, *data);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Transfer<u8> for MockSpi {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+            let mut chip = self.bus.chip();
+            assert!(chip.selected, "spi traffic with cs high");
+            let register = self
+                .read
+                .take()
+                .expect("clocked out data without a read address");
+            chip.mosi.extend_from_slice(words);
+            chip.read(register, words);
+            Ok(words)
+        }
+    }
+
+    pub struct MockCs(MockBus);
+
+    impl OutputPin for MockCs {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.chip().selected = true;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.chip().selected = false;
+            Ok(())
+        }
+    }
+
+    // waits go in the log so a test can see them between the register accesses
+    pub struct MockDelay(MockBus);
+
+    impl DelayMs<u32> for MockDelay {
+        fn delay_ms(&mut self, ms: u32) {
+            self.0.chip().log.push(Access::Delay(ms * 1000));
+        }
+    }
+
+    impl DelayUs<u32> for MockDelay {
+        fn delay_us(&mut self, us: u32) {
+            self.0.chip().log.push(Access::Delay(us));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{Access, MockBus, BANK_SEL};
+    use super::*;
+
+    const ADDRESS: u8 = 0x68;
+
+    #[test]
+    fn i2c_goes_to_the_selected_bank() {
+        let mock = MockBus::new();
+        let mut bus = I2cBus::new(mock.i2c(ADDRESS), ADDRESS);
+        bus.write_register(BANK_SEL, 2 << 4).unwrap();
+        bus.write_register(0x01, 0x55).unwrap();
+        assert_eq!(mock.chip().get(2, 0x01), 0x55);
+        assert_eq!(mock.chip().get(0, 0x01), 0x00);
+        mock.chip().set(2, 0x02, 0xAA);
+        let mut buf = [0; 2];
+        bus.read_registers(0x01, &mut buf).unwrap();
+        assert_eq!(buf, [0x55, 0xAA]);
+        assert_eq!(bus.user_ctrl(), 0);
+    }
+
+    #[test]
+    fn spi_sets_the_read_bit() {
+        let mock = MockBus::new();
+        mock.chip().set(0, 0x00, 0xEA);
+        let (spi, cs) = mock.spi();
+        let mut bus = SpiBus::new(spi, cs);
+        let mut buf = [0; 1];
+        bus.read_registers(0x00, &mut buf).unwrap();
+        assert_eq!(buf, [0xEA]);
+        // address byte then a dummy clocked out for the data
+        assert_eq!(mock.chip().mosi, [0x80, 0x00]);
+        assert!(!mock.chip().selected);
+
+        mock.chip().mosi.clear();
+        bus.write_register(0x03, 0x10).unwrap();
+        assert_eq!(mock.chip().mosi, [0x03, 0x10]);
+        // a burst is one address and auto increment
+        mock.chip().set(0, 0x2D, 0x12);
+        mock.chip().set(0, 0x2E, 0x34);
+        let mut burst = [0; 2];
+        bus.read_registers(0x2D, &mut burst).unwrap();
+        assert_eq!(burst, [0x12, 0x34]);
+        assert_eq!(mock.chip().log.last(), Some(&Access::Read(0, 0x2D, 2)));
+        assert!(!mock.chip().selected);
+    }
+
+    #[test]
+    fn spi_goes_to_the_selected_bank() {
+        let mock = MockBus::new();
+        let (spi, cs) = mock.spi();
+        let mut bus = SpiBus::new(spi, cs);
+        for bank in 0..4 {
+            bus.write_register(BANK_SEL, bank << 4).unwrap();
+            bus.write_register(0x10, 0xB0 | bank).unwrap();
+        }
+        for bank in 0..4 {
+            assert_eq!(mock.chip().get(bank as usize, 0x10), 0xB0 | bank);
+        }
+        assert_eq!(bus.user_ctrl(), USER_CTRL_I2C_IF_DIS);
+    }
+}
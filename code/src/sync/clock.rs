@@ -0,0 +1,29 @@
+// the rp2040 timer counter, readable from either core and from interrupts without owning
+// the one hal::Timer. only counts once hal::Timer::new has taken the peripheral out of reset
+use embedded_hal::blocking::delay::DelayUs;
+use rp2040_hal::pac;
+
+// timer ticks (microseconds) since the timer started, same as hal::Timer::get_counter
+pub fn now() -> u64 {
+    // the raw registers dont latch each other, so read the high word until it holds still
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    let mut high = timer.timerawh.read().bits();
+    loop {
+        let low = timer.timerawl.read().bits();
+        let high_again = timer.timerawh.read().bits();
+        if high == high_again {
+            return (u64::from(high) << 32) | u64::from(low);
+        }
+        high = high_again;
+    }
+}
+
+// spins on the counter, for a core that doesnt have the systick delay
+pub struct ClockDelay;
+
+impl DelayUs<u32> for ClockDelay {
+    fn delay_us(&mut self, us: u32) {
+        let start = now();
+        while now() - start < us as u64 {}
+    }
+}
@@ -0,0 +1,3 @@
+pub mod clock;
+pub mod seqlock;
+pub use seqlock::SeqLock;
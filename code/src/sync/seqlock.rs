@@ -0,0 +1,175 @@
+// single writer seqlock for handing state between the cores
+// the writer never waits, readers retry if they catch it halfway through a write
+// only needs atomic load/store so it works on the m0+ (no CAS)
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{fence, AtomicBool, AtomicU32, Ordering};
+
+pub struct SeqLock<T: Copy> {
+    seq: AtomicU32, // odd while a write is in progress
+    data: UnsafeCell<T>,
+    writer_taken: AtomicBool,
+}
+
+// readers only ever get copies and there is only one writer
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            data: UnsafeCell::new(value),
+            writer_taken: AtomicBool::new(false),
+        }
+    }
+
+    // hands out the one and only writer, None if somebody already has it
+    pub fn writer(&self) -> Option<SeqLockWriter<'_, T>> {
+        critical_section::with(|_| {
+            if self.writer_taken.load(Ordering::Relaxed) {
+                None
+            } else {
+                self.writer_taken.store(true, Ordering::Relaxed);
+                Some(SeqLockWriter { lock: self })
+            }
+        })
+    }
+
+    pub fn read(&self) -> T {
+        loop {
+            match self.try_read() {
+                Some(value) => return value,
+                None => spin_loop(),
+            }
+        }
+    }
+
+    // None if a write was in progress or landed while copying
+    pub fn try_read(&self) -> Option<T> {
+        let before = self.seq.load(Ordering::Acquire);
+        if before & 1 != 0 {
+            return None;
+        }
+        // might be torn, only trusted if the sequence didnt move
+        let value = unsafe { ptr::read_volatile(self.data.get()) };
+        fence(Ordering::Acquire);
+        let after = self.seq.load(Ordering::Relaxed);
+        if before == after {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    pub fn sequence(&self) -> u32 {
+        self.seq.load(Ordering::Acquire)
+    }
+}
+
+pub struct SeqLockWriter<'a, T: Copy> {
+    lock: &'a SeqLock<T>,
+}
+
+impl<'a, T: Copy> SeqLockWriter<'a, T> {
+    pub fn write(&mut self, value: T) {
+        let seq = self.lock.seq.load(Ordering::Relaxed);
+        self.lock.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.lock.data.get(), value) };
+        self.lock.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    // nobody else writes so this can never be torn
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.lock.data.get()) }
+    }
+
+    pub fn update<F: FnOnce(&mut T)>(&mut self, f: F) {
+        let mut value = self.read();
+        f(&mut value);
+        self.write(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_writer() {
+        let lock = SeqLock::new(0u32);
+        {
+            let writer = lock.writer();
+            assert!(writer.is_some());
+            assert!(lock.writer().is_none());
+        }
+        // the first one going out of scope doesnt give it back either, core1 owns it for good
+        assert!(lock.writer().is_none());
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let lock = SeqLock::new([0u32; 4]);
+        let mut writer = lock.writer().unwrap();
+        assert_eq!(lock.read(), [0; 4]);
+        assert_eq!(lock.sequence(), 0);
+        writer.write([1, 2, 3, 4]);
+        assert_eq!(lock.read(), [1, 2, 3, 4]);
+        assert_eq!(lock.try_read(), Some([1, 2, 3, 4]));
+        assert_eq!(writer.read(), [1, 2, 3, 4]);
+        writer.update(|value| value[2] = 7);
+        assert_eq!(lock.read(), [1, 2, 7, 4]);
+        // two bumps per write, even again when its done
+        assert_eq!(lock.sequence(), 4);
+    }
+
+    #[test]
+    fn try_read_backs_off_mid_write() {
+        let lock = SeqLock::new(5u32);
+        // what a reader on the other core sees between the writers two stores
+        lock.seq.store(1, Ordering::Relaxed);
+        assert_eq!(lock.try_read(), None);
+        lock.seq.store(2, Ordering::Relaxed);
+        assert_eq!(lock.try_read(), Some(5));
+    }
+
+    #[test]
+    fn sequence_wraps() {
+        let lock = SeqLock::new(0u8);
+        lock.seq.store(u32::MAX - 1, Ordering::Relaxed);
+        let mut writer = lock.writer().unwrap();
+        writer.write(1);
+        assert_eq!(lock.sequence(), 0);
+        assert_eq!(lock.try_read(), Some(1));
+    }
+
+    #[test]
+    fn never_reads_a_torn_value() {
+        // the writer keeps every element equal, a reader that sees a mix got half a write
+        const WRITES: u32 = 200_000;
+        let lock = SeqLock::new([0u32; 16]);
+        let mut writer = lock.writer().unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..3 {
+                scope.spawn(|| {
+                    let mut last = 0;
+                    while last < WRITES {
+                        let value = lock.read();
+                        assert!(
+                            value.iter().all(|v| *v == value[0]),
+                            "torn read {:?}",
+                            value
+                        );
+                        // and writes never show up out of order
+                        assert!(value[0] >= last);
+                        last = value[0];
+                    }
+                });
+            }
+            for n in 1..=WRITES {
+                writer.write([n; 16]);
+            }
+        });
+    }
+}